@@ -0,0 +1,29 @@
+//! Times `parse` on large and pathological sources. Run with `cargo bench`.
+
+use orelang_but_rust::parser;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+fn main() {
+    let cases = [
+        ("100k tokens", "(f x 1)\n(g \"s\") ; c\n".repeat(8_000)),
+        (
+            "50k-deep nesting",
+            format!("{}{}", "(".repeat(50_000), ")".repeat(50_000)),
+        ),
+        ("50k unclosed parens", "(".repeat(50_000)),
+        ("1MB comment", format!(";{}\n(f)", "x".repeat(1_000_000))),
+        ("1MB string", format!("\"{}\"", "x".repeat(1_000_000))),
+        ("100k bad chars", "@".repeat(100_000)),
+    ];
+    for (name, source) in cases {
+        let mut total = Duration::ZERO;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            std::hint::black_box(parser::parse(&source));
+            total += start.elapsed();
+        }
+        println!("{:<24}{:>12.2?}", name, total / RUNS);
+    }
+}
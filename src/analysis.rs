@@ -0,0 +1,1074 @@
+use crate::builtins::{builtin, Arity, BuiltinKind};
+use crate::parser::{collect_defuns, cond_clause, is_let, number_value, Expr, Span, Spanned};
+use std::collections::{HashMap, HashSet};
+
+/// Reports every identifier that isn't a builtin, a `defun` name visible from
+/// its scope, a parameter of an enclosing `defun` or a `let` binding.
+pub fn check_unbound(ast: &[Spanned<Expr>]) -> Vec<(Span, String)> {
+    let mut unbound = vec![];
+    let mut scopes = vec![];
+    check_body(ast, &mut scopes, HashSet::new(), &mut unbound);
+    unbound
+}
+
+/// Checks a sequence of forms in a new scope. `defun`s directly in the body
+/// are visible to all of its forms, so functions may refer to each other.
+fn check_body<'a>(
+    body: &'a [Spanned<Expr>],
+    scopes: &mut Vec<HashSet<&'a str>>,
+    mut scope: HashSet<&'a str>,
+    unbound: &mut Vec<(Span, String)>,
+) {
+    scope.extend(body.iter().filter_map(|(expr, _)| defun_name(expr)));
+    scopes.push(scope);
+    for expr in body {
+        check_expr(expr, scopes, unbound);
+    }
+    scopes.pop();
+}
+
+fn check_expr<'a>(
+    (expr, span): &'a Spanned<Expr>,
+    scopes: &mut Vec<HashSet<&'a str>>,
+    unbound: &mut Vec<(Span, String)>,
+) {
+    match expr {
+        // Quoted data isn't evaluated, so its identifiers aren't references.
+        Expr::Number(_) | Expr::Ratio(_) | Expr::Str(_) | Expr::Quote(_) => {}
+        Expr::Ident(name) => {
+            let bound =
+                builtin(name).is_some() || scopes.iter().any(|scope| scope.contains(name.as_str()));
+            if !bound {
+                unbound.push((span.clone(), format!("unbound variable '{}'", name)));
+            }
+        }
+        Expr::List(items) => match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(name), _), (Expr::List(params), _), body @ ..]
+                if keyword == "defun" =>
+            {
+                let mut scope = params_scope(params);
+                scope.insert(name);
+                check_body(body, scopes, scope, unbound);
+            }
+            [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..]
+                if is_let(keyword) =>
+            {
+                let mut scope = HashSet::new();
+                for (binding, _) in bindings {
+                    if let Expr::List(binding) = binding {
+                        if let [(Expr::Ident(name), _), value] = binding.as_slice() {
+                            if keyword == "let*" {
+                                scopes.push(scope);
+                                check_expr(value, scopes, unbound);
+                                scope = scopes.pop().unwrap_or_default();
+                            } else {
+                                check_expr(value, scopes, unbound);
+                            }
+                            scope.insert(name.as_str());
+                        }
+                    }
+                }
+                check_body(body, scopes, scope, unbound);
+            }
+            _ => {
+                for item in items {
+                    check_expr(item, scopes, unbound);
+                }
+            }
+        },
+    }
+}
+
+/// Reports `let` bindings and `defun` parameters that nothing in their scope
+/// refers to, pointing at the name. Names starting with `_` are meant to be
+/// unused and are left out.
+pub fn check_unused(ast: &[Spanned<Expr>]) -> Vec<(Span, String)> {
+    let mut locals = vec![];
+    let mut scopes = vec![];
+    track_body(ast, &mut scopes, HashMap::new(), &mut locals);
+    let mut unused: Vec<_> = locals
+        .into_iter()
+        .filter(|(name, _, used)| !used && !name.starts_with('_'))
+        .map(|(name, span, _)| (span.clone(), format!("unused variable '{}'", name)))
+        .collect();
+    unused.sort_by_key(|(span, _)| span.start);
+    unused
+}
+
+/// A scope maps each name to its entry in the locals, or to `None` for
+/// `defun` names, which shadow but are never reported.
+type UsageScope<'a> = HashMap<&'a str, Option<usize>>;
+
+fn track_body<'a>(
+    body: &'a [Spanned<Expr>],
+    scopes: &mut Vec<UsageScope<'a>>,
+    mut scope: UsageScope<'a>,
+    locals: &mut Vec<(&'a str, &'a Span, bool)>,
+) {
+    scope.extend(
+        body.iter()
+            .filter_map(|(expr, _)| defun_name(expr))
+            .map(|name| (name, None)),
+    );
+    scopes.push(scope);
+    for expr in body {
+        track_expr(expr, scopes, locals);
+    }
+    scopes.pop();
+}
+
+fn track_expr<'a>(
+    (expr, _): &'a Spanned<Expr>,
+    scopes: &mut Vec<UsageScope<'a>>,
+    locals: &mut Vec<(&'a str, &'a Span, bool)>,
+) {
+    match expr {
+        Expr::Number(_) | Expr::Ratio(_) | Expr::Str(_) | Expr::Quote(_) => {}
+        Expr::Ident(name) => {
+            let local = scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name.as_str()));
+            if let Some(Some(index)) = local {
+                locals[*index].2 = true;
+            }
+        }
+        Expr::List(items) => match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(name), _), (Expr::List(params), _), body @ ..]
+                if keyword == "defun" =>
+            {
+                let mut scope = HashMap::from([(name.as_str(), None)]);
+                for param in params {
+                    declare(param, &mut scope, locals);
+                }
+                track_body(body, scopes, scope, locals);
+            }
+            [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..]
+                if is_let(keyword) =>
+            {
+                let mut scope = HashMap::new();
+                for (binding, _) in bindings {
+                    if let Expr::List(binding) = binding {
+                        if let [name, value] = binding.as_slice() {
+                            if keyword == "let*" {
+                                scopes.push(scope);
+                                track_expr(value, scopes, locals);
+                                scope = scopes.pop().unwrap_or_default();
+                            } else {
+                                track_expr(value, scopes, locals);
+                            }
+                            declare(name, &mut scope, locals);
+                        }
+                    }
+                }
+                track_body(body, scopes, scope, locals);
+            }
+            _ => {
+                for item in items {
+                    track_expr(item, scopes, locals);
+                }
+            }
+        },
+    }
+}
+
+fn declare<'a>(
+    (name, span): &'a Spanned<Expr>,
+    scope: &mut UsageScope<'a>,
+    locals: &mut Vec<(&'a str, &'a Span, bool)>,
+) {
+    if let Expr::Ident(name) = name {
+        scope.insert(name, Some(locals.len()));
+        locals.push((name, span, false));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameKind {
+    Function,
+    Variable,
+}
+
+/// A name introduced by a `defun`, a parameter or a `let` binding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: String,
+    pub kind: NameKind,
+    /// The name token.
+    pub span: Span,
+    /// The `defun` form for functions and parameters, the `(name value)`
+    /// pair for `let` bindings.
+    pub form_span: Span,
+}
+
+impl Binding {
+    fn new(name: &str, kind: NameKind, span: &Span, form_span: &Span) -> Self {
+        Binding {
+            name: name.to_string(),
+            kind,
+            span: span.clone(),
+            form_span: form_span.clone(),
+        }
+    }
+}
+
+/// Walks from the top level down to `offset`, returning the bindings in
+/// scope there, outermost first, and the atom under `offset` if any. A
+/// binding's own name counts as in scope, so resolving it finds itself.
+fn scope_at(ast: &[Spanned<Expr>], offset: usize) -> (Vec<Binding>, Option<&Spanned<Expr>>) {
+    let defuns = |body: &[Spanned<Expr>]| -> Vec<Binding> {
+        body.iter()
+            .filter_map(|(expr, form_span)| match expr {
+                Expr::List(items) => match items.as_slice() {
+                    [(Expr::Ident(keyword), _), (Expr::Ident(name), span), ..]
+                        if keyword == "defun" =>
+                    {
+                        Some(Binding::new(name, NameKind::Function, span, form_span))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    };
+    // The cursor may sit right after an atom, but only inside a list's parens.
+    let contains = |(expr, span): &Spanned<Expr>| match expr {
+        Expr::List(_) => span.start < offset && offset <= span.end,
+        _ => span.start <= offset && offset <= span.end,
+    };
+
+    let mut bindings = defuns(ast);
+    let mut forms = ast;
+    loop {
+        let Some(form) = forms.iter().find(|form| contains(form)) else {
+            return (bindings, None);
+        };
+        let Expr::List(items) = &form.0 else {
+            return (bindings, Some(form));
+        };
+        forms = match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(name), name_span), params @ (Expr::List(_), _), body @ ..]
+                if keyword == "defun" =>
+            {
+                bindings.push(Binding::new(name, NameKind::Function, name_span, &form.1));
+                let Expr::List(param_list) = &params.0 else {
+                    unreachable!()
+                };
+                for (param, span) in param_list {
+                    if let Expr::Ident(param) = param {
+                        bindings.push(Binding::new(param, NameKind::Variable, span, &form.1));
+                    }
+                }
+                bindings.extend(defuns(body));
+                if contains(&items[1]) {
+                    return (bindings, Some(&items[1]));
+                }
+                if contains(params) {
+                    param_list
+                } else {
+                    body
+                }
+            }
+            [(Expr::Ident(keyword), _), (Expr::List(binding_list), bindings_span), body @ ..]
+                if is_let(keyword) =>
+            {
+                let names =
+                    binding_list
+                        .iter()
+                        .filter_map(|(binding, binding_span)| match binding {
+                            Expr::List(binding) => match binding.as_slice() {
+                                [name @ (Expr::Ident(_), _), _] => Some((name, binding_span)),
+                                _ => None,
+                            },
+                            _ => None,
+                        });
+                let to_binding = |((name, span), form_span): (&Spanned<Expr>, &Span)| match name {
+                    Expr::Ident(name) => Binding::new(name, NameKind::Variable, span, form_span),
+                    _ => unreachable!(),
+                };
+                if bindings_span.end <= offset {
+                    bindings.extend(names.map(to_binding));
+                    bindings.extend(defuns(body));
+                    body
+                } else {
+                    // Each `let*` value sees the bindings before it.
+                    if keyword == "let*" {
+                        bindings.extend(
+                            names
+                                .clone()
+                                .filter(|(_, form_span)| form_span.end <= offset)
+                                .map(to_binding),
+                        );
+                    }
+                    if let Some(name) = names.clone().find(|(name, _)| contains(name)) {
+                        bindings.push(to_binding(name));
+                        return (bindings, Some(name.0));
+                    }
+                    binding_list
+                }
+            }
+            _ => items,
+        };
+    }
+}
+
+/// Lists the names visible at `offset`, innermost first: `defun`s of every
+/// enclosing body, parameters of enclosing `defun`s and bindings of
+/// enclosing `let` bodies. Shadowed names are left out.
+pub fn names_in_scope(ast: &[Spanned<Expr>], offset: usize) -> Vec<(String, NameKind)> {
+    let (bindings, _) = scope_at(ast, offset);
+    let mut seen = HashSet::new();
+    bindings
+        .into_iter()
+        .rev()
+        .filter(|binding| seen.insert(binding.name.clone()))
+        .map(|binding| (binding.name, binding.kind))
+        .collect()
+}
+
+/// Resolves the identifier at `offset` to the nearest binding of its name,
+/// following lexical scope.
+pub fn find_definition(ast: &[Spanned<Expr>], offset: usize) -> Option<Binding> {
+    let (bindings, target) = scope_at(ast, offset);
+    let (Expr::Ident(name), _) = target? else {
+        return None;
+    };
+    bindings
+        .into_iter()
+        .rev()
+        .find(|binding| &binding.name == name)
+}
+
+/// Finds the name tokens of every identifier resolving to `binding`, its
+/// own name included.
+pub fn find_references(ast: &[Spanned<Expr>], binding: &Binding) -> Vec<Span> {
+    let mut references = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, span)) = stack.pop() {
+        match expr {
+            Expr::List(items) => stack.extend(items.iter().rev()),
+            Expr::Ident(name)
+                if *name == binding.name
+                    && find_definition(ast, span.start).as_ref() == Some(binding) =>
+            {
+                references.push(span.clone())
+            }
+            _ => {}
+        }
+    }
+    references
+}
+
+/// A call: a list headed by an identifier. `defun` and `let` forms are
+/// definitions rather than calls and are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub name: String,
+    /// The head identifier.
+    pub span: Span,
+    /// The innermost `defun` the call is made from, if any.
+    pub caller: Option<Binding>,
+}
+
+/// Lists every call in `ast` in source order.
+pub fn collect_calls(ast: &[Spanned<Expr>]) -> Vec<Call> {
+    let mut calls = vec![];
+    for expr in ast {
+        collect_expr_calls(expr, None, &mut calls);
+    }
+    calls
+}
+
+fn collect_expr_calls(
+    (expr, span): &Spanned<Expr>,
+    caller: Option<&Binding>,
+    calls: &mut Vec<Call>,
+) {
+    let Expr::List(items) = expr else {
+        return;
+    };
+    match items.as_slice() {
+        [(Expr::Ident(keyword), _), (Expr::Ident(name), name_span), (Expr::List(_), _), body @ ..]
+            if keyword == "defun" =>
+        {
+            let defun = Binding::new(name, NameKind::Function, name_span, span);
+            for expr in body {
+                collect_expr_calls(expr, Some(&defun), calls);
+            }
+        }
+        [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..] if is_let(keyword) => {
+            for (binding, _) in bindings {
+                if let Expr::List(binding) = binding {
+                    if let [(Expr::Ident(_), _), value] = binding.as_slice() {
+                        collect_expr_calls(value, caller, calls);
+                    }
+                }
+            }
+            for expr in body {
+                collect_expr_calls(expr, caller, calls);
+            }
+        }
+        _ => {
+            if let Some((Expr::Ident(name), head_span)) = items.first() {
+                calls.push(Call {
+                    name: name.clone(),
+                    span: head_span.clone(),
+                    caller: caller.cloned(),
+                });
+            }
+            for expr in items {
+                collect_expr_calls(expr, caller, calls);
+            }
+        }
+    }
+}
+
+/// An outline entry: a `defun` or a `let` binding, with the ones nested
+/// inside it as children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: NameKind,
+    /// The whole `defun` form or `(name value)` pair.
+    pub span: Span,
+    /// The name token.
+    pub selection_span: Span,
+    pub children: Vec<Symbol>,
+}
+
+/// Builds the outline of `ast` in source order.
+pub fn collect_symbols(ast: &[Spanned<Expr>]) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    for expr in ast {
+        collect_expr_symbols(expr, &mut symbols);
+    }
+    symbols
+}
+
+fn collect_expr_symbols((expr, span): &Spanned<Expr>, symbols: &mut Vec<Symbol>) {
+    let Expr::List(items) = expr else {
+        return;
+    };
+    match items.as_slice() {
+        [(Expr::Ident(keyword), _), (Expr::Ident(name), name_span), (Expr::List(_), _), body @ ..]
+            if keyword == "defun" =>
+        {
+            symbols.push(Symbol {
+                name: name.clone(),
+                kind: NameKind::Function,
+                span: span.clone(),
+                selection_span: name_span.clone(),
+                children: collect_symbols(body),
+            });
+        }
+        [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..] if is_let(keyword) => {
+            for (binding, binding_span) in bindings {
+                if let Expr::List(binding) = binding {
+                    if let [(Expr::Ident(name), name_span), value] = binding.as_slice() {
+                        symbols.push(Symbol {
+                            name: name.clone(),
+                            kind: NameKind::Variable,
+                            span: binding_span.clone(),
+                            selection_span: name_span.clone(),
+                            children: collect_symbols(std::slice::from_ref(value)),
+                        });
+                    }
+                }
+            }
+            symbols.extend(collect_symbols(body));
+        }
+        _ => symbols.extend(collect_symbols(items)),
+    }
+}
+
+/// Reports calls whose argument count doesn't match the called `defun` or
+/// builtin, pointing at the whole call form, and `cond` clauses that aren't
+/// a test and an expression, pointing at the clause.
+pub fn check_arity(ast: &[Spanned<Expr>]) -> Vec<(Span, String)> {
+    let mut arities = HashMap::new();
+    for defun in collect_defuns(ast) {
+        arities
+            .entry(defun.name.0)
+            .or_insert(Arity::Exact(defun.params.len()));
+    }
+
+    let mut mismatches = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, span)) = stack.pop() {
+        let Expr::List(items) = expr else {
+            continue;
+        };
+        match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(_), _), (Expr::List(_), _), body @ ..]
+                if keyword == "defun" =>
+            {
+                stack.extend(body.iter().rev());
+            }
+            [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..]
+                if is_let(keyword) =>
+            {
+                stack.extend(body.iter().rev());
+                for (binding, _) in bindings.iter().rev() {
+                    if let Expr::List(binding) = binding {
+                        stack.extend(binding.iter().skip(1).rev());
+                    }
+                }
+            }
+            [(Expr::Ident(keyword), _), clauses @ ..] if keyword == "cond" => {
+                for (clause, clause_span) in clauses {
+                    if cond_clause(clause).is_none() {
+                        mismatches.push((
+                            clause_span.clone(),
+                            "'cond' clause must be a (test expr) list".to_string(),
+                        ));
+                    }
+                }
+                // A clause is a test and an expression rather than a call.
+                for (clause, _) in clauses.iter().rev() {
+                    stack.extend(cond_clause(clause).into_iter().flatten().rev());
+                }
+            }
+            [(Expr::Ident(name), _), args @ ..] => {
+                let arity = arities.get(name).copied().or_else(|| builtin(name)?.arity);
+                if let Some(arity) = arity {
+                    if !arity.accepts(args.len()) {
+                        mismatches.push((
+                            span.clone(),
+                            format!("'{}' expects {} arguments, got {}", name, arity, args.len()),
+                        ));
+                    }
+                }
+                stack.extend(args.iter().rev());
+            }
+            _ => stack.extend(items.iter().rev()),
+        }
+    }
+    mismatches
+}
+
+/// Reports literal arguments of the arithmetic and comparison builtins that
+/// can't be numbers, like the string in `(+ 1 "two")` or the boolean in
+/// `(* true 3)`, pointing at the argument. Identifiers and calls could be
+/// anything, so only literals are checked.
+pub fn check_types(ast: &[Spanned<Expr>]) -> Vec<(Span, String)> {
+    let defuns: HashSet<String> = collect_defuns(ast)
+        .into_iter()
+        .map(|defun| defun.name.0)
+        .collect();
+
+    let mut mismatches = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, _)) = stack.pop() {
+        let Expr::List(items) = expr else {
+            continue;
+        };
+        match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(_), _), (Expr::List(_), _), body @ ..]
+                if keyword == "defun" =>
+            {
+                stack.extend(body.iter().rev());
+            }
+            [(Expr::Ident(keyword), _), (Expr::List(bindings), _), body @ ..]
+                if is_let(keyword) =>
+            {
+                stack.extend(body.iter().rev());
+                for (binding, _) in bindings.iter().rev() {
+                    if let Expr::List(binding) = binding {
+                        stack.extend(binding.iter().skip(1).rev());
+                    }
+                }
+            }
+            [(Expr::Ident(name), _), args @ ..] => {
+                let numeric = ["+", "-", "*", "/", "=", "==", "!=", "<", ">", "<=", ">="]
+                    .contains(&name.as_str());
+                if numeric && !defuns.contains(name) {
+                    for (arg, span) in args {
+                        let found = match arg {
+                            Expr::Str(_) => "a string",
+                            Expr::Ident(ident) if ident == "true" || ident == "false" => {
+                                "a boolean"
+                            }
+                            _ => continue,
+                        };
+                        mismatches.push((
+                            span.clone(),
+                            format!("'{}' expects numbers, got {}", name, found),
+                        ));
+                    }
+                }
+                stack.extend(args.iter().rev());
+            }
+            _ => stack.extend(items.iter().rev()),
+        }
+    }
+    mismatches
+}
+
+/// A top-level `defun` whose name was already defined earlier in the file.
+#[derive(Debug, PartialEq)]
+pub struct DuplicateDefun {
+    pub name: String,
+    pub span: Span,
+    pub first_span: Span,
+}
+
+/// Finds top-level `defun`s that redefine an earlier one, pointing at the
+/// name tokens of both definitions.
+pub fn check_duplicate_defuns(ast: &[Spanned<Expr>]) -> Vec<DuplicateDefun> {
+    let mut first_spans: HashMap<&str, &Span> = HashMap::new();
+    let mut duplicates = vec![];
+    for (expr, _) in ast {
+        let Expr::List(items) = expr else {
+            continue;
+        };
+        if let [(Expr::Ident(keyword), _), (Expr::Ident(name), span), ..] = items.as_slice() {
+            if keyword != "defun" {
+                continue;
+            }
+            match first_spans.get(name.as_str()) {
+                Some(first_span) => duplicates.push(DuplicateDefun {
+                    name: name.clone(),
+                    span: span.clone(),
+                    first_span: (*first_span).clone(),
+                }),
+                None => {
+                    first_spans.insert(name, span);
+                }
+            }
+        }
+    }
+    duplicates
+}
+
+/// Finds the forms that can never run because an earlier sibling in the same
+/// body always fails, i.e. is a call of one of `diverging`. Bodies are the
+/// top level and the bodies of `defun` and `let`. Each span covers a run of
+/// unreachable siblings.
+pub fn check_unreachable(ast: &[Spanned<Expr>], diverging: &[String]) -> Vec<Span> {
+    let diverges = |(expr, _): &Spanned<Expr>| match expr {
+        Expr::List(items) => matches!(
+            items.first(),
+            Some((Expr::Ident(name), _)) if diverging.contains(name)
+        ),
+        _ => false,
+    };
+
+    let mut unreachable = vec![];
+    let mut bodies = vec![ast];
+    while let Some(body) = bodies.pop() {
+        if let Some(index) = body.iter().position(diverges) {
+            if let (Some((_, first)), Some((_, last))) = (body.get(index + 1), body.last()) {
+                unreachable.push(first.start..last.end);
+            }
+        }
+        for (expr, _) in body {
+            let Expr::List(items) = expr else {
+                continue;
+            };
+            match items.as_slice() {
+                [(Expr::Ident(keyword), _), _, (Expr::List(_), _), body @ ..]
+                    if keyword == "defun" =>
+                {
+                    bodies.push(body)
+                }
+                [(Expr::Ident(keyword), _), (Expr::List(_), _), body @ ..] if is_let(keyword) => {
+                    bodies.push(body)
+                }
+                _ => {}
+            }
+        }
+    }
+    unreachable.sort_by_key(|span| span.start);
+    unreachable
+}
+
+fn defun_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::List(items) => match items.as_slice() {
+            [(Expr::Ident(keyword), _), (Expr::Ident(name), _), ..] if keyword == "defun" => {
+                Some(name)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn params_scope(params: &[Spanned<Expr>]) -> HashSet<&str> {
+    params
+        .iter()
+        .filter_map(|(param, _)| match param {
+            Expr::Ident(param) => Some(param.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The spans of the expressions around `offset`, innermost first: the
+/// expression there, then each list or quote it is part of. A quoted
+/// expression is followed by its quote, which includes the `'`.
+pub fn enclosing_spans(ast: &[Spanned<Expr>], offset: usize) -> Vec<Span> {
+    let mut spans = vec![];
+    let contains = |span: &Span| span.start <= offset && offset <= span.end;
+    let mut expr = ast.iter().find(|(_, span)| contains(span));
+    while let Some((inner, span)) = expr {
+        spans.push(span.clone());
+        expr = match inner {
+            Expr::List(items) => items.iter().find(|(_, span)| contains(span)),
+            Expr::Quote(quoted) => Some(quoted.as_ref()).filter(|(_, span)| contains(span)),
+            _ => None,
+        };
+    }
+    spans.reverse();
+    spans
+}
+
+/// Finds the innermost list around `offset` if it is built only from
+/// literals, quoted data, the builtin constants and calls of builtin
+/// functions without effects, so evaluating it can't depend on anything
+/// else. Lists inside quoted data aren't forms, so the quoted whole is
+/// found instead.
+pub fn constant_list_at(ast: &[Spanned<Expr>], offset: usize) -> Option<&Spanned<Expr>> {
+    let defuns: HashSet<String> = collect_defuns(ast)
+        .into_iter()
+        .map(|defun| defun.name.0)
+        .collect();
+    let mut innermost = None;
+    let mut items = ast;
+    while let Some(expr) = items.iter().find(|(_, span)| span.contains(&offset)) {
+        let list = match &expr.0 {
+            Expr::List(list) => list,
+            Expr::Quote(quoted) if matches!(quoted.0, Expr::List(_)) => {
+                innermost = Some(expr);
+                break;
+            }
+            _ => break,
+        };
+        innermost = Some(expr);
+        items = list;
+    }
+    innermost.filter(|expr| is_constant(expr, &defuns))
+}
+
+fn is_constant((expr, _): &Spanned<Expr>, defuns: &HashSet<String>) -> bool {
+    match expr {
+        Expr::Number(_) | Expr::Ratio(_) | Expr::Str(_) => true,
+        Expr::Ident(name) => {
+            name == "true"
+                || name == "false"
+                || builtin(name).is_some_and(|builtin| builtin.kind == BuiltinKind::Constant)
+        }
+        Expr::List(items) => match items.split_first() {
+            Some(((Expr::Ident(head), _), args)) => {
+                let pure = builtin(head).is_some_and(|builtin| {
+                    builtin.kind == BuiltinKind::Function
+                        && !["print", "error"].contains(&head.as_str())
+                });
+                pure && !defuns.contains(head) && args.iter().all(|arg| is_constant(arg, defuns))
+            }
+            _ => false,
+        },
+        Expr::Quote(_) => true,
+    }
+}
+
+/// Finds the colors written in `ast`: `(rgb r g b)` calls with integer
+/// channels from 0 to 255 and `"#rrggbb"` strings.
+pub fn collect_colors(ast: &[Spanned<Expr>]) -> Vec<(Span, [u8; 3])> {
+    let channel = |(expr, _): &Spanned<Expr>| match expr {
+        Expr::Number(literal) => number_value(literal)
+            .filter(|value| value.fract() == 0.0 && (0.0..=255.0).contains(value))
+            .map(|value| value as u8),
+        _ => None,
+    };
+
+    let mut colors = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, span)) = stack.pop() {
+        match expr {
+            Expr::List(items) => {
+                if let [(Expr::Ident(head), _), red, green, blue] = items.as_slice() {
+                    if let (true, Some(red), Some(green), Some(blue)) =
+                        (head == "rgb", channel(red), channel(green), channel(blue))
+                    {
+                        colors.push((span.clone(), [red, green, blue]));
+                    }
+                }
+                stack.extend(items.iter().rev());
+            }
+            Expr::Str(text) => colors.extend(hex_color(text).map(|rgb| (span.clone(), rgb))),
+            _ => {}
+        }
+    }
+    colors
+}
+
+fn hex_color(text: &str) -> Option<[u8; 3]> {
+    let digits = text.strip_prefix('#')?;
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(&digits[index..index + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    fn unbound(source: &str) -> Vec<(Span, String)> {
+        check_unbound(&parse(source).ast)
+    }
+
+    #[test]
+    fn unused_locals() {
+        let unused = |source| check_unused(&parse(source).ast);
+        assert_eq!(
+            unused("(let ((x 1)) 2)"),
+            vec![(7..8, "unused variable 'x'".to_string())]
+        );
+        assert_eq!(unused("(let ((_x 1)) 2)"), vec![]);
+        assert_eq!(
+            unused("(defun f (n) 1)"),
+            vec![(10..11, "unused variable 'n'".to_string())]
+        );
+        assert_eq!(
+            unused("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))"),
+            vec![]
+        );
+        // An inner binding of the same name shadows the outer one, and a
+        // value is evaluated before its own binding exists.
+        assert_eq!(
+            unused("(defun f (x) (let ((x x) (y 1)) (let ((x 2)) x)))"),
+            vec![
+                (20..21, "unused variable 'x'".to_string()),
+                (26..27, "unused variable 'y'".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_after_diverging_forms() {
+        let diverging = vec!["error".to_string(), "exit".to_string()];
+        let source = "(print 1)\n(let ((x 1)) (exit) (print x) x)\n(if true (error \"a\") 2)\n(error \"b\")\n(print 2)";
+        let ast = parse(source).ast;
+        let spans: Vec<_> = check_unreachable(&ast, &diverging)
+            .into_iter()
+            .map(|span| &source[span])
+            .collect();
+        assert_eq!(spans, vec!["(print x) x", "(print 2)"]);
+        assert!(check_unreachable(&ast, &[]).is_empty());
+    }
+
+    #[test]
+    fn check_unbound_variables() {
+        assert_eq!(
+            unbound("(print undefined_var)"),
+            vec![(7..20, "unbound variable 'undefined_var'".into())]
+        );
+        assert!(unbound("(defun f (x) (+ x 1))").is_empty());
+        assert!(unbound("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))").is_empty());
+        assert!(unbound("(defun f () (g))\n(defun g () (f))").is_empty());
+        assert!(unbound("(let ((x 1) (y 2)) (+ x y))").is_empty());
+
+        let names: Vec<_> = unbound("(defun f (x) (+ x y))\n(+ x (let ((y x)) y))")
+            .into_iter()
+            .map(|(span, _)| span)
+            .collect();
+        assert_eq!(names, vec![18..19, 25..26, 36..37]);
+
+        assert_eq!(
+            unbound("(let ((x 1) (y x)) y)"),
+            vec![(15..16, "unbound variable 'x'".into())]
+        );
+        assert!(unbound("(let* ((x 1) (y x)) y)").is_empty());
+    }
+
+    #[test]
+    fn check_duplicate_defun_names() {
+        let source = "(defun f (x) x)\n(defun g () 1)\n(defun f (y) y)\n(let () (defun g () 2))";
+        assert_eq!(
+            check_duplicate_defuns(&parse(source).ast),
+            vec![DuplicateDefun {
+                name: "f".into(),
+                span: 38..39,
+                first_span: 7..8,
+            }]
+        );
+    }
+
+    #[test]
+    fn check_call_arity() {
+        let fact = "(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))\n";
+        let mismatches = check_arity(&parse(&format!("{}(fact 1 2)", fact)).ast);
+        assert_eq!(
+            mismatches,
+            vec![(53..63, "'fact' expects 1 arguments, got 2".into())]
+        );
+        assert!(check_arity(&parse(&format!("{}(fact 1)", fact)).ast).is_empty());
+        assert!(check_arity(&parse("(+ 1 2 3)").ast).is_empty());
+
+        let mismatches = check_arity(&parse("(let ((x (+))) (= x))").ast);
+        let spans: Vec<_> = mismatches.into_iter().map(|(span, _)| span).collect();
+        assert_eq!(spans, vec![9..12, 15..20]);
+
+        // Clauses aren't calls, so `((x) 1)` doesn't call `x` with one
+        // argument, but a clause without an expression is malformed.
+        let mismatches =
+            check_arity(&parse("(defun x () 1)\n(cond ((x) 1) (else) (true (= 1)))").ast);
+        assert_eq!(
+            mismatches,
+            vec![
+                (29..35, "'cond' clause must be a (test expr) list".into()),
+                (42..47, "'=' expects 2 arguments, got 1".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn literal_type_mismatches() {
+        assert_eq!(
+            check_types(&parse("(+ 1 \"x\")").ast),
+            vec![(5..8, "'+' expects numbers, got a string".into())]
+        );
+        assert_eq!(
+            check_types(&parse("(print (* true 3))").ast),
+            vec![(10..14, "'*' expects numbers, got a boolean".into())]
+        );
+        assert_eq!(
+            check_types(&parse("(== 1 true)").ast),
+            vec![(6..10, "'==' expects numbers, got a boolean".into())]
+        );
+        assert!(check_types(&parse("(defun f (n) (+ 1 n 3/4 (g)))").ast).is_empty());
+        assert!(check_types(&parse("(defun + (a b) a) (+ \"a\" \"b\")").ast).is_empty());
+    }
+
+    #[test]
+    fn constant_lists() {
+        let ast = parse("(+ n (* 2 (- pi 1)) (print 1))").ast;
+        let span = |offset| constant_list_at(&ast, offset).map(|(_, span)| span.clone());
+        assert_eq!(span(1), None);
+        assert_eq!(span(6), Some(5..19));
+        assert_eq!(span(12), Some(10..18));
+        assert_eq!(span(21), None);
+
+        let ast = parse("(defun * (a b) a)\n(* 2 3)").ast;
+        assert_eq!(constant_list_at(&ast, 19), None);
+
+        let ast = parse("(print '(1 (2 3)))").ast;
+        let span = |offset| constant_list_at(&ast, offset).map(|(_, span)| span.clone());
+        assert_eq!(span(12), Some(7..17));
+        assert_eq!(span(1), None);
+    }
+
+    #[test]
+    fn names_in_scope_at_offset() {
+        let source = "(defun fact (n) )\n(defun g (x) (let ((n 1)) (+ n x)))";
+        let ast = parse(source).ast;
+        assert_eq!(
+            names_in_scope(&ast, 16),
+            vec![
+                ("n".into(), NameKind::Variable),
+                ("fact".into(), NameKind::Function),
+                ("g".into(), NameKind::Function),
+            ]
+        );
+
+        let names: Vec<_> = names_in_scope(&ast, 48)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["n", "x", "g", "fact"]);
+        let names: Vec<_> = names_in_scope(&ast, 0)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["g", "fact"]);
+    }
+
+    #[test]
+    fn find_definition_follows_scope() {
+        let source = "(defun fact (n) (* n (fact (- n 1))))\n(let ((n 2) (m n)) (+ n m))";
+        let ast = parse(source).ast;
+        let definition = |offset| find_definition(&ast, offset).map(|binding| binding.span);
+
+        assert_eq!(definition(19), Some(13..14));
+        assert_eq!(definition(13), Some(13..14));
+        assert_eq!(definition(22), Some(7..11));
+        assert_eq!(definition(7), Some(7..11));
+        assert_eq!(definition(60), Some(45..46));
+        assert_eq!(definition(53), None);
+        let binding = find_definition(&ast, 60).unwrap();
+        assert_eq!(binding.form_span, 44..49);
+        assert_eq!(find_definition(&ast, 19).unwrap().form_span, 0..37);
+        assert_eq!(definition(17), None);
+
+        // A `let*` value resolves to the bindings before it.
+        let ast = parse("(let* ((n 2) (m n)) m)").ast;
+        let definition = |offset| find_definition(&ast, offset).map(|binding| binding.span);
+        assert_eq!(definition(16), Some(8..9));
+        assert_eq!(definition(20), Some(14..15));
+    }
+
+    #[test]
+    fn find_references_respects_shadowing() {
+        let source = "(defun f (n) (+ n (let ((n 1)) n)))\n(f n)";
+        let ast = parse(source).ast;
+        let param = find_definition(&ast, 10).unwrap();
+        assert_eq!(find_references(&ast, &param), vec![10..11, 16..17]);
+        let f = find_definition(&ast, 37).unwrap();
+        assert_eq!(find_references(&ast, &f), vec![7..8, 37..38]);
+    }
+
+    #[test]
+    fn collect_calls_with_callers() {
+        let source = "(defun f (x) (let ((y (g x))) (h y)))\n(f 1)";
+        let calls = collect_calls(&parse(source).ast);
+        let found: Vec<_> = calls
+            .iter()
+            .map(|call| {
+                let caller = call.caller.as_ref().map(|caller| caller.name.as_str());
+                (call.name.as_str(), call.span.clone(), caller)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("g", 23..24, Some("f")),
+                ("h", 31..32, Some("f")),
+                ("f", 39..40, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn collect_rgb_and_hex_colors() {
+        let source = "(fill (rgb 255 128 0) \"#00FF7f\")\n(rgb 256 0 0) (rgb 1.5 0 0) \"#fff\"";
+        assert_eq!(
+            collect_colors(&parse(source).ast),
+            vec![(6..21, [255, 128, 0]), (22..31, [0, 255, 127])]
+        );
+    }
+
+    #[test]
+    fn collect_nested_symbols() {
+        let source = "(defun f (x)\n  (let ((y (* x 2)))\n    (defun g () y)\n    (g)))\n(print 1)";
+        let symbols = collect_symbols(&parse(source).ast);
+        assert_eq!(symbols.len(), 1);
+        let f = &symbols[0];
+        assert_eq!((f.name.as_str(), f.span.clone()), ("f", 0..62));
+        let children: Vec<_> = f
+            .children
+            .iter()
+            .map(|symbol| (symbol.name.as_str(), symbol.kind, symbol.span.clone()))
+            .collect();
+        assert_eq!(
+            children,
+            vec![
+                ("y", NameKind::Variable, 21..32),
+                ("g", NameKind::Function, 38..52),
+            ]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+
+/// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinKind {
+    /// Applied to its evaluated arguments like a `defun`.
+    Function,
+    /// A special form or boolean the evaluator handles itself.
+    Keyword,
+    /// A named number every program starts with.
+    Constant,
+}
+
+/// A name the evaluator provides without a definition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub kind: BuiltinKind,
+    /// `None` when the form checks its own shape, like `defun`.
+    pub arity: Option<Arity>,
+    /// What it does, for completion and hover docs.
+    pub doc: &'static str,
+    /// The value of a constant.
+    pub value: Option<f64>,
+}
+
+const fn function(name: &'static str, arity: Arity, doc: &'static str) -> Builtin {
+    Builtin {
+        name,
+        kind: BuiltinKind::Function,
+        arity: Some(arity),
+        doc,
+        value: None,
+    }
+}
+
+const fn keyword(name: &'static str, arity: Option<Arity>, doc: &'static str) -> Builtin {
+    Builtin {
+        name,
+        kind: BuiltinKind::Keyword,
+        arity,
+        doc,
+        value: None,
+    }
+}
+
+const fn constant(name: &'static str, value: f64, doc: &'static str) -> Builtin {
+    Builtin {
+        name,
+        kind: BuiltinKind::Constant,
+        arity: None,
+        doc,
+        value: Some(value),
+    }
+}
+
+/// The single list of builtins. The evaluator, diagnostics, completion and
+/// hover all read it, so they can't disagree about what exists.
+const BUILTINS: &[Builtin] = &[
+    function("+", Arity::AtLeast(1), "Addition: sums its arguments."),
+    function(
+        "-",
+        Arity::AtLeast(1),
+        "Subtraction: subtracts the other arguments from the first, or negates a single argument.",
+    ),
+    function(
+        "*",
+        Arity::AtLeast(1),
+        "Multiplication: multiplies its arguments.",
+    ),
+    function(
+        "/",
+        Arity::AtLeast(1),
+        "Division: divides the first argument by the others. Dividing by zero is an error.",
+    ),
+    function(
+        "=",
+        Arity::Exact(2),
+        "Equality: whether both arguments are equal.",
+    ),
+    function(
+        "==",
+        Arity::Exact(2),
+        "Equality: the same as `=`.",
+    ),
+    function(
+        "<",
+        Arity::Exact(2),
+        "Whether the first number is less than the second.",
+    ),
+    function(
+        ">",
+        Arity::Exact(2),
+        "Whether the first number is greater than the second.",
+    ),
+    function(
+        "!=",
+        Arity::Exact(2),
+        "Inequality: whether the arguments differ.",
+    ),
+    function(
+        "<=",
+        Arity::Exact(2),
+        "Whether the first number is less than or equal to the second.",
+    ),
+    function(
+        ">=",
+        Arity::Exact(2),
+        "Whether the first number is greater than or equal to the second.",
+    ),
+    function("print", Arity::Exact(1), "Prints its argument."),
+    function(
+        "error",
+        Arity::Exact(1),
+        "Stops evaluation with its argument as the error message.",
+    ),
+    keyword("true", None, "The boolean true."),
+    keyword("false", None, "The boolean false."),
+    keyword(
+        "if",
+        Some(Arity::Exact(3)),
+        "`(if condition then else)` evaluates `then` when the boolean `condition` is true, `else` otherwise.",
+    ),
+    keyword(
+        "cond",
+        None,
+        "`(cond (test expr)...)` evaluates the `expr` of the first clause whose boolean `test` is true.",
+    ),
+    keyword(
+        "else",
+        None,
+        "The test of a `cond` clause that matches when no clause before it does.",
+    ),
+    keyword(
+        "defun",
+        None,
+        "`(defun name (params...) body...)` defines a function and returns it.",
+    ),
+    keyword(
+        "let",
+        None,
+        "`(let ((name value)...) body...)` binds each name to its value, then evaluates the body with them in scope. The values are evaluated outside the bindings.",
+    ),
+    keyword(
+        "let*",
+        None,
+        "`(let* ((name value)...) body...)` binds each name in turn like `let`, but each value sees the bindings before it.",
+    ),
+    constant(
+        "pi",
+        std::f64::consts::PI,
+        "The ratio of a circle's circumference to its diameter.",
+    ),
+    constant("e", std::f64::consts::E, "Euler's number."),
+];
+
+pub fn builtins() -> &'static [Builtin] {
+    BUILTINS
+}
+
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
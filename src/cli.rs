@@ -0,0 +1,129 @@
+use crate::format::{format_source, FormatOptions};
+use crate::{offset_to_position, parse_error_message, PositionEncoding};
+use chumsky::error::Simple;
+use orelang_but_rust::parser::{highlight, parse};
+use ropey::Rope;
+use std::fs;
+use std::process::ExitCode;
+
+const USAGE: &str =
+    "usage: orelang-but-rust [parse FILE | highlight FILE | fmt FILE [--write | --check]]";
+
+/// Runs the command line mode for `args` (without the program name).
+pub fn run(args: &[String]) -> ExitCode {
+    match args {
+        [command, file] if command == "parse" => parse_file(file),
+        [command, file] if command == "highlight" => highlight_file(file),
+        [command, file] if command == "fmt" => format_file(file, None),
+        [command, file, flag] if command == "fmt" && (flag == "--write" || flag == "--check") => {
+            format_file(file, Some(flag))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn read(file: &str) -> Option<String> {
+    fs::read_to_string(file)
+        .map_err(|err| eprintln!("{}: {}", file, err))
+        .ok()
+}
+
+/// Prints the AST of `file` one top-level form per line, then its parse
+/// errors. Fails when there are any errors.
+fn parse_file(file: &str) -> ExitCode {
+    let Some(source) = read(file) else {
+        return ExitCode::FAILURE;
+    };
+    let result = parse(&source);
+    for expr in &result.ast {
+        println!("{:?}", expr);
+    }
+
+    let rope = Rope::from_str(&source);
+    for err in &result.parse_errors {
+        println!("error: {}", error_message(file, &rope, err));
+    }
+    if result.parse_errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints the highlights of `file`, one per line as its char span, token
+/// type and modifiers.
+fn highlight_file(file: &str) -> ExitCode {
+    let Some(source) = read(file) else {
+        return ExitCode::FAILURE;
+    };
+    for (span, token_type, modifiers) in highlight(&source) {
+        let mut line = format!("{:?} {}", span, token_type.as_str());
+        for modifier in modifiers {
+            line.push(' ');
+            line.push_str(modifier.as_str());
+        }
+        println!("{}", line);
+    }
+    ExitCode::SUCCESS
+}
+
+fn error_message(file: &str, rope: &Rope, err: &Simple<String>) -> String {
+    // Columns count chars, like editors show them.
+    match offset_to_position(rope, err.span().start, PositionEncoding::Utf32) {
+        Some(position) => format!(
+            "{}:{}:{}: {}",
+            file,
+            position.line + 1,
+            position.character + 1,
+            parse_error_message(err)
+        ),
+        None => format!("{}: {}", file, parse_error_message(err)),
+    }
+}
+
+/// Prints the formatted `file`, or with `--write` writes it back in place.
+/// With `--check` nothing is written, and it fails if the file would change.
+fn format_file(file: &str, flag: Option<&str>) -> ExitCode {
+    let Some(source) = read(file) else {
+        return ExitCode::FAILURE;
+    };
+    let formatted = match format_source(&source, &FormatOptions::default()) {
+        Ok(formatted) => formatted,
+        Err(err) => {
+            let rope = Rope::from_str(&source);
+            let position = offset_to_position(&rope, err.span.start, PositionEncoding::Utf32)
+                .unwrap_or_default();
+            eprintln!(
+                "{}:{}:{}: {}",
+                file,
+                position.line + 1,
+                position.character + 1,
+                err
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match flag {
+        Some("--check") if formatted != source => {
+            eprintln!("{} is not formatted", file);
+            ExitCode::FAILURE
+        }
+        Some("--check") => ExitCode::SUCCESS,
+        Some(_) if formatted != source => match fs::write(file, formatted) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("{}: {}", file, err);
+                ExitCode::FAILURE
+            }
+        },
+        Some(_) => ExitCode::SUCCESS,
+        None => {
+            print!("{}", formatted);
+            ExitCode::SUCCESS
+        }
+    }
+}
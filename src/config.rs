@@ -0,0 +1,154 @@
+use crate::parser::DEFAULT_MAX_DEPTH;
+use serde::Deserialize;
+use std::time::Duration;
+use tower_lsp::lsp_types::DiagnosticSeverity;
+
+/// Server settings, read from `initializationOptions`. Missing fields keep
+/// their defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    pub enable_diagnostics: bool,
+    pub enable_eval_diagnostics: bool,
+    pub indent_width: usize,
+    pub debounce_ms: u64,
+    /// Emit semantic tokens for parens tagged with their nesting depth.
+    pub rainbow_parens: bool,
+    /// Evaluate a document when it is saved and log the result.
+    pub eval_on_save: bool,
+    /// Lists nested deeper than this are reported and left out of the AST.
+    pub max_depth: usize,
+    /// Lay the whole document out again on format. Otherwise formatting only
+    /// trims trailing whitespace.
+    pub reflow: bool,
+    /// How each category of diagnostic is reported, keyed by category name.
+    pub diagnostic_severities: DiagnosticSeverities,
+    /// Calls of these never return, so forms after them in a body are
+    /// reported as unreachable.
+    pub diverging_forms: Vec<String>,
+    /// Log how long each parse took and what it produced.
+    pub verbose: bool,
+}
+
+/// The severity a diagnostic category is reported with, or `Off` to drop it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warning,
+    Information,
+    Hint,
+    Off,
+}
+
+impl Level {
+    pub fn severity(self) -> Option<DiagnosticSeverity> {
+        match self {
+            Level::Error => Some(DiagnosticSeverity::ERROR),
+            Level::Warning => Some(DiagnosticSeverity::WARNING),
+            Level::Information => Some(DiagnosticSeverity::INFORMATION),
+            Level::Hint => Some(DiagnosticSeverity::HINT),
+            Level::Off => None,
+        }
+    }
+}
+
+/// The level of each diagnostic category. Categories left out of the
+/// settings keep their default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DiagnosticSeverities {
+    /// An unclosed `(` or a stray `)`.
+    pub unbalanced_parens: Level,
+    pub unbound_variable: Level,
+    /// A call with the wrong number of arguments.
+    pub arity_mismatch: Level,
+    /// A literal that can't be an operand of an arithmetic builtin.
+    pub type_mismatch: Level,
+    /// A `defun` of a name defined earlier in the document.
+    pub duplicate_defun: Level,
+}
+
+impl Default for DiagnosticSeverities {
+    fn default() -> Self {
+        DiagnosticSeverities {
+            unbalanced_parens: Level::Error,
+            unbound_variable: Level::Warning,
+            arity_mismatch: Level::Error,
+            type_mismatch: Level::Error,
+            duplicate_defun: Level::Warning,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enable_diagnostics: true,
+            enable_eval_diagnostics: false,
+            indent_width: 2,
+            debounce_ms: 150,
+            rainbow_parens: false,
+            eval_on_save: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            reflow: true,
+            diagnostic_severities: DiagnosticSeverities::default(),
+            diverging_forms: vec!["error".into()],
+            verbose: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings, falling back to the defaults when they are absent
+    /// or malformed.
+    pub fn from_value(value: Option<&serde_json::Value>) -> Self {
+        value
+            .and_then(|value| Config::deserialize(value).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn config_from_value() {
+        assert_eq!(Config::from_value(None), Config::default());
+
+        let config = Config::from_value(Some(&json!({
+            "enableDiagnostics": false,
+            "indentWidth": 4,
+        })));
+        assert_eq!(
+            config,
+            Config {
+                enable_diagnostics: false,
+                indent_width: 4,
+                ..Default::default()
+            }
+        );
+
+        let config = Config::from_value(Some(&json!({
+            "diagnosticSeverities": { "unbound-variable": "error", "duplicate-defun": "off" },
+        })));
+        assert_eq!(
+            config.diagnostic_severities,
+            DiagnosticSeverities {
+                unbound_variable: Level::Error,
+                duplicate_defun: Level::Off,
+                ..Default::default()
+            }
+        );
+
+        let malformed = json!({ "enableDiagnostics": "no", "debounceMs": 10 });
+        assert_eq!(Config::from_value(Some(&malformed)), Config::default());
+        assert_eq!(Config::from_value(Some(&json!(null))), Config::default());
+    }
+}
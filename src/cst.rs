@@ -0,0 +1,256 @@
+use crate::parser::Span;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CstKind {
+    LParen,
+    RParen,
+    Quote,
+    Number,
+    Str,
+    Ident,
+    Comment,
+    Whitespace,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstToken {
+    pub kind: CstKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CstNode {
+    Token(CstToken),
+    /// A parenthesized form. `children` starts with its `(` and ends with its
+    /// `)` unless the form is unclosed.
+    List {
+        children: Vec<CstNode>,
+        span: Span,
+    },
+}
+
+/// A lossless syntax tree. Unlike the token stream from `parser::lexer`, it
+/// keeps whitespace and comments as nodes, so printing it gives back the
+/// exact source and comments stay next to the code around them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstTree {
+    pub children: Vec<CstNode>,
+}
+
+impl fmt::Display for CstNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CstNode::Token(token) => f.write_str(&token.text),
+            CstNode::List { children, .. } => children.iter().try_for_each(|child| child.fmt(f)),
+        }
+    }
+}
+
+impl fmt::Display for CstTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.children.iter().try_for_each(|child| child.fmt(f))
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '\'' | ';' | '"')
+}
+
+/// Splits `source` into tokens covering every char, trivia included. Spans
+/// are char offsets like the rest of the parser. A comment, or a leading
+/// `#!` line, stops before the line break, so a `\r` of a CRLF ending stays
+/// whitespace.
+pub fn tokenize(source: &str) -> Vec<CstToken> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        let rest = &chars[start..];
+        let (kind, len) = match rest[0] {
+            '#' if start == 0 && rest.get(1) == Some(&'!') => (
+                CstKind::Comment,
+                rest.iter()
+                    .position(|c| *c == '\n' || *c == '\r')
+                    .unwrap_or(rest.len()),
+            ),
+            '(' => (CstKind::LParen, 1),
+            ')' => (CstKind::RParen, 1),
+            '\'' => (CstKind::Quote, 1),
+            ';' => (
+                CstKind::Comment,
+                rest.iter()
+                    .position(|c| *c == '\n' || *c == '\r')
+                    .unwrap_or(rest.len()),
+            ),
+            '"' => {
+                let mut escaped = false;
+                let close = rest[1..].iter().position(|c| {
+                    let close = *c == '"' && !escaped;
+                    escaped = *c == '\\' && !escaped;
+                    close
+                });
+                (CstKind::Str, close.map_or(rest.len(), |close| close + 2))
+            }
+            c if c.is_whitespace() => (
+                CstKind::Whitespace,
+                rest.iter()
+                    .position(|c| !c.is_whitespace())
+                    .unwrap_or(rest.len()),
+            ),
+            c => (
+                if c.is_ascii_digit() || c == '-' && rest.get(1).is_some_and(char::is_ascii_digit) {
+                    CstKind::Number
+                } else {
+                    CstKind::Ident
+                },
+                rest.iter()
+                    .position(|c| is_delimiter(*c))
+                    .unwrap_or(rest.len()),
+            ),
+        };
+        tokens.push(CstToken {
+            kind,
+            text: chars[start..start + len].iter().collect(),
+            span: start..start + len,
+        });
+        start += len;
+    }
+    tokens
+}
+
+/// Builds the lossless tree of `source`. It never fails: a stray `)` stays a
+/// token where it appears and an unclosed `(` runs to the end of the source.
+pub fn to_cst(source: &str) -> CstTree {
+    let mut stack: Vec<(usize, Vec<CstNode>)> = vec![];
+    let mut top_level = vec![];
+
+    for token in tokenize(source) {
+        let node = match token.kind {
+            CstKind::LParen => {
+                stack.push((token.span.start, vec![CstNode::Token(token)]));
+                continue;
+            }
+            CstKind::RParen => match stack.pop() {
+                Some((start, mut children)) => {
+                    let span = start..token.span.end;
+                    children.push(CstNode::Token(token));
+                    CstNode::List { children, span }
+                }
+                None => CstNode::Token(token),
+            },
+            _ => CstNode::Token(token),
+        };
+        match stack.last_mut() {
+            Some((_, children)) => children.push(node),
+            None => top_level.push(node),
+        }
+    }
+
+    let end = source.chars().count();
+    while let Some((start, children)) = stack.pop() {
+        let node = CstNode::List {
+            children,
+            span: start..end,
+        };
+        match stack.last_mut() {
+            Some((_, children)) => children.push(node),
+            None => top_level.push(node),
+        }
+    }
+
+    CstTree {
+        children: top_level,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn comments(nodes: &[CstNode], found: &mut Vec<String>) {
+        for node in nodes {
+            match node {
+                CstNode::Token(token) if token.kind == CstKind::Comment => {
+                    found.push(token.text.clone())
+                }
+                CstNode::Token(_) => {}
+                CstNode::List { children, .. } => comments(children, found),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_preserves_comments() {
+        let source = "; factorial\n(defun fact (n) ; n >= 0\n  (if (= n 0)\n      1 ; base\n      (* n (fact (- n 1)))))\n; end";
+        let tree = to_cst(source);
+        assert_eq!(tree.to_string(), source);
+
+        let mut found = vec![];
+        comments(&tree.children, &mut found);
+        assert_eq!(found, vec!["; factorial", "; n >= 0", "; base", "; end"]);
+    }
+
+    #[test]
+    fn comments_attach_inside_their_form() {
+        let tree = to_cst("(f ; why\r\n x)");
+        let [CstNode::List { children, span }] = tree.children.as_slice() else {
+            panic!("expected a single list");
+        };
+        assert_eq!(span, &(0..13));
+        let tokens: Vec<_> = children
+            .iter()
+            .map(|child| match child {
+                CstNode::Token(token) => token,
+                CstNode::List { .. } => panic!("unexpected list"),
+            })
+            .collect();
+        let kinds: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CstKind::LParen,
+                CstKind::Ident,
+                CstKind::Whitespace,
+                CstKind::Comment,
+                CstKind::Whitespace,
+                CstKind::Ident,
+                CstKind::RParen,
+            ]
+        );
+        assert_eq!(tokens[3].span, 3..8);
+    }
+
+    #[test]
+    fn negative_numbers() {
+        let tree = to_cst("(- -5 x)");
+        let [CstNode::List { children, .. }] = tree.children.as_slice() else {
+            panic!("expected a single list");
+        };
+        let kinds: Vec<_> = children
+            .iter()
+            .filter_map(|child| match child {
+                CstNode::Token(token) if token.kind != CstKind::Whitespace => Some(token.kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CstKind::LParen,
+                CstKind::Ident,
+                CstKind::Number,
+                CstKind::Ident,
+                CstKind::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_unbalanced_source() {
+        for source in ["(f (g \"a \\\" b\"", "f) (g)) ;", "\"open"] {
+            assert_eq!(to_cst(source).to_string(), source);
+        }
+    }
+}
@@ -0,0 +1,1189 @@
+use crate::builtins::{builtin, builtins, Arity, Builtin, BuiltinKind};
+use crate::parser::{cond_clause, number_value, Expr, Span, Spanned};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Number(f64),
+    /// An exact fraction, always reduced and with a positive denominator.
+    Ratio(i64, i64),
+    Bool(bool),
+    Str(String),
+    /// Quoted data like `'(1 2 3)`.
+    List(Vec<Value>),
+    Function(Rc<Function>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Ratio(a, b), Value::Ratio(c, d)) => (a, b) == (c, d),
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Significant digits kept when printing a number, enough to hide the noise
+/// of binary fractions like `0.1 + 0.2`.
+const DISPLAY_PRECISION: usize = 15;
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a as i64
+}
+
+/// Reduces `numerator/denominator`, or `None` if the denominator is zero or
+/// the result doesn't fit.
+fn reduce(numerator: i64, denominator: i64) -> Option<(i64, i64)> {
+    if denominator == 0 {
+        return None;
+    }
+    let divisor = gcd(numerator, denominator).checked_mul(denominator.signum())?;
+    Some((
+        numerator.checked_div(divisor)?,
+        denominator.checked_div(divisor)?,
+    ))
+}
+
+/// Applies one of `+ - * /` to two ratios exactly, or returns `None` when
+/// the result overflows.
+fn ratio_op(name: &str, (a, b): (i64, i64), (c, d): (i64, i64)) -> Option<(i64, i64)> {
+    match name {
+        "+" => reduce(
+            a.checked_mul(d)?.checked_add(c.checked_mul(b)?)?,
+            b.checked_mul(d)?,
+        ),
+        "-" => reduce(
+            a.checked_mul(d)?.checked_sub(c.checked_mul(b)?)?,
+            b.checked_mul(d)?,
+        ),
+        "*" => reduce(a.checked_mul(c)?, b.checked_mul(d)?),
+        _ => reduce(a.checked_mul(d)?, b.checked_mul(c)?),
+    }
+}
+
+/// Folds `ratios` with one of `+ - * /` like the float arithmetic does, or
+/// returns `None` when there are none or the result overflows.
+fn ratio_arithmetic(
+    name: &str,
+    ratios: &[(i64, i64)],
+    span: &Span,
+) -> Result<Option<Value>, EvalError> {
+    let (first, rest) = match ratios {
+        [] => return Ok(None),
+        [_] if name == "-" => ((0, 1), ratios),
+        [_] if name == "/" => ((1, 1), ratios),
+        [first, rest @ ..] => (*first, rest),
+    };
+    if name == "/" && rest.iter().any(|(numerator, _)| *numerator == 0) {
+        return Err(EvalError::DivByZero { span: span.clone() });
+    }
+    let result = rest
+        .iter()
+        .try_fold(first, |acc, ratio| ratio_op(name, acc, *ratio));
+    Ok(result.map(|(numerator, denominator)| Value::Ratio(numerator, denominator)))
+}
+
+/// Reads a `3/4` literal.
+fn ratio_value(literal: &str) -> Option<Value> {
+    let (numerator, denominator) = literal.split_once('/')?;
+    let (numerator, denominator) = reduce(numerator.parse().ok()?, denominator.parse().ok()?)?;
+    Some(Value::Ratio(numerator, denominator))
+}
+
+impl Value {
+    /// Formats the value for the eval command and inlay hints. Integral
+    /// numbers print without a fraction and the rest are rounded to
+    /// `DISPLAY_PRECISION` significant digits.
+    pub fn to_display_string(&self) -> String {
+        match self {
+            Value::Number(n) => {
+                let rounded = format!("{:.*e}", DISPLAY_PRECISION - 1, n);
+                rounded.parse::<f64>().unwrap_or(*n).to_string()
+            }
+            Value::Ratio(numerator, 1) => numerator.to_string(),
+            Value::Ratio(numerator, denominator) => format!("{}/{}", numerator, denominator),
+            Value::Bool(b) => b.to_string(),
+            Value::Str(s) => format!("{:?}", s),
+            Value::List(items) => {
+                let items: Vec<_> = items.iter().map(Value::to_display_string).collect();
+                format!("({})", items.join(" "))
+            }
+            Value::Function(function) => format!("#<function {}>", function.name),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_display_string())
+    }
+}
+
+/// A user function closing over the scope it was defined in. The scope
+/// holds the function under its name, so the function only holds it weakly
+/// to avoid a reference cycle.
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    body: Vec<Spanned<Expr>>,
+    scope: Weak<RefCell<Scope>>,
+    budget: Rc<Budget>,
+}
+
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Function")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why evaluation stopped, with the span of the form responsible.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    Unbound {
+        name: String,
+        span: Span,
+    },
+    /// A call of a name that is neither bound nor a builtin function.
+    UnknownFunction {
+        name: String,
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: Arity,
+        got: usize,
+        span: Span,
+    },
+    TypeError {
+        span: Span,
+        msg: String,
+    },
+    DivByZero {
+        span: Span,
+    },
+    /// A form or literal that can't be evaluated, like a `let` without
+    /// bindings.
+    Malformed {
+        span: Span,
+        msg: String,
+    },
+    /// Raised by the program itself with `error`.
+    Raised {
+        span: Span,
+        message: String,
+    },
+    StepLimitExceeded {
+        limit: usize,
+        span: Span,
+    },
+    DepthLimitExceeded {
+        limit: usize,
+        span: Span,
+    },
+    Cancelled {
+        span: Span,
+    },
+}
+
+impl EvalError {
+    /// The form the error is reported at.
+    pub fn span(&self) -> &Span {
+        match self {
+            EvalError::Unbound { span, .. }
+            | EvalError::UnknownFunction { span, .. }
+            | EvalError::ArityMismatch { span, .. }
+            | EvalError::TypeError { span, .. }
+            | EvalError::DivByZero { span }
+            | EvalError::Malformed { span, .. }
+            | EvalError::Raised { span, .. }
+            | EvalError::StepLimitExceeded { span, .. }
+            | EvalError::DepthLimitExceeded { span, .. }
+            | EvalError::Cancelled { span } => span,
+        }
+    }
+
+    fn type_error(msg: impl Into<String>, span: &Span) -> Self {
+        EvalError::TypeError {
+            span: span.clone(),
+            msg: msg.into(),
+        }
+    }
+
+    fn malformed(msg: impl Into<String>, span: &Span) -> Self {
+        EvalError::Malformed {
+            span: span.clone(),
+            msg: msg.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::Unbound { name, .. } => write!(f, "unbound variable '{}'", name),
+            EvalError::UnknownFunction { name, .. } => write!(f, "unknown function '{}'", name),
+            EvalError::ArityMismatch {
+                name,
+                expected,
+                got,
+                ..
+            } => write!(f, "'{}' expects {} arguments, got {}", name, expected, got),
+            EvalError::TypeError { msg, .. }
+            | EvalError::Malformed { msg, .. }
+            | EvalError::Raised { message: msg, .. } => f.write_str(msg),
+            EvalError::DivByZero { .. } => f.write_str("division by zero"),
+            EvalError::StepLimitExceeded { limit, .. } => {
+                write!(f, "evaluation exceeded {} steps", limit)
+            }
+            EvalError::DepthLimitExceeded { limit, .. } => {
+                write!(f, "evaluation exceeded a depth of {}", limit)
+            }
+            EvalError::Cancelled { .. } => f.write_str("evaluation was cancelled"),
+        }
+    }
+}
+
+/// Upper bound on reductions before evaluation gives up, so a runaway
+/// program can't hang the server.
+pub const MAX_STEPS: usize = 1_000_000;
+
+/// Upper bound on nested evaluation, kept well below what the Rust stack
+/// can take.
+pub const MAX_DEPTH: usize = 500;
+
+#[derive(Debug)]
+struct Budget {
+    steps: Cell<usize>,
+    max_steps: usize,
+    depth: Cell<usize>,
+    /// Set from another thread to stop evaluation at the next step.
+    cancelled: OnceCell<Arc<AtomicBool>>,
+}
+
+impl Budget {
+    fn enter(&self, span: &Span) -> Result<(), EvalError> {
+        if let Some(cancelled) = self.cancelled.get() {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(EvalError::Cancelled { span: span.clone() });
+            }
+        }
+        if self.steps.get() >= self.max_steps {
+            return Err(EvalError::StepLimitExceeded {
+                limit: self.max_steps,
+                span: span.clone(),
+            });
+        }
+        if self.depth.get() >= MAX_DEPTH {
+            return Err(EvalError::DepthLimitExceeded {
+                limit: MAX_DEPTH,
+                span: span.clone(),
+            });
+        }
+        self.steps.set(self.steps.get() + 1);
+        self.depth.set(self.depth.get() + 1);
+        Ok(())
+    }
+
+    fn exit(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Scope {
+    vars: HashMap<String, Value>,
+    parent: Option<Env>,
+}
+
+/// A lexical scope mapping names to values. Cloning an `Env` shares the
+/// scope, and `child` opens a nested scope that falls back to its parent.
+/// All scopes derived from one root share its step budget.
+#[derive(Debug, Clone)]
+pub struct Env {
+    scope: Rc<RefCell<Scope>>,
+    budget: Rc<Budget>,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::with_max_steps(MAX_STEPS)
+    }
+}
+
+impl Env {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A root scope holding the builtin constants like `pi`.
+    pub fn with_max_steps(max_steps: usize) -> Self {
+        let env = Env {
+            scope: Default::default(),
+            budget: Rc::new(Budget {
+                steps: Cell::new(0),
+                max_steps,
+                depth: Cell::new(0),
+                cancelled: OnceCell::new(),
+            }),
+        };
+        for builtin in builtins() {
+            if let Some(value) = builtin.value {
+                env.define(builtin.name, Value::Number(value));
+            }
+        }
+        env
+    }
+
+    /// Defines `globals` in this scope, next to the constants. Like any
+    /// binding there, they are shadowed by parameters and `let` bindings.
+    pub fn with_globals(self, globals: HashMap<String, Value>) -> Self {
+        for (name, value) in globals {
+            self.define(name, value);
+        }
+        self
+    }
+
+    /// Stops evaluation with an error once `cancelled` is set, for callers
+    /// evaluating on another thread.
+    pub fn with_cancellation(self, cancelled: Arc<AtomicBool>) -> Self {
+        let _ = self.budget.cancelled.set(cancelled);
+        self
+    }
+
+    pub fn child(&self) -> Self {
+        Env {
+            scope: Rc::new(RefCell::new(Scope {
+                vars: HashMap::new(),
+                parent: Some(self.clone()),
+            })),
+            budget: self.budget.clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let scope = self.scope.borrow();
+        match scope.vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => scope.parent.as_ref()?.get(name),
+        }
+    }
+
+    pub fn define(&self, name: impl Into<String>, value: Value) {
+        self.scope.borrow_mut().vars.insert(name.into(), value);
+    }
+}
+
+pub fn eval(expr: &Spanned<Expr>, env: &mut Env) -> Result<Value, EvalError> {
+    env.budget.enter(&expr.1)?;
+    // The call is applied inside this level, so nested calls count towards
+    // the depth.
+    let result = match eval_expr(expr, env) {
+        Ok(Tail::Value(value)) => Ok(value),
+        Ok(Tail::Call(call)) => apply(*call),
+        Err(err) => Err(err),
+    };
+    env.budget.exit();
+    result
+}
+
+/// What evaluating an expression in tail position leaves to do.
+enum Tail {
+    Value(Value),
+    /// A call of a user function, left for `apply` to run. Boxed to keep
+    /// the stack frames of nested evaluation small.
+    Call(Box<Call>),
+}
+
+impl Tail {
+    /// Leaves a call of `function` to `apply`, or fails once the scope it
+    /// was defined in has ended.
+    fn call(function: Rc<Function>, values: Vec<Value>, span: &Span) -> Result<Tail, EvalError> {
+        let Some(scope) = function.scope.upgrade() else {
+            return Err(EvalError::malformed(
+                format!(
+                    "'{}' can't be called once the scope it was defined in has ended",
+                    function.name
+                ),
+                span,
+            ));
+        };
+        let env = Env {
+            scope,
+            budget: function.budget.clone(),
+        };
+        Ok(Tail::Call(Box::new(Call {
+            function,
+            env,
+            values,
+            span: span.clone(),
+        })))
+    }
+}
+
+struct Call {
+    function: Rc<Function>,
+    /// The scope the function was defined in, kept alive until the call is
+    /// made.
+    env: Env,
+    values: Vec<Value>,
+    span: Span,
+}
+
+/// Evaluates `expr` in tail position. A call of a user function comes back
+/// unapplied, so `apply` runs tail calls in a loop rather than recursing,
+/// and tail-recursive functions can loop without running out of stack.
+fn eval_tail(expr: &Spanned<Expr>, env: &mut Env) -> Result<Tail, EvalError> {
+    env.budget.enter(&expr.1)?;
+    let result = eval_expr(expr, env);
+    env.budget.exit();
+    result
+}
+
+fn eval_expr(expr: &Spanned<Expr>, env: &mut Env) -> Result<Tail, EvalError> {
+    let (expr, span) = expr;
+    let value = match expr {
+        Expr::Number(n) => number_value(n)
+            .map(Value::Number)
+            .ok_or_else(|| EvalError::malformed(format!("invalid number '{}'", n), span))?,
+        Expr::Ratio(ratio) => ratio_value(ratio).ok_or_else(|| {
+            EvalError::malformed(
+                format!(
+                    "invalid ratio '{}': the denominator is zero or too large",
+                    ratio
+                ),
+                span,
+            )
+        })?,
+        Expr::Str(s) => Value::Str(s.clone()),
+        Expr::Ident(name) => match name.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => env.get(name).ok_or_else(|| EvalError::Unbound {
+                name: name.clone(),
+                span: span.clone(),
+            })?,
+        },
+        Expr::Quote(quoted) => quoted_value(quoted, env)?,
+        Expr::List(items) => {
+            let Some(((head, head_span), args)) = items.split_first() else {
+                return Err(EvalError::malformed("cannot evaluate an empty list", span));
+            };
+            let Expr::Ident(name) = head else {
+                return Err(EvalError::type_error("expected a function name", head_span));
+            };
+            match name.as_str() {
+                "if" => return eval_if(args, env, span),
+                "cond" => return eval_cond(args, env, span),
+                "defun" => eval_defun(args, env, span)?,
+                "let" | "let*" => return eval_let(name, args, env, span),
+                _ => {
+                    let values = args
+                        .iter()
+                        .map(|arg| eval(arg, env))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    match env.get(name) {
+                        Some(Value::Function(function)) => {
+                            return Tail::call(function, values, span);
+                        }
+                        Some(_) => {
+                            return Err(EvalError::type_error(
+                                format!("'{}' is not a function", name),
+                                head_span,
+                            ))
+                        }
+                        None => apply_builtin(name, head_span, args, &values, span)?,
+                    }
+                }
+            }
+        }
+    };
+    Ok(Tail::Value(value))
+}
+
+/// The value of quoted data: literals stand for themselves and lists for
+/// the list of their items' values, none of them evaluated as forms.
+fn quoted_value(quoted: &Spanned<Expr>, env: &mut Env) -> Result<Value, EvalError> {
+    match &quoted.0 {
+        Expr::Number(_) | Expr::Ratio(_) | Expr::Str(_) => eval(quoted, env),
+        Expr::Ident(name) if name == "true" || name == "false" => eval(quoted, env),
+        Expr::Ident(_) => Err(EvalError::malformed(
+            "quoted symbols can't be evaluated",
+            &quoted.1,
+        )),
+        Expr::List(items) => items
+            .iter()
+            .map(|item| quoted_value(item, env))
+            .collect::<Result<_, _>>()
+            .map(Value::List),
+        Expr::Quote(quoted) => quoted_value(quoted, env),
+    }
+}
+
+/// Evaluates top-level forms in order and returns the value of the last one.
+pub fn eval_program(ast: &[Spanned<Expr>], env: &mut Env) -> Result<Option<Value>, EvalError> {
+    hoist_defuns(ast, env);
+    let mut result = None;
+    for expr in ast {
+        result = Some(eval(expr, env)?);
+    }
+    Ok(result)
+}
+
+/// Evaluates a body, its last form in tail position.
+fn eval_body(body: &[Spanned<Expr>], env: &mut Env, span: &Span) -> Result<Tail, EvalError> {
+    let Some((last, init)) = body.split_last() else {
+        return Err(EvalError::malformed("expected a body", span));
+    };
+    hoist_defuns(body, env);
+    for expr in init {
+        eval(expr, env)?;
+    }
+    eval_tail(last, env)
+}
+
+/// Defines the functions of a body's `defun`s before any of its forms run,
+/// so they can call those defined after them, mutual recursion included.
+/// Each `defun` defines its function again when reached, so a redefinition
+/// still only takes effect from there on. Malformed ones are left to fail
+/// then.
+fn hoist_defuns(body: &[Spanned<Expr>], env: &mut Env) {
+    for (expr, span) in body {
+        if let Expr::List(items) = expr {
+            if let [(Expr::Ident(keyword), _), args @ ..] = items.as_slice() {
+                if keyword == "defun" {
+                    let _ = eval_defun(args, env, span);
+                }
+            }
+        }
+    }
+}
+
+fn eval_defun(args: &[Spanned<Expr>], env: &mut Env, span: &Span) -> Result<Value, EvalError> {
+    let [(Expr::Ident(name), _), (Expr::List(params), _), body @ ..] = args else {
+        return Err(EvalError::malformed(
+            "'defun' expects a name, a parameter list and a body",
+            span,
+        ));
+    };
+    let params = params
+        .iter()
+        .map(|(param, param_span)| match param {
+            Expr::Ident(param) => Ok(param.clone()),
+            _ => Err(EvalError::malformed("parameter must be a name", param_span)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let function = Value::Function(Rc::new(Function {
+        name: name.clone(),
+        params,
+        body: body.to_vec(),
+        scope: Rc::downgrade(&env.scope),
+        budget: env.budget.clone(),
+    }));
+    env.define(name.clone(), function.clone());
+    Ok(function)
+}
+
+/// Evaluates a `let` form, whose values are all evaluated in the outer
+/// scope, or a `let*` form, where each value sees the bindings before it.
+fn eval_let(
+    keyword: &str,
+    args: &[Spanned<Expr>],
+    env: &mut Env,
+    span: &Span,
+) -> Result<Tail, EvalError> {
+    let [(Expr::List(bindings), _), body @ ..] = args else {
+        return Err(EvalError::malformed(
+            format!("'{}' expects a binding list and a body", keyword),
+            span,
+        ));
+    };
+    let mut scope = env.child();
+    for (binding, binding_span) in bindings {
+        let Expr::List(binding) = binding else {
+            return Err(EvalError::malformed(
+                "binding must be a (name value) list",
+                binding_span,
+            ));
+        };
+        let [(Expr::Ident(name), _), value] = binding.as_slice() else {
+            return Err(EvalError::malformed(
+                "binding must be a (name value) list",
+                binding_span,
+            ));
+        };
+        let value = if keyword == "let*" {
+            eval(value, &mut scope)?
+        } else {
+            eval(value, env)?
+        };
+        scope.define(name.clone(), value);
+    }
+    eval_body(body, &mut scope, span)
+}
+
+/// Makes `call`, then each call its body leaves in tail position in turn.
+fn apply(mut call: Call) -> Result<Value, EvalError> {
+    loop {
+        let Call {
+            function,
+            env,
+            values,
+            span,
+        } = call;
+        if function.params.len() != values.len() {
+            return Err(EvalError::ArityMismatch {
+                name: function.name.clone(),
+                expected: Arity::Exact(function.params.len()),
+                got: values.len(),
+                span,
+            });
+        }
+        let mut scope = env.child();
+        for (param, value) in function.params.iter().zip(values) {
+            scope.define(param.clone(), value);
+        }
+        match eval_body(&function.body, &mut scope, &span)? {
+            Tail::Value(value) => return Ok(value),
+            Tail::Call(next) => call = *next,
+        }
+    }
+}
+
+fn eval_if(args: &[Spanned<Expr>], env: &mut Env, span: &Span) -> Result<Tail, EvalError> {
+    let [cond, then, otherwise] = args else {
+        return Err(EvalError::ArityMismatch {
+            name: "if".into(),
+            expected: Arity::Exact(3),
+            got: args.len(),
+            span: span.clone(),
+        });
+    };
+    match eval(cond, env)? {
+        Value::Bool(true) => eval_tail(then, env),
+        Value::Bool(false) => eval_tail(otherwise, env),
+        _ => Err(EvalError::type_error(
+            "'if' condition must be a boolean",
+            &cond.1,
+        )),
+    }
+}
+
+/// Evaluates the expression of the first clause whose test is true, or of
+/// the `else` clause when one is reached first.
+fn eval_cond(args: &[Spanned<Expr>], env: &mut Env, span: &Span) -> Result<Tail, EvalError> {
+    for (clause, clause_span) in args {
+        let Some([test, expr]) = cond_clause(clause) else {
+            return Err(EvalError::malformed(
+                "'cond' clause must be a (test expr) list",
+                clause_span,
+            ));
+        };
+        if matches!(&test.0, Expr::Ident(name) if name == "else") {
+            return eval_tail(expr, env);
+        }
+        match eval(test, env)? {
+            Value::Bool(true) => return eval_tail(expr, env),
+            Value::Bool(false) => {}
+            _ => {
+                return Err(EvalError::type_error(
+                    "'cond' test must be a boolean",
+                    &test.1,
+                ))
+            }
+        }
+    }
+    Err(EvalError::malformed("no 'cond' clause matched", span))
+}
+
+fn apply_builtin(
+    name: &str,
+    name_span: &Span,
+    args: &[Spanned<Expr>],
+    values: &[Value],
+    span: &Span,
+) -> Result<Value, EvalError> {
+    let numbers = || {
+        values
+            .iter()
+            .zip(args)
+            .map(|(value, (_, arg_span))| match value {
+                Value::Number(n) => Ok(*n),
+                Value::Ratio(numerator, denominator) => Ok(*numerator as f64 / *denominator as f64),
+                _ => Err(EvalError::type_error(
+                    format!("'{}' expects numbers", name),
+                    arg_span,
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let Some(Builtin {
+        kind: BuiltinKind::Function,
+        arity: Some(arity),
+        ..
+    }) = builtin(name)
+    else {
+        return Err(EvalError::UnknownFunction {
+            name: name.into(),
+            span: name_span.clone(),
+        });
+    };
+    let arity_error = || EvalError::ArityMismatch {
+        name: name.into(),
+        expected: *arity,
+        got: values.len(),
+        span: span.clone(),
+    };
+    if !arity.accepts(values.len()) {
+        return Err(arity_error());
+    }
+
+    match name {
+        "+" | "-" | "*" | "/" => {
+            // Ratios stay exact as long as every operand is one and the
+            // result fits.
+            let ratios = values
+                .iter()
+                .map(|value| match value {
+                    Value::Ratio(numerator, denominator) => Some((*numerator, *denominator)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            if let Some(ratio) = ratios
+                .map(|ratios| ratio_arithmetic(name, &ratios, span))
+                .transpose()?
+                .flatten()
+            {
+                return Ok(ratio);
+            }
+
+            let numbers = numbers()?;
+            let Some((first, rest)) = numbers.split_first() else {
+                return Err(arity_error());
+            };
+            let result = match (name, rest.is_empty()) {
+                ("+", _) => numbers.iter().sum(),
+                ("*", _) => numbers.iter().product(),
+                ("-", true) => -first,
+                ("-", false) => rest.iter().fold(*first, |acc, n| acc - n),
+                ("/", true) => divide(1.0, *first, span)?,
+                _ => rest
+                    .iter()
+                    .try_fold(*first, |acc, n| divide(acc, *n, span))?,
+            };
+            Ok(Value::Number(result))
+        }
+        // There is no output to print to, so only the value is passed on.
+        "print" => match values {
+            [value] => Ok(value.clone()),
+            _ => Err(arity_error()),
+        },
+        "error" => match values {
+            [value] => Err(EvalError::Raised {
+                span: span.clone(),
+                message: match value {
+                    Value::Str(message) => message.clone(),
+                    value => value.to_display_string(),
+                },
+            }),
+            _ => Err(arity_error()),
+        },
+        "=" | "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+            let [a, b] = numbers()?[..] else {
+                return Err(arity_error());
+            };
+            let result = match name {
+                "=" | "==" => a == b,
+                "!=" => a != b,
+                "<" => a < b,
+                ">" => a > b,
+                "<=" => a <= b,
+                _ => a >= b,
+            };
+            Ok(Value::Bool(result))
+        }
+        _ => Err(EvalError::UnknownFunction {
+            name: name.into(),
+            span: name_span.clone(),
+        }),
+    }
+}
+
+fn divide(a: f64, b: f64, span: &Span) -> Result<f64, EvalError> {
+    if b == 0.0 {
+        Err(EvalError::DivByZero { span: span.clone() })
+    } else {
+        Ok(a / b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    fn eval_str(source: &str) -> Result<Value, EvalError> {
+        let ast = parse(source).ast;
+        eval(&ast[0], &mut Env::new())
+    }
+
+    #[test]
+    fn eval_arithmetic() {
+        assert_eq!(eval_str("(+ 1 (* 2 3))"), Ok(Value::Number(7.0)));
+        assert_eq!(eval_str("(- 10 4 1)"), Ok(Value::Number(5.0)));
+        assert_eq!(eval_str("(- 3)"), Ok(Value::Number(-3.0)));
+        assert_eq!(eval_str("(- 1 -2.5)"), Ok(Value::Number(3.5)));
+        assert_eq!(eval_str("(+ 0xff 1)"), Ok(Value::Number(256.0)));
+        assert_eq!(eval_str("(print (+ 1 2))"), Ok(Value::Number(3.0)));
+        assert_eq!(eval_str("(/ 9 2)"), Ok(Value::Number(4.5)));
+        assert_eq!(eval_str("(< 1 2)"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(<= 2 2)"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(>= 1 2)"), Ok(Value::Bool(false)));
+        assert_eq!(eval_str("(!= 1 2)"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(== 1 1)"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(== 1 2)"), Ok(Value::Bool(false)));
+        assert_eq!(eval_str("(if (= 1 2) 10 20)"), Ok(Value::Number(20.0)));
+        assert_eq!(eval_str("(if true 10 20)"), Ok(Value::Number(10.0)));
+    }
+
+    fn eval_program_str(source: &str) -> Result<Option<Value>, EvalError> {
+        let ast = parse(source).ast;
+        eval_program(&ast, &mut Env::new())
+    }
+
+    #[test]
+    fn eval_recursion() {
+        let source = "(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))\n(fact 5)";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(120.0))));
+    }
+
+    #[test]
+    fn eval_mutual_recursion_before_definitions() {
+        let source = "(even 10)
+(defun even (n) (if (= n 0) true (odd (- n 1))))
+(defun odd (n) (if (= n 0) false (even (- n 1))))
+(odd 7)";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Bool(true))));
+        let source = format!("{}\n(even 10)", source);
+        assert_eq!(eval_program_str(&source), Ok(Some(Value::Bool(true))));
+
+        // Bodies hoist their own `defun`s too.
+        let source = "(let ((n 4)) (print (twice n)) (defun twice (x) (* x 2)) (twice n))";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(8.0))));
+
+        // A redefinition takes effect where it is made.
+        let source = "(defun f () 1)\n(if (= (f) 1) 0 (error \"redefined early\"))\n(defun f () 2)";
+        assert!(eval_program_str(source).is_ok());
+    }
+
+    #[test]
+    fn eval_let_shadowing() {
+        let source = "(let ((x 1)) (+ (let ((x 10) (y x)) (+ x y)) x))";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(12.0))));
+    }
+
+    #[test]
+    fn eval_let_is_parallel_and_let_star_sequential() {
+        assert_eq!(
+            eval_program_str("(let ((x 1) (y x)) y)"),
+            Err(EvalError::Unbound {
+                name: "x".into(),
+                span: 15..16,
+            })
+        );
+        let source = "(let ((x 10)) (let ((x 1) (y x)) y))";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(10.0))));
+        let source = "(let* ((x 1) (y x)) y)";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(1.0))));
+        let source = "(let ((x 10)) (let* ((x 1) (y (+ x 1))) (+ x y)))";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(3.0))));
+        assert!(matches!(
+            eval_program_str("(let* x 1)"),
+            Err(EvalError::Malformed { msg, .. }) if msg == "'let*' expects a binding list and a body"
+        ));
+    }
+
+    #[test]
+    fn eval_cond_clauses() {
+        let cond = |x| {
+            let source = format!(
+                "(let ((x {})) (cond ((< x 0) \"negative\") ((= x 0) \"zero\") (else \"positive\")))",
+                x
+            );
+            eval_program_str(&source)
+        };
+        assert_eq!(cond(-1), Ok(Some(Value::Str("negative".into()))));
+        assert_eq!(cond(0), Ok(Some(Value::Str("zero".into()))));
+        assert_eq!(cond(1), Ok(Some(Value::Str("positive".into()))));
+
+        assert_eq!(
+            eval_program_str("(cond ((= 1 2) 1))"),
+            Err(EvalError::Malformed {
+                span: 0..18,
+                msg: "no 'cond' clause matched".into(),
+            })
+        );
+        assert_eq!(
+            eval_program_str("(cond ((= 1 2) 1) (true))"),
+            Err(EvalError::Malformed {
+                span: 18..24,
+                msg: "'cond' clause must be a (test expr) list".into(),
+            })
+        );
+        assert!(matches!(
+            eval_program_str("(cond (1 2))"),
+            Err(EvalError::TypeError { span, .. }) if span == (7..8)
+        ));
+    }
+
+    #[test]
+    fn eval_quoted_data() {
+        let value = eval_program_str("'(1 2 3)").unwrap().unwrap();
+        assert_eq!(
+            value,
+            Value::List(vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0)
+            ])
+        );
+        assert_eq!(value.to_string(), "(1 2 3)");
+        let value = eval_program_str("'(1/2 (\"a\" true) ())").unwrap().unwrap();
+        assert_eq!(value.to_string(), "(1/2 (\"a\" true) ())");
+        assert_eq!(eval_program_str("'5"), Ok(Some(Value::Number(5.0))));
+        assert_eq!(
+            eval_program_str("'(1 x)"),
+            Err(EvalError::Malformed {
+                span: 4..5,
+                msg: "quoted symbols can't be evaluated".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn eval_closure_captures_defining_scope() {
+        let source = "(defun outer (k) (defun inner (x) (+ x k)) (inner 1))\n(outer 41)";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(42.0))));
+    }
+
+    #[test]
+    fn functions_dont_keep_their_scope_alive() {
+        let mut env = Env::new();
+        let function = eval_program(&parse("(defun f (n) (f n))").ast, &mut env);
+        assert!(matches!(function, Ok(Some(Value::Function(_)))));
+        let scope = Rc::downgrade(&env.scope);
+        drop(env);
+        assert!(scope.upgrade().is_none());
+
+        let source = "(defun make (k) (defun add (x) (+ x k)))\n(let ((add (make 1))) (add 2))";
+        assert_eq!(
+            eval_program_str(source),
+            Err(EvalError::Malformed {
+                span: 63..70,
+                msg: "'add' can't be called once the scope it was defined in has ended".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn display_value() {
+        assert_eq!(Value::Number(120.0).to_string(), "120");
+        assert_eq!(Value::Number(4.5).to_string(), "4.5");
+        assert_eq!(Value::Bool(false).to_string(), "false");
+        let function = eval_program_str("(defun f (x) x)").unwrap().unwrap();
+        assert_eq!(function.to_string(), "#<function f>");
+        let list = Value::List(vec![Value::Number(1.0), Value::Str("a".into())]);
+        assert_eq!(list.to_string(), "(1 \"a\")");
+        assert_eq!(Value::List(vec![]).to_string(), "()");
+    }
+
+    #[test]
+    fn eval_exponent_literals() {
+        assert_eq!(eval_program_str("1.5e-3"), Ok(Some(Value::Number(0.0015))));
+        let value = eval_program_str("(* 1000 1.5e-3)").unwrap().unwrap();
+        assert_eq!(value, Value::Number(1.5));
+        assert_eq!(value.to_display_string(), "1.5");
+        let value = eval_program_str("(- 2.5E2 0.5e+1)").unwrap().unwrap();
+        assert_eq!(value.to_display_string(), "245");
+        let value = eval_program_str("(+ 0.1 0.2)").unwrap().unwrap();
+        assert_eq!(value.to_display_string(), "0.3");
+    }
+
+    #[test]
+    fn eval_ratios() {
+        let value = eval_program_str("(+ 1/2 1/4)").unwrap().unwrap();
+        assert_eq!(value, Value::Ratio(3, 4));
+        assert_eq!(value.to_display_string(), "3/4");
+        assert_eq!(eval_program_str("2/4"), Ok(Some(Value::Ratio(1, 2))));
+        assert_eq!(
+            eval_program_str("(* 2/3 3/2)"),
+            Ok(Some(Value::Ratio(1, 1)))
+        );
+        assert_eq!(eval_program_str("(- 1/3)"), Ok(Some(Value::Ratio(-1, 3))));
+        assert_eq!(
+            eval_program_str("(+ -2/4 1/4)"),
+            Ok(Some(Value::Ratio(-1, 4)))
+        );
+        assert_eq!(
+            eval_program_str("(/ 1/3 2/3)"),
+            Ok(Some(Value::Ratio(1, 2)))
+        );
+        assert_eq!(eval_program_str("(+ 1/2 1)"), Ok(Some(Value::Number(1.5))));
+        assert_eq!(eval_program_str("(< 1/3 0.5)"), Ok(Some(Value::Bool(true))));
+        assert_eq!(
+            eval_program_str("(/ 1/2 0/3)").unwrap_err().to_string(),
+            "division by zero"
+        );
+        assert_eq!(
+            eval_program_str("1/0").unwrap_err().to_string(),
+            "invalid ratio '1/0': the denominator is zero or too large"
+        );
+        let big = "(* 9223372036854775807/2 9223372036854775807/3)";
+        assert!(matches!(eval_program_str(big), Ok(Some(Value::Number(_)))));
+    }
+
+    #[test]
+    fn eval_arity_mismatch() {
+        let source = "(defun f (x) x)\n(f 1 2)";
+        let err = eval_program_str(source).unwrap_err();
+        assert_eq!(err.to_string(), "'f' expects 1 arguments, got 2");
+        assert_eq!(*err.span(), 16..23);
+    }
+
+    #[test]
+    fn eval_runaway_programs_stop() {
+        let source = "(defun f (x) (+ 1 (f x)))\n(f 1)";
+        let err = eval_program_str(source).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation exceeded a depth of 500");
+
+        let ast = parse("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))\n(fact 50)").ast;
+        let err = eval_program(&ast, &mut Env::with_max_steps(100)).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation exceeded 100 steps");
+    }
+
+    #[test]
+    fn eval_tail_calls_in_constant_stack() {
+        let source = "(defun count (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1))))\n\
+                      (defun even (n) (if (= n 0) true (odd (- n 1))))\n\
+                      (defun odd (n) (if (= n 0) false (even (- n 1))))\n\
+                      (let ((total (count 50000 0))) (if (even 10001) 0 total))";
+        assert_eq!(eval_program_str(source), Ok(Some(Value::Number(50000.0))));
+
+        // Calls outside tail position still nest, and stop at the depth limit.
+        let source = "(defun sum (n) (if (= n 0) 0 (+ n (sum (- n 1)))))\n(sum 100000)";
+        let err = eval_program_str(source).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation exceeded a depth of 500");
+
+        // Runaway loops still hit the step limit.
+        let ast = parse("(defun spin (n) (spin n))\n(spin 1)").ast;
+        let err = eval_program(&ast, &mut Env::with_max_steps(1000)).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation exceeded 1000 steps");
+    }
+
+    #[test]
+    fn eval_with_globals() {
+        let globals = HashMap::from([("x".to_string(), Value::Number(10.0))]);
+        let mut env = Env::new().with_globals(globals);
+        let ast = parse("(+ x 5)\n(let ((x 1)) x)\n(defun f (x) x)\n(f 2)").ast;
+        assert_eq!(eval(&ast[0], &mut env), Ok(Value::Number(15.0)));
+        assert_eq!(eval(&ast[1], &mut env), Ok(Value::Number(1.0)));
+        assert_eq!(
+            eval_program(&ast[2..], &mut env),
+            Ok(Some(Value::Number(2.0)))
+        );
+        assert_eq!(eval(&ast[0], &mut env), Ok(Value::Number(15.0)));
+
+        assert_eq!(
+            eval_str("(* 2 pi)"),
+            Ok(Value::Number(2.0 * std::f64::consts::PI))
+        );
+        assert_eq!(eval_str("(let ((e 1)) e)"), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn eval_cancellation() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut env = Env::new().with_cancellation(cancelled.clone());
+        let ast = parse("(+ 1 2)").ast;
+        assert_eq!(eval(&ast[0], &mut env), Ok(Value::Number(3.0)));
+
+        cancelled.store(true, Ordering::Relaxed);
+        let err = eval(&ast[0], &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation was cancelled");
+    }
+
+    #[test]
+    fn eval_error_variants() {
+        let cases = [
+            ("(+ 1 x)", "unbound variable 'x'", 5..6),
+            ("(g 1)", "unknown function 'g'", 1..2),
+            ("(= 1)", "'=' expects 2 arguments, got 1", 0..5),
+            ("(+ 1 \"a\")", "'+' expects numbers", 5..8),
+            ("(/ 1 0)", "division by zero", 0..7),
+            ("()", "cannot evaluate an empty list", 0..2),
+            ("(error \"no\")", "no", 0..12),
+        ];
+        for (source, message, span) in cases {
+            let err = eval_str(source).unwrap_err();
+            assert_eq!(
+                (err.to_string(), err.span().clone()),
+                (message.into(), span)
+            );
+        }
+        assert!(matches!(
+            eval_str("(f)"),
+            Err(EvalError::UnknownFunction { name, .. }) if name == "f"
+        ));
+        assert!(matches!(
+            eval_str("(+ 1 true)"),
+            Err(EvalError::TypeError { .. })
+        ));
+
+        let ast = parse("(+ 1 2)").ast;
+        let err = eval(&ast[0], &mut Env::with_max_steps(1)).unwrap_err();
+        assert_eq!(
+            err,
+            EvalError::StepLimitExceeded {
+                limit: 1,
+                span: 3..4
+            }
+        );
+        assert_eq!(err.to_string(), "evaluation exceeded 1 steps");
+    }
+
+    #[test]
+    fn eval_errors() {
+        let err = eval_str("(+ 1 x)").unwrap_err();
+        assert_eq!(*err.span(), 5..6);
+        assert_eq!(err.to_string(), "unbound variable 'x'");
+
+        let err = eval_str("(+ 1 true)").unwrap_err();
+        assert_eq!(*err.span(), 5..9);
+
+        let err = eval_str("(if true 1)").unwrap_err();
+        assert_eq!(*err.span(), 0..11);
+
+        let err = eval_str("(/ 1 0)").unwrap_err();
+        assert_eq!(err.to_string(), "division by zero");
+
+        let err = eval_program_str("(print 1)\n(error \"stop here\")\n(print 2)").unwrap_err();
+        assert_eq!(err.to_string(), "stop here");
+        assert_eq!(*err.span(), 10..29);
+    }
+}
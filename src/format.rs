@@ -0,0 +1,443 @@
+use crate::cst::{to_cst, tokenize, CstKind, CstNode, CstToken};
+use crate::parse_error_message;
+use crate::parser::{parse, Span, BOM};
+use ropey::Rope;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatOptions {
+    pub indent_width: usize,
+    /// Lines longer than this are wrapped before the element that crosses
+    /// it, where a line break is allowed.
+    pub max_line_width: usize,
+    /// End non-empty output with a line break.
+    pub trailing_newline: bool,
+    /// Lay the whole source out again. Otherwise only trailing whitespace is
+    /// trimmed and the end of the file normalized, for minimal diffs.
+    pub reflow: bool,
+    /// Line up the comments ending consecutive lines of code in one column.
+    pub align_trailing_comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent_width: 2,
+            max_line_width: 80,
+            trailing_newline: true,
+            reflow: true,
+            align_trailing_comments: false,
+        }
+    }
+}
+
+/// The source doesn't parse, so it is left as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+fn flatten<'a>(nodes: &'a [CstNode], tokens: &mut Vec<&'a CstToken>) {
+    for node in nodes {
+        match node {
+            CstNode::Token(token) => tokens.push(token),
+            CstNode::List { children, .. } => flatten(children, tokens),
+        }
+    }
+}
+
+/// Width of the element starting `tokens` when laid out on one line, up to
+/// the first line break inside it.
+fn element_width(tokens: &[&CstToken]) -> usize {
+    let mut width = 0;
+    let mut depth: usize = 0;
+    let mut previous = None;
+    for token in tokens {
+        match token.kind {
+            CstKind::Whitespace if token.text.contains('\n') => break,
+            CstKind::Whitespace => continue,
+            CstKind::RParen => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if previous.is_some_and(|kind| !matches!(kind, CstKind::LParen | CstKind::Quote))
+            && token.kind != CstKind::RParen
+        {
+            width += 1;
+        }
+        width += token.text.chars().count();
+        if token.kind == CstKind::LParen {
+            depth += 1;
+        }
+        if (depth == 0 && token.kind != CstKind::Quote) || token.kind == CstKind::Comment {
+            break;
+        }
+        previous = Some(token.kind);
+    }
+    width
+}
+
+/// Formats `source` into the canonical layout. Line breaks are kept where
+/// they are, except that runs of blank lines collapse into one and blank
+/// lines at either end are dropped. Every line is indented by its nesting
+/// depth, and tokens on a line are separated by a single space, with none
+/// just inside parens. Comments stay where they are and no line keeps
+/// trailing whitespace. With `trailing_newline`, the output ends with
+/// exactly one line break.
+///
+/// A line that would grow past `max_line_width` breaks before the next
+/// element of a list. Breaks are only ever added, so formatting formatted
+/// output changes nothing.
+///
+/// With `align_trailing_comments`, comments that follow code on consecutive
+/// lines start in the same column, one space past the longest of those lines.
+/// Comments on a line of their own are left where they are.
+///
+/// Without `reflow`, only `trim_edits` are applied and the source doesn't
+/// need to parse.
+pub fn format_source(source: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    if !options.reflow {
+        let mut rope = Rope::from_str(source);
+        for (span, text) in trim_edits(&rope, options).into_iter().rev() {
+            rope.remove(span.clone());
+            rope.insert(span.start, &text);
+        }
+        return Ok(rope.to_string());
+    }
+    // A byte order mark stays in front, out of the layout.
+    if let Some(rest) = source.strip_prefix(BOM) {
+        return match format_source(rest, options) {
+            Ok(formatted) => Ok(format!("{}{}", BOM, formatted)),
+            Err(err) => Err(FormatError {
+                span: err.span.start + 1..err.span.end + 1,
+                ..err
+            }),
+        };
+    }
+    if let Some(err) = parse(source).parse_errors.first() {
+        return Err(FormatError {
+            message: parse_error_message(err),
+            span: err.span(),
+        });
+    }
+
+    let tree = to_cst(source);
+    let mut tokens = vec![];
+    flatten(&tree.children, &mut tokens);
+
+    let mut output = String::new();
+    let mut line_start = 0;
+    let mut depth: usize = 0;
+    let mut line_breaks = 0;
+    let mut previous = None;
+    let mut trailing_comments = vec![];
+    for (index, token) in tokens.iter().enumerate() {
+        if token.kind == CstKind::Whitespace {
+            line_breaks += token.text.matches('\n').count();
+            continue;
+        }
+        if token.kind == CstKind::RParen {
+            depth = depth.saturating_sub(1);
+        }
+
+        // A comment ends its line, even when only a lone `\r` followed it.
+        if previous == Some(CstKind::Comment) {
+            line_breaks = line_breaks.max(1);
+        }
+        let element = element_width(&tokens[index..]);
+        let wraps = depth > 0
+            && previous != Some(CstKind::Quote)
+            && !matches!(token.kind, CstKind::RParen | CstKind::Comment)
+            && output[line_start..].chars().count() + 1 + element > options.max_line_width;
+        match previous {
+            None => {}
+            // A quote sticks to what it quotes.
+            Some(CstKind::Quote) => {}
+            Some(CstKind::LParen) if line_breaks == 0 => {}
+            Some(_) if line_breaks == 0 && token.kind == CstKind::RParen => {}
+            Some(_) if line_breaks > 0 || wraps => {
+                output.push_str(&"\n".repeat(line_breaks.clamp(1, 2)));
+                line_start = output.len();
+                output.push_str(&" ".repeat(depth * options.indent_width));
+            }
+            Some(_) => output.push(' '),
+        }
+        if token.kind == CstKind::Comment && !output[line_start..].trim_start().is_empty() {
+            trailing_comments.push(output.len());
+        }
+        match token.kind {
+            CstKind::Comment => output.push_str(token.text.trim_end()),
+            _ => output.push_str(&token.text),
+        }
+
+        if token.kind == CstKind::LParen {
+            depth += 1;
+        }
+        line_breaks = 0;
+        previous = Some(token.kind);
+    }
+
+    if options.align_trailing_comments {
+        align_comments(&mut output, &trailing_comments);
+    }
+    if options.trailing_newline && !output.is_empty() {
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Pads the trailing comments starting at the byte offsets `comments` of
+/// `output` so that those on consecutive lines share a column.
+fn align_comments(output: &mut String, comments: &[usize]) {
+    // (line, width of the code before the comment, comment offset)
+    let lines: Vec<(usize, usize, usize)> = comments
+        .iter()
+        .map(|&offset| {
+            let before = &output[..offset];
+            let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+            let line = before.matches('\n').count();
+            (
+                line,
+                before[line_start..].trim_end().chars().count(),
+                offset,
+            )
+        })
+        .collect();
+
+    let mut groups: Vec<&[(usize, usize, usize)]> = vec![];
+    let mut start = 0;
+    for end in 1..=lines.len() {
+        if end == lines.len() || lines[end].0 != lines[end - 1].0 + 1 {
+            groups.push(&lines[start..end]);
+            start = end;
+        }
+    }
+
+    let mut padding = vec![];
+    for group in groups.into_iter().filter(|group| group.len() > 1) {
+        let column = group
+            .iter()
+            .map(|(_, width, _)| width + 1)
+            .max()
+            .unwrap_or(0);
+        for (_, width, offset) in group {
+            padding.push((*offset, column - width - 1));
+        }
+    }
+    for (offset, spaces) in padding.into_iter().rev() {
+        output.insert_str(offset, &" ".repeat(spaces));
+    }
+}
+
+/// Edits, in source order, that trim trailing whitespace from every line of
+/// `rope` and drop blank lines at its end, leaving one line break there with
+/// `trailing_newline`. Whitespace inside string literals is kept.
+pub fn trim_edits(rope: &Rope, options: &FormatOptions) -> Vec<(Span, String)> {
+    let source = rope.to_string();
+    // The flat tokens rather than the tree, which could nest too deeply to
+    // walk in source that doesn't parse.
+    let strings: Vec<Span> = tokenize(&source)
+        .into_iter()
+        .filter(|token| token.kind == CstKind::Str)
+        .map(|token| token.span)
+        .collect();
+    let in_string = |offset: usize| {
+        let index = strings.partition_point(|span| span.end <= offset);
+        strings
+            .get(index)
+            .is_some_and(|span| span.start < offset && offset < span.end)
+    };
+
+    let content_end = source.trim_end().chars().count();
+    let mut edits = vec![];
+    for (index, line) in rope.lines().enumerate() {
+        let line_start = rope.line_to_char(index);
+        let body: String = line.chars().filter(|c| *c != '\n' && *c != '\r').collect();
+        let body_end = line_start + body.chars().count();
+        let trimmed_end = line_start + body.trim_end().chars().count();
+        if body_end > content_end {
+            break;
+        }
+        if trimmed_end < body_end && !in_string(trimmed_end) {
+            edits.push((trimmed_end..body_end, String::new()));
+        }
+    }
+
+    let ending = if options.trailing_newline && content_end > 0 {
+        "\n"
+    } else {
+        ""
+    };
+    let tail = content_end..rope.len_chars();
+    if rope.slice(tail.clone()) != ending {
+        edits.push((tail, ending.to_string()));
+    }
+    edits
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format(source: &str) -> String {
+        format_source(source, &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn format_messy_source() {
+        let source = "\n\n(defun   fact (n) ; factorial\n(if (= n 0)\n1\n(* n (fact (- n 1)))))\n\n\n\n( print(fact 5) )   ";
+        assert_eq!(
+            format(source),
+            "(defun fact (n) ; factorial\n  (if (= n 0)\n    1\n    (* n (fact (- n 1)))))\n\n(print (fact 5))\n"
+        );
+        assert_eq!(format(""), "");
+        assert_eq!(format(" ; only\r\n"), "; only\n");
+    }
+
+    #[test]
+    fn format_keeps_byte_order_mark() {
+        assert_eq!(format("\u{feff}( print 1 )"), "\u{feff}(print 1)\n");
+        let err = format_source("\u{feff}(f", &FormatOptions::default()).unwrap_err();
+        assert_eq!(err.span, 1..2);
+    }
+
+    #[test]
+    fn format_keeps_quotes_next_to_what_they_quote() {
+        assert_eq!(format("(f ' ( 1 2 )\n'\nx)"), "(f '(1 2)\n  'x)\n");
+        assert_eq!(format("''a"), "''a\n");
+    }
+
+    #[test]
+    fn trim_pathological_sources() {
+        // Too deep to parse, and to walk as a tree.
+        let deep = format!("{}{}", "(".repeat(50_000), ")".repeat(50_000));
+        let edits = trim_edits(&Rope::from_str(&deep), &FormatOptions::default());
+        assert_eq!(edits, vec![(100_000..100_000, "\n".to_string())]);
+
+        let lines = "(f \"x \ny\") \n".repeat(20_000);
+        let edits = trim_edits(&Rope::from_str(&lines), &FormatOptions::default());
+        assert_eq!(edits.len(), 20_000);
+    }
+
+    #[test]
+    fn format_with_indent_width() {
+        let options = FormatOptions {
+            indent_width: 4,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source("(let ((x 1))\n(print x))", &options).unwrap(),
+            "(let ((x 1))\n    (print x))\n"
+        );
+    }
+
+    #[test]
+    fn format_wraps_long_lines() {
+        let options = FormatOptions {
+            max_line_width: 20,
+            trailing_newline: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source("(print (+ 100 200 300) (* 400 500 600))", &options).unwrap(),
+            "(print\n  (+ 100 200 300)\n  (* 400 500 600))"
+        );
+        assert_eq!(
+            format_source("(f aaaaaaaaaaaa bbbbbbbbbbbb) ; a long comment", &options).unwrap(),
+            "(f aaaaaaaaaaaa\n  bbbbbbbbbbbb) ; a long comment"
+        );
+    }
+
+    #[test]
+    fn format_normalizes_file_endings() {
+        assert_eq!(format("(print 1)"), "(print 1)\n");
+        assert_eq!(format("(print 1)\n\n\n\n"), "(print 1)\n");
+        assert_eq!(format("(print 1) ; one   \n  \n\t\n"), "(print 1) ; one\n");
+        let options = FormatOptions {
+            trailing_newline: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source("(print 1)\n\n\n", &options).unwrap(),
+            "(print 1)"
+        );
+    }
+
+    #[test]
+    fn trim_without_reflow() {
+        let options = FormatOptions {
+            reflow: false,
+            ..Default::default()
+        };
+        let source = "(defun f (x)   \n      (print \"a  \n b\")) ; f \t\n\n\n(f 1";
+        assert_eq!(
+            format_source(source, &options).unwrap(),
+            "(defun f (x)\n      (print \"a  \n b\")) ; f\n\n\n(f 1\n"
+        );
+        let rope = Rope::from_str("(f)  \r\n(g)\r\n\r\n");
+        assert_eq!(
+            trim_edits(&rope, &options),
+            vec![(3..5, String::new()), (10..14, "\n".into())]
+        );
+    }
+
+    #[test]
+    fn format_keeps_comments_before_lone_carriage_returns() {
+        assert_eq!(format("(f ; c\r)"), "(f ; c\n)\n");
+    }
+
+    #[test]
+    fn format_aligns_trailing_comments() {
+        let options = FormatOptions {
+            align_trailing_comments: true,
+            ..Default::default()
+        };
+        let source = "(let ((x 1) ; one\n      (yy 22) ; two\n      (zzz 333)) ; three\n  ; on its own\n  (print x)) ; four";
+        assert_eq!(
+            format_source(source, &options).unwrap(),
+            "(let ((x 1)    ; one\n    (yy 22)    ; two\n    (zzz 333)) ; three\n  ; on its own\n  (print x)) ; four\n"
+        );
+        assert_eq!(
+            format(source),
+            "(let ((x 1) ; one\n    (yy 22) ; two\n    (zzz 333)) ; three\n  ; on its own\n  (print x)) ; four\n"
+        );
+    }
+
+    #[test]
+    fn format_is_idempotent() {
+        let sources = [
+            "(defun fact (n) ; factorial\n(if (= n 0) 1 (* n (fact (- n 1)))))\n(print (fact 5))",
+            "\n\n; header\n\n\n(let ((x 1) (y 2))\n   (print (+ x y))) ; sum\n",
+            "(f \"a  string\" 0xff 1.5e-3\n\n\n(g))",
+            "(define-very-long-function-name (with many parameters here) (and a body that goes past the limit of the line))",
+        ];
+        for options in [
+            FormatOptions::default(),
+            FormatOptions {
+                indent_width: 4,
+                max_line_width: 30,
+                trailing_newline: false,
+                reflow: true,
+                align_trailing_comments: true,
+            },
+        ] {
+            for source in sources {
+                let formatted = format_source(source, &options).unwrap();
+                assert_eq!(format_source(&formatted, &options).unwrap(), formatted);
+            }
+        }
+    }
+
+    #[test]
+    fn format_rejects_unbalanced_source() {
+        let err = format_source("(print 1", &FormatOptions::default()).unwrap_err();
+        assert_eq!(err.message, "unclosed '('");
+        assert_eq!(err.span, 0..1);
+    }
+}
@@ -0,0 +1,4 @@
+//! The orelang lexer, parser and highlighter, for tools outside the
+//! language server.
+
+pub mod parser;
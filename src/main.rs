@@ -1,82 +1,1251 @@
-mod parser;
-use parser::{parse, ImCompleteSemanticToken};
+mod analysis;
+mod builtins;
+mod cli;
+mod config;
+mod cst;
+mod eval;
+mod format;
+use analysis::{
+    check_arity, check_duplicate_defuns, check_types, check_unbound, check_unreachable,
+    check_unused, collect_calls, collect_colors, collect_symbols, constant_list_at,
+    enclosing_spans, find_definition, find_references, names_in_scope, NameKind, Symbol,
+};
+use builtins::{builtin, builtins, Builtin, BuiltinKind};
+use chumsky::error::{Simple, SimpleReason};
+use config::{Config, Level};
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use eval::{eval, eval_program, Env, Value};
+use format::{format_source, trim_edits, FormatOptions};
+use orelang_but_rust::parser;
+use parser::{
+    call_context, collect_defun_params, collect_defuns, collect_doc_comments,
+    collect_parameter_hints, collect_requires, find_extractable, find_identifier_occurrences,
+    highlights, match_paren, nesting_depth, paren_fixes, parse_with_max_depth, reparse,
+    token_at_offset, tokenize, Edit, Expr, ImCompleteSemanticToken, ParenFix, ParseResult, Span,
+    Spanned, Token, PAREN_DEPTH_MODIFIERS,
+};
 use ropey::Rope;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
 
-use tower_lsp::jsonrpc::Result;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
+const EVAL_COMMAND: &str = "orelang/eval";
+
+/// The language id clients send for orelang documents.
+const LANGUAGE_ID: &str = "orelang";
+
+/// Marks builtin completion items, whose docs are filled in on resolve.
+const BUILTIN_DATA: &str = "builtin";
+
+/// Templates for common forms: the label, the snippet with tab stops and the
+/// plain text inserted for clients without snippet support.
+const SNIPPETS: &[(&str, &str, &str)] = &[
+    (
+        "defun",
+        "(defun ${1:name} (${2:params})\n  $0)",
+        "(defun name (params)\n  )",
+    ),
+    (
+        "if",
+        "(if ${1:condition}\n    ${2:then}\n    ${3:else})",
+        "(if condition\n    then\n    else)",
+    ),
+    (
+        "let",
+        "(let ((${1:name} ${2:value}))\n  $0)",
+        "(let ((name value))\n  )",
+    ),
+    (
+        "let*",
+        "(let* ((${1:name} ${2:value}))\n  $0)",
+        "(let* ((name value))\n  )",
+    ),
+];
+
 #[derive(Debug)]
 struct Backend {
     client: Client,
 
-    publish_diagnostics_capable: Mutex<bool>,
+    publish_diagnostics_capable: RwLock<bool>,
+
+    snippet_capable: RwLock<bool>,
+
+    definition_link_capable: RwLock<bool>,
+
+    semantic_tokens_refresh_capable: RwLock<bool>,
+
+    multiline_token_capable: RwLock<bool>,
+
+    work_done_progress_capable: RwLock<bool>,
+
+    position_encoding: RwLock<PositionEncoding>,
+
+    workspace_folders: RwLock<Vec<WorkspaceFolder>>,
+
+    rope_map: DashMap<String, Rope>,
+
+    token_types_map: DashMap<SemanticTokenType, usize>,
+
+    semantic_token_map: DashMap<String, Vec<ImCompleteSemanticToken>>,
+
+    ast_map: DashMap<String, Vec<Spanned<Expr>>>,
+
+    /// The tokens of the last compile of each document.
+    token_map: DashMap<String, CompiledTokens>,
 
-    rope_map: Mutex<HashMap<String, Rope>>,
+    /// The diagnostics the last compile of each document published.
+    diagnostic_map: DashMap<String, Vec<Diagnostic>>,
 
-    token_types_map: Mutex<HashMap<SemanticTokenType, usize>>,
+    config: RwLock<Config>,
 
-    semantic_token_map: Mutex<HashMap<String, Vec<ImCompleteSemanticToken>>>,
+    source_hash_map: DashMap<String, u64>,
+
+    /// The language id each open document was opened with.
+    language_id_map: DashMap<String, String>,
+
+    /// The latest version of each document, sent along with its diagnostics
+    /// so the client can drop stale ones.
+    version_map: DashMap<String, i32>,
+
+    /// The edits made since the last error-free compile of each document,
+    /// with the hash of the text they lead to. `None` until the first edit.
+    edit_map: DashMap<String, (Option<Edit>, u64)>,
+
+    pending_compile_map: DashMap<String, (u64, AbortHandle)>,
+
+    compile_generation: AtomicU64,
 }
 
-fn create_simple_diagnostics(
-    message: String,
-    start_line: u32,
-    start_column: u32,
-    end_line: u32,
-    end_column: u32,
-) -> Diagnostic {
-    Diagnostic::new_simple(
-        Range {
-            start: Position {
-                line: start_line,
-                character: start_column,
-            },
-            end: Position {
-                line: end_line,
-                character: end_column,
-            },
+/// The tokens a compile of a document lexed.
+#[derive(Debug)]
+struct CompiledTokens {
+    /// The version of the document compiled, `None` when it isn't open.
+    version: Option<i32>,
+    /// The hash of the compiled text.
+    hash: u64,
+    /// How many chars the compiled text has.
+    len: usize,
+    tokens: Vec<(Token, Span)>,
+}
+
+/// The tokens and AST of the current text of a document.
+enum Parsed<'a> {
+    /// Those of the last compile, which compiled the current text.
+    Compiled(
+        Ref<'a, String, CompiledTokens>,
+        Ref<'a, String, Vec<Spanned<Expr>>>,
+    ),
+    /// Parsed on demand, since the last compile is of another text.
+    Fresh(ParseResult),
+}
+
+impl Parsed<'_> {
+    fn tokens(&self) -> &[(Token, Span)] {
+        match self {
+            Parsed::Compiled(compiled, _) => &compiled.tokens,
+            Parsed::Fresh(parse_result) => &parse_result.tokens,
+        }
+    }
+
+    fn ast(&self) -> &[Spanned<Expr>] {
+        match self {
+            Parsed::Compiled(_, ast) => ast,
+            Parsed::Fresh(parse_result) => &parse_result.ast,
+        }
+    }
+}
+
+fn parse_error_message(err: &Simple<String>) -> String {
+    match err.reason() {
+        SimpleReason::Custom(message) => message.clone(),
+        SimpleReason::Unexpected => {
+            let found = match err.found() {
+                Some(found) => format!("unexpected character '{}'", found),
+                None => "unexpected end of input".to_string(),
+            };
+            match describe_expected(err) {
+                Some(expected) => format!("{}, expected {}", found, expected),
+                None => found,
+            }
+        }
+        _ => err.to_string(),
+    }
+}
+
+/// Describes the tokens that could have started where a lexer error is, in
+/// words rather than as the single chars chumsky tracks. Chars that could
+/// only have continued the previous token are left out.
+fn describe_expected(err: &Simple<String>) -> Option<String> {
+    let expected: HashSet<Option<&str>> = err.expected().map(Option::as_deref).collect();
+    let starts = |chars: &str| {
+        expected
+            .iter()
+            .flatten()
+            .any(|found| found.chars().all(|c| chars.contains(c)))
+    };
+    let described: Vec<&str> = [
+        (starts("("), "'('"),
+        (starts(")"), "')'"),
+        (
+            starts("+-*/=<>_")
+                || expected
+                    .iter()
+                    .flatten()
+                    .any(|found| found.chars().all(char::is_alphabetic)),
+            "an identifier",
+        ),
+        (starts("0123456789"), "a number"),
+        (starts("\""), "a string"),
+        (starts(";"), "a comment"),
+        (expected.contains(&None), "the end of input"),
+    ]
+    .into_iter()
+    .filter_map(|(expected, description)| expected.then_some(description))
+    .collect();
+
+    match described.as_slice() {
+        [] => None,
+        [description] => Some(description.to_string()),
+        [descriptions @ .., last] => Some(format!("{} or {}", descriptions.join(", "), last)),
+    }
+}
+
+/// What the `character` of a `Position` counts, negotiated with the client
+/// at initialization. Offsets are always char indices, matching the spans
+/// produced by the lexer, and are converted at the protocol boundary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum PositionEncoding {
+    Utf8,
+    /// The protocol's default, which every client understands.
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// The first of the client's encodings, in its order of preference,
+    /// that the server knows.
+    fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        offered
+            .into_iter()
+            .flatten()
+            .find_map(|kind| match kind.as_str() {
+                "utf-8" => Some(PositionEncoding::Utf8),
+                "utf-16" => Some(PositionEncoding::Utf16),
+                "utf-32" => Some(PositionEncoding::Utf32),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    fn kind(self) -> PositionEncodingKind {
+        match self {
+            PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+            PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+            PositionEncoding::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// How many code units come before char `offset`.
+    fn units(self, rope: &Rope, offset: usize) -> Option<usize> {
+        match self {
+            PositionEncoding::Utf8 => rope.try_char_to_byte(offset).ok(),
+            PositionEncoding::Utf16 => rope.try_char_to_utf16_cu(offset).ok(),
+            PositionEncoding::Utf32 => (offset <= rope.len_chars()).then_some(offset),
+        }
+    }
+
+    /// The char containing code unit `units`.
+    fn offset(self, rope: &Rope, units: usize) -> Option<usize> {
+        match self {
+            PositionEncoding::Utf8 => rope.try_byte_to_char(units).ok(),
+            PositionEncoding::Utf16 => rope.try_utf16_cu_to_char(units).ok(),
+            PositionEncoding::Utf32 => (units <= rope.len_chars()).then_some(units),
+        }
+    }
+
+    /// How many code units `span` takes up.
+    fn len(self, rope: &Rope, span: &Span) -> Option<usize> {
+        match self {
+            // Keeps spans past the end measurable, like before there was a
+            // choice of encoding.
+            PositionEncoding::Utf32 => Some(span.len()),
+            _ => Some(self.units(rope, span.end)? - self.units(rope, span.start)?),
+        }
+    }
+}
+
+fn offset_to_position(rope: &Rope, offset: usize, encoding: PositionEncoding) -> Option<Position> {
+    let line = rope.try_char_to_line(offset).ok()?;
+    let line_first = rope.try_line_to_char(line).ok()?;
+    let character = encoding.units(rope, offset)? - encoding.units(rope, line_first)?;
+    Some(Position::new(line as u32, character as u32))
+}
+
+fn position_to_offset(
+    rope: &Rope,
+    position: Position,
+    encoding: PositionEncoding,
+) -> Option<usize> {
+    let line_first = rope.try_line_to_char(position.line as usize).ok()?;
+    let units = encoding.units(rope, line_first)? + position.character as usize;
+    encoding.offset(rope, units)
+}
+
+/// Splits `span` at its line breaks into one span per line, for clients
+/// that can't take a token over several lines. The line breaks themselves
+/// and empty lines are left out.
+fn split_lines(rope: &Rope, span: Span) -> Vec<Span> {
+    if span.end > rope.len_chars() {
+        return vec![span];
+    }
+    let first = rope.char_to_line(span.start);
+    let last = rope.char_to_line(span.end);
+    if first == last {
+        return vec![span];
+    }
+    (first..=last)
+        .filter_map(|index| {
+            let line = rope.line(index);
+            let mut len = line.len_chars();
+            while len > 0 && matches!(line.char(len - 1), '\n' | '\r') {
+                len -= 1;
+            }
+            let line_start = rope.line_to_char(index);
+            let start = span.start.max(line_start);
+            let end = span.end.min(line_start + len);
+            (start < end).then_some(start..end)
+        })
+        .collect()
+}
+
+/// Converts a span of chars into a range in the negotiated encoding. Every
+/// range sent to the client goes through here.
+fn span_to_range(rope: &Rope, span: &Span, encoding: PositionEncoding) -> Option<Range> {
+    Some(Range {
+        start: offset_to_position(rope, span.start, encoding)?,
+        end: offset_to_position(rope, span.end, encoding)?,
+    })
+}
+
+/// Applies one change to `rope`: a whole-document replacement or, with a
+/// range, an incremental edit. Changes with an out-of-bounds range are
+/// dropped. Returns the edit, or `None` unless it was incremental.
+fn apply_content_change(
+    rope: &mut Rope,
+    content_change: &TextDocumentContentChangeEvent,
+    encoding: PositionEncoding,
+) -> Option<Edit> {
+    let Some(range) = content_change.range else {
+        *rope = Rope::from_str(&content_change.text);
+        return None;
+    };
+    let start = position_to_offset(rope, range.start, encoding)?;
+    let end = position_to_offset(rope, range.end, encoding)?;
+    rope.remove(start..end);
+    rope.insert(start, &content_change.text);
+    Some(Edit {
+        start,
+        old_end: end,
+        new_end: start + content_change.text.chars().count(),
+    })
+}
+
+/// The part of an identifier or operator typed right before `offset`.
+/// Empty after `(` or whitespace.
+fn completion_prefix(rope: &Rope, offset: usize) -> String {
+    let before = rope.slice(..offset);
+    let mut prefix: Vec<char> = before
+        .chars_at(offset)
+        .reversed()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    if prefix.is_empty() {
+        prefix.extend(
+            before
+                .chars_at(offset)
+                .reversed()
+                .take_while(|c| "+-*/=<>!".contains(*c)),
+        );
+    }
+    prefix.into_iter().rev().collect()
+}
+
+/// Sets its flag when dropped, so work on another thread notices that the
+/// request it belongs to was cancelled.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether a document opened as `language_id` is orelang. Clients that don't
+/// know the language may send another id, so the `.ore` extension counts too.
+fn is_orelang(language_id: &str, uri: &Url) -> bool {
+    language_id == LANGUAGE_ID || uri.path().ends_with(".ore")
+}
+
+fn source_hash(src: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    src.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Resolves a required path against the directory of `uri`. When no file is
+/// there, the first workspace folder that has one wins.
+fn resolve_require(uri: &Url, path: &str, folders: &[WorkspaceFolder]) -> Option<Url> {
+    let relative = uri.join(path).ok()?;
+    let exists = |url: &Url| url.to_file_path().is_ok_and(|path| path.exists());
+    if exists(&relative) {
+        return Some(relative);
+    }
+    let in_folders = folders.iter().find_map(|folder| {
+        let mut base = folder.uri.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        base.join(path).ok().filter(exists)
+    });
+    Some(in_folders.unwrap_or(relative))
+}
+
+/// Collects the `.ore` files under `dir`, without following symlinks.
+fn ore_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            ore_files(&path, files);
+        } else if file_type.is_file() && path.extension().is_some_and(|ext| ext == "ore") {
+            files.push(path);
+        }
+    }
+}
+
+/// Merges the diagnostics that several passes report for the same range
+/// into one, in the order they first appear. It has the highest severity
+/// among them and each distinct message on its own line, so a problem two
+/// passes find shows up once. Overlapping but different ranges are kept
+/// apart, since they point at different code.
+fn merge_diagnostics(diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    // `ERROR` is the lowest and a missing severity counts as a hint.
+    let rank = |diagnostic: &Diagnostic| diagnostic.severity.unwrap_or(DiagnosticSeverity::HINT);
+    let mut merged: Vec<Diagnostic> = vec![];
+    for diagnostic in diagnostics {
+        let Some(existing) = merged
+            .iter_mut()
+            .find(|existing| existing.range == diagnostic.range)
+        else {
+            merged.push(diagnostic);
+            continue;
+        };
+        let mut messages: Vec<String> = existing.message.lines().map(String::from).collect();
+        if !messages.contains(&diagnostic.message) {
+            messages.push(diagnostic.message.clone());
+        }
+        let mut related_information = existing.related_information.take().unwrap_or_default();
+        related_information.extend(diagnostic.related_information.clone().unwrap_or_default());
+        if rank(&diagnostic) < rank(existing) {
+            *existing = diagnostic;
+        }
+        existing.message = messages.join("\n");
+        existing.related_information =
+            (!related_information.is_empty()).then_some(related_information);
+    }
+    merged
+}
+
+fn overlaps(a: &Span, b: &Span) -> bool {
+    a.start <= b.end && b.start <= a.end
+}
+
+fn code_action(
+    title: String,
+    kind: CodeActionKind,
+    uri: &Url,
+    edit: TextEdit,
+    diagnostics: Option<Vec<Diagnostic>>,
+) -> CodeActionOrCommand {
+    let is_preferred = diagnostics.as_ref().map(|_| true);
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title,
+        kind: Some(kind),
+        diagnostics,
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+            ..Default::default()
+        }),
+        is_preferred,
+        ..Default::default()
+    })
+}
+
+/// Quick fixes inserting or removing a paren for each unbalanced one in
+/// `selection`, linked to the client's diagnostics at the same range.
+fn paren_fix_actions(
+    uri: &Url,
+    rope: &Rope,
+    tokens: &[(Token, Span)],
+    selection: &Span,
+    diagnostics: &[Diagnostic],
+    encoding: PositionEncoding,
+) -> Vec<CodeActionOrCommand> {
+    paren_fixes(tokens)
+        .into_iter()
+        .filter(|(span, _)| overlaps(span, selection))
+        .filter_map(|(span, fix)| {
+            let diagnostic_range = span_to_range(rope, &span, encoding)?;
+            let (title, edit) = match fix {
+                ParenFix::Insert(offset) => (
+                    "Add missing ')'",
+                    TextEdit {
+                        range: span_to_range(rope, &(offset..offset), encoding)?,
+                        new_text: ")".into(),
+                    },
+                ),
+                ParenFix::Remove => (
+                    "Remove extra ')'",
+                    TextEdit {
+                        range: diagnostic_range,
+                        new_text: String::new(),
+                    },
+                ),
+            };
+            let diagnostics = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.range == diagnostic_range)
+                .cloned()
+                .collect();
+            Some(code_action(
+                title.into(),
+                CodeActionKind::QUICKFIX,
+                uri,
+                edit,
+                Some(diagnostics),
+            ))
+        })
+        .collect()
+}
+
+/// Rewrites integer literals in `selection` between decimal and hex.
+fn radix_actions(
+    uri: &Url,
+    rope: &Rope,
+    tokens: &[(Token, Span)],
+    selection: &Span,
+    encoding: PositionEncoding,
+) -> Vec<CodeActionOrCommand> {
+    tokens
+        .iter()
+        .filter(|(_, span)| overlaps(span, selection))
+        .filter_map(|(token, span)| {
+            let Token::Number(literal) = token else {
+                return None;
+            };
+            let converted = match literal.strip_prefix("0x") {
+                Some(digits) => u64::from_str_radix(digits, 16).ok()?.to_string(),
+                None => format!("0x{:x}", literal.parse::<u64>().ok()?),
+            };
+            let edit = TextEdit {
+                range: span_to_range(rope, span, encoding)?,
+                new_text: converted.clone(),
+            };
+            Some(code_action(
+                format!("Convert {} to {}", literal, converted),
+                CodeActionKind::REFACTOR_REWRITE,
+                uri,
+                edit,
+                None,
+            ))
+        })
+        .collect()
+}
+
+/// Moves the call argument in `selection` into a `let` binding wrapped
+/// around the call, named `tmp` or the first free `tmpN`.
+fn extract_let_action(
+    uri: &Url,
+    rope: &Rope,
+    tokens: &[(Token, Span)],
+    ast: &[Spanned<Expr>],
+    selection: &Span,
+    encoding: PositionEncoding,
+) -> Option<CodeActionOrCommand> {
+    let ((_, expr), (_, form)) = find_extractable(ast, selection)?;
+    let names: HashSet<&str> = tokens
+        .iter()
+        .filter_map(|(token, _)| match token {
+            Token::Ident(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let name = std::iter::once("tmp".to_string())
+        .chain((1..).map(|n| format!("tmp{}", n)))
+        .find(|name| !names.contains(name.as_str()))?;
+
+    let new_text = format!(
+        "(let (({} {})) {}{}{})",
+        name,
+        rope.get_slice(expr.clone())?,
+        rope.get_slice(form.start..expr.start)?,
+        name,
+        rope.get_slice(expr.end..form.end)?,
+    );
+    let edit = TextEdit {
+        range: span_to_range(rope, form, encoding)?,
+        new_text,
+    };
+    Some(code_action(
+        format!("Extract into let binding '{}'", name),
+        CodeActionKind::REFACTOR_EXTRACT,
+        uri,
+        edit,
+        None,
+    ))
+}
+
+/// What the starter template action fills an empty document with.
+const STARTER_TEMPLATE: &str =
+    "(defun fact (n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))\n\n(print (fact 5))\n";
+
+/// Offers to fill a document that is empty, or only whitespace, with a
+/// small program to start from.
+fn starter_template_action(
+    uri: &Url,
+    rope: &Rope,
+    encoding: PositionEncoding,
+) -> Option<CodeActionOrCommand> {
+    if !rope.chars().all(char::is_whitespace) {
+        return None;
+    }
+    let edit = TextEdit {
+        range: span_to_range(rope, &(0..rope.len_chars()), encoding)?,
+        new_text: STARTER_TEMPLATE.into(),
+    };
+    Some(code_action(
+        "Insert starter template".into(),
+        CodeActionKind::EMPTY,
+        uri,
+        edit,
+        None,
+    ))
+}
+
+/// Markdown describing a builtin, with its arity when it has a fixed one.
+fn builtin_documentation(builtin: &Builtin) -> String {
+    let mut value = builtin.doc.to_string();
+    if let Some(arity) = builtin.arity {
+        value.push_str(&format!("\n\nTakes {} arguments.", arity));
+    }
+    value
+}
+
+fn document_symbol(
+    rope: &Rope,
+    symbol: Symbol,
+    encoding: PositionEncoding,
+) -> Option<DocumentSymbol> {
+    #[allow(deprecated)]
+    Some(DocumentSymbol {
+        name: symbol.name,
+        detail: None,
+        kind: match symbol.kind {
+            NameKind::Function => SymbolKind::FUNCTION,
+            NameKind::Variable => SymbolKind::VARIABLE,
         },
-        message,
-    )
+        tags: None,
+        deprecated: None,
+        range: span_to_range(rope, &symbol.span, encoding)?,
+        selection_range: span_to_range(rope, &symbol.selection_span, encoding)?,
+        children: Some(
+            symbol
+                .children
+                .into_iter()
+                .filter_map(|child| document_symbol(rope, child, encoding))
+                .collect(),
+        ),
+    })
+}
+
+/// The label `(name params...)` of a signature, and where each parameter
+/// sits in it. The offsets are in the negotiated encoding, like positions.
+fn signature_label(
+    name: &str,
+    params: &[String],
+    encoding: PositionEncoding,
+) -> Option<(String, Vec<ParameterInformation>)> {
+    let mut label = format!("({}", name);
+    let spans: Vec<Span> = params
+        .iter()
+        .map(|param| {
+            label.push(' ');
+            let start = label.chars().count();
+            label.push_str(param);
+            start..label.chars().count()
+        })
+        .collect();
+    label.push(')');
+
+    let rope = Rope::from_str(&label);
+    let parameters = spans
+        .iter()
+        .map(|span| {
+            let start = encoding.len(&rope, &(0..span.start))? as u32;
+            let end = start + encoding.len(&rope, span)? as u32;
+            Some(ParameterInformation {
+                label: ParameterLabel::LabelOffsets([start, end]),
+                documentation: None,
+            })
+        })
+        .collect::<Option<_>>()?;
+    Some((label, parameters))
+}
+
+fn call_hierarchy_item(
+    rope: &Rope,
+    uri: &Url,
+    name: &str,
+    kind: SymbolKind,
+    span: &Span,
+    selection_span: &Span,
+    encoding: PositionEncoding,
+) -> Option<CallHierarchyItem> {
+    Some(CallHierarchyItem {
+        name: name.to_string(),
+        kind,
+        tags: None,
+        detail: None,
+        uri: uri.clone(),
+        range: span_to_range(rope, span, encoding)?,
+        selection_range: span_to_range(rope, selection_span, encoding)?,
+        data: None,
+    })
 }
 
 impl Backend {
     pub fn new(client: Client) -> Backend {
         Backend {
             client,
-            publish_diagnostics_capable: Mutex::new(false),
-            rope_map: Mutex::new(HashMap::new()),
-            token_types_map: Mutex::new(HashMap::new()),
-            semantic_token_map: Mutex::new(HashMap::new()),
+            publish_diagnostics_capable: RwLock::new(false),
+            snippet_capable: RwLock::new(false),
+            definition_link_capable: RwLock::new(false),
+            semantic_tokens_refresh_capable: RwLock::new(false),
+            multiline_token_capable: RwLock::new(false),
+            position_encoding: RwLock::new(PositionEncoding::default()),
+            work_done_progress_capable: RwLock::new(false),
+            workspace_folders: RwLock::new(vec![]),
+            rope_map: DashMap::new(),
+            token_types_map: DashMap::new(),
+            semantic_token_map: DashMap::new(),
+            ast_map: DashMap::new(),
+            token_map: DashMap::new(),
+            diagnostic_map: DashMap::new(),
+            config: RwLock::new(Config::default()),
+            source_hash_map: DashMap::new(),
+            language_id_map: DashMap::new(),
+            version_map: DashMap::new(),
+            edit_map: DashMap::new(),
+            pending_compile_map: DashMap::new(),
+            compile_generation: AtomicU64::new(0),
         }
     }
-    pub async fn compile(&self, uri: Url, src: &str) {
-        self.rope_map
-            .lock()
-            .unwrap()
-            .insert(uri.to_string(), Rope::from_str(src));
+    /// Parses `src` and publishes its diagnostics, unless it is identical to
+    /// the last compiled source of `uri`. Returns whether it was parsed.
+    pub async fn compile(&self, uri: Url, src: &str) -> bool {
+        let hash = source_hash(src);
+        let previous_hash = self.source_hash_map.insert(uri.to_string(), hash);
+        // `src` is the text of the version current now, before any await
+        // lets a newer change in.
+        let version = self.version_map.get(uri.as_str()).map(|version| *version);
+        if previous_hash == Some(hash) {
+            // Edits that undo each other lead back to the compiled text, so
+            // its results hold for this version too.
+            let restamped = match self.token_map.get_mut(uri.as_str()) {
+                Some(mut compiled) if compiled.hash == hash && compiled.version != version => {
+                    compiled.version = version;
+                    true
+                }
+                _ => false,
+            };
+            if restamped {
+                let diagnostics = self
+                    .diagnostic_map
+                    .get(uri.as_str())
+                    .map(|diagnostics| diagnostics.clone())
+                    .unwrap_or_default();
+                self.send_publish_diagnostics(uri, diagnostics).await;
+            }
+            return false;
+        }
+
+        let rope = Rope::from_str(src);
+        let (max_depth, verbose) = {
+            let config = self.config.read().await;
+            (config.max_depth, config.verbose)
+        };
+        let started = verbose.then(Instant::now);
+        // When edits since the last error-free compile lead to exactly this
+        // source, only the form they touch needs parsing again.
+        let parse_result = match self.edit_map.remove(uri.as_str()) {
+            Some((_, (Some(edit), edited_hash))) if edited_hash == hash => {
+                match self.cached_parse(uri.as_str()) {
+                    Some(previous) => reparse(previous, src, edit, max_depth),
+                    None => parse_with_max_depth(src, max_depth),
+                }
+            }
+            _ => parse_with_max_depth(src, max_depth),
+        };
+        if let Some(started) = started {
+            let message = format!(
+                "parsed {} in {:?}: {} tokens, {} errors",
+                uri,
+                started.elapsed(),
+                parse_result.tokens.len(),
+                parse_result.parse_errors.len()
+            );
+            self.client.log_message(MessageType::LOG, message).await;
+        }
+        let error_free = parse_result.parse_errors.is_empty();
+        let len = parse_result.len;
+        let diagnostics = self.collect_diagnostics(&uri, &rope, &parse_result).await;
 
-        let semantic_tokens = parse(src).semantic_tokens;
+        // A change made while this compile awaited has already updated the
+        // rope, found no edits to add to and compiles again itself, so the
+        // rope and the edits are left to it.
+        let current = self.version_map.get(uri.as_str()).map(|version| *version) == version;
+        if current {
+            self.rope_map.insert(uri.to_string(), rope);
+        }
 
         self.semantic_token_map
-            .lock()
-            .unwrap()
-            .insert(uri.to_string(), semantic_tokens);
+            .insert(uri.to_string(), parse_result.semantic_tokens);
+
+        self.ast_map.insert(uri.to_string(), parse_result.ast);
+
+        self.token_map.insert(
+            uri.to_string(),
+            CompiledTokens {
+                version,
+                hash,
+                len,
+                tokens: parse_result.tokens,
+            },
+        );
+        self.diagnostic_map
+            .insert(uri.to_string(), diagnostics.clone());
+
+        if error_free && current {
+            self.edit_map.insert(uri.to_string(), (None, hash));
+        }
 
-        let diagnostics = vec![
-            create_simple_diagnostics("diagnostic message 1".into(), 0, 0, 0, 5),
-            create_simple_diagnostics("diagnostic message 2".into(), 1, 0, 1, 5),
-        ];
         self.send_publish_diagnostics(uri, diagnostics).await;
+        true
+    }
+
+    /// The last parse of `uri`, which had no errors if edits are tracked.
+    fn cached_parse(&self, uri: &str) -> Option<ParseResult> {
+        Some(ParseResult {
+            len: self.token_map.get(uri)?.len,
+            tokens: self.token_map.get(uri)?.tokens.clone(),
+            semantic_tokens: self.semantic_token_map.get(uri)?.clone(),
+            parse_errors: vec![],
+            ast: self.ast_map.get(uri)?.clone(),
+        })
+    }
+
+    /// The tokens and AST of `rope`, the current text of `uri`. Those of
+    /// the last compile are borrowed when it compiled the current version;
+    /// while a debounced compile is pending, `rope` is parsed here instead.
+    fn parsed(&self, uri: &str, rope: &Rope, max_depth: usize) -> Parsed<'_> {
+        let version = self.version_map.get(uri).map(|version| *version);
+        let compiled = self
+            .token_map
+            .get(uri)
+            .filter(|compiled| compiled.version == version);
+        match (compiled, self.ast_map.get(uri)) {
+            (Some(compiled), Some(ast)) => Parsed::Compiled(compiled, ast),
+            _ => Parsed::Fresh(parse_with_max_depth(&rope.to_string(), max_depth)),
+        }
+    }
+
+    /// Parses the file at `path` as it is on disk, unless it is open.
+    fn index_file(&self, path: &Path, max_depth: usize) {
+        if let (Ok(uri), Ok(src)) = (Url::from_file_path(path), std::fs::read_to_string(path)) {
+            // Holding the entry keeps a document opened meanwhile from
+            // being overwritten by what's on disk.
+            if let dashmap::mapref::entry::Entry::Vacant(entry) =
+                self.rope_map.entry(uri.to_string())
+            {
+                let parse_result = parse_with_max_depth(&src, max_depth);
+                self.ast_map.insert(uri.to_string(), parse_result.ast);
+                self.token_map.insert(
+                    uri.to_string(),
+                    CompiledTokens {
+                        version: None,
+                        hash: source_hash(&src),
+                        len: parse_result.len,
+                        tokens: parse_result.tokens,
+                    },
+                );
+                entry.insert(Rope::from_str(&src));
+            }
+        }
+    }
+
+    /// Parses the `.ore` files in the workspace folders that aren't open
+    /// yet, so workspace symbols cover them. Shows the progress when the
+    /// client supports work done progress.
+    async fn index_workspace(&self) {
+        let mut files = vec![];
+        for folder in self.workspace_folders.read().await.iter() {
+            if let Ok(dir) = folder.uri.to_file_path() {
+                ore_files(&dir, &mut files);
+            }
+        }
+        files.sort();
+        if files.is_empty() {
+            return;
+        }
+
+        let token = NumberOrString::String("orelang/indexWorkspace".into());
+        let report = *self.work_done_progress_capable.read().await
+            && self
+                .client
+                .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                    token: token.clone(),
+                })
+                .await
+                .is_ok();
+        let progress = |progress| async {
+            if report {
+                self.client
+                    .send_notification::<notification::Progress>(ProgressParams {
+                        token: token.clone(),
+                        value: ProgressParamsValue::WorkDone(progress),
+                    })
+                    .await;
+            }
+        };
+
+        let total = files.len();
+        progress(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: "Indexing orelang files".into(),
+            cancellable: Some(false),
+            message: Some(format!("0/{}", total)),
+            percentage: Some(0),
+        }))
+        .await;
+        let max_depth = self.config.read().await.max_depth;
+        for (index, path) in files.iter().enumerate() {
+            self.index_file(path, max_depth);
+            progress(WorkDoneProgress::Report(WorkDoneProgressReport {
+                cancellable: Some(false),
+                message: Some(format!("{}/{}", index + 1, total)),
+                percentage: Some(((index + 1) * 100 / total) as u32),
+            }))
+            .await;
+        }
+        progress(WorkDoneProgress::End(WorkDoneProgressEnd {
+            message: Some(format!("Indexed {} files", total)),
+        }))
+        .await;
+    }
+
+    /// Whether `uri` is handled, which only documents opened with a foreign
+    /// language id aren't.
+    fn is_supported(&self, uri: &Url) -> bool {
+        self.language_id_map
+            .get(uri.as_str())
+            .is_none_or(|language_id| is_orelang(&language_id, uri))
+    }
+
+    fn open_documents(&self) -> Vec<Url> {
+        self.source_hash_map
+            .iter()
+            .filter_map(|entry| Url::parse(entry.key()).ok())
+            .collect()
+    }
+
+    /// Compiles every open document again, e.g. after the configuration
+    /// changed, bypassing the unchanged-content check.
+    async fn recompile_all(&self) {
+        for uri in self.open_documents() {
+            self.recompile(uri).await;
+        }
+    }
+
+    /// Compiles the stored text of `uri` again, bypassing the
+    /// unchanged-content check.
+    async fn recompile(&self, uri: Url) {
+        let Some(src) = self.rope_map.get(uri.as_str()).map(|rope| rope.to_string()) else {
+            return;
+        };
+        self.source_hash_map.remove(uri.as_str());
+        self.edit_map.remove(uri.as_str());
+        self.compile(uri, &src).await;
+    }
+
+    /// Compiles `src` once no newer change for `uri` arrives within the
+    /// debounce delay. Each call replaces the pending one for the same `uri`,
+    /// so the latest content wins. Returns whether this call compiled.
+    pub async fn schedule_compile(&self, uri: Url, src: &str) -> bool {
+        let delay = self.config.read().await.debounce();
+        let generation = self.compile_generation.fetch_add(1, Ordering::SeqCst);
+        let timer = tokio::spawn(tokio::time::sleep(delay));
+
+        let previous = self
+            .pending_compile_map
+            .insert(uri.to_string(), (generation, timer.abort_handle()));
+        if let Some((_, previous)) = previous {
+            previous.abort();
+        }
+
+        if timer.await.is_err() {
+            return false;
+        }
+
+        self.pending_compile_map
+            .remove_if(uri.as_str(), |_, (pending, _)| *pending == generation);
+        self.compile(uri, src).await;
+        true
+    }
+
+    /// Parse errors and static analysis warnings, followed by the first
+    /// runtime error of evaluating the top-level forms when evaluation
+    /// diagnostics are enabled. Diagnostics of the same range are merged.
+    async fn collect_diagnostics(
+        &self,
+        uri: &Url,
+        rope: &Rope,
+        parse_result: &ParseResult,
+    ) -> Vec<Diagnostic> {
+        let encoding = *self.position_encoding.read().await;
+        let config = self.config.read().await.clone();
+        let severities = &config.diagnostic_severities;
+        let diagnostic = |span: &Span, level: Level, message: String| {
+            Some(Diagnostic {
+                severity: Some(level.severity()?),
+                ..Diagnostic::new_simple(span_to_range(rope, span, encoding)?, message)
+            })
+        };
+
+        let mut diagnostics = parse_result
+            .parse_errors
+            .iter()
+            .filter_map(|err| {
+                let message = parse_error_message(err);
+                let level = match message.as_str() {
+                    "unclosed '('" | "unexpected ')'" => severities.unbalanced_parens,
+                    _ => Level::Error,
+                };
+                diagnostic(&err.span(), level, message)
+            })
+            .collect::<Vec<_>>();
+
+        diagnostics.extend(
+            check_unbound(&parse_result.ast)
+                .into_iter()
+                .filter_map(|(span, message)| {
+                    diagnostic(&span, severities.unbound_variable, message)
+                }),
+        );
+
+        diagnostics.extend(
+            check_arity(&parse_result.ast)
+                .iter()
+                .filter_map(|(span, message)| {
+                    diagnostic(span, severities.arity_mismatch, message.clone())
+                }),
+        );
+
+        diagnostics.extend(
+            check_types(&parse_result.ast)
+                .into_iter()
+                .filter_map(|(span, message)| diagnostic(&span, severities.type_mismatch, message)),
+        );
+
+        diagnostics.extend(
+            check_duplicate_defuns(&parse_result.ast)
+                .into_iter()
+                .filter_map(|duplicate| {
+                    let first_range = span_to_range(rope, &duplicate.first_span, encoding)?;
+                    Some(Diagnostic {
+                        related_information: Some(vec![DiagnosticRelatedInformation {
+                            location: Location::new(uri.clone(), first_range),
+                            message: format!("'{}' first defined here", duplicate.name),
+                        }]),
+                        ..diagnostic(
+                            &duplicate.span,
+                            severities.duplicate_defun,
+                            format!("function '{}' is already defined", duplicate.name),
+                        )?
+                    })
+                }),
+        );
+
+        diagnostics.extend(check_unused(&parse_result.ast).into_iter().filter_map(
+            |(span, message)| {
+                Some(Diagnostic {
+                    tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                    ..diagnostic(&span, Level::Hint, message)?
+                })
+            },
+        ));
+
+        diagnostics.extend(
+            check_unreachable(&parse_result.ast, &config.diverging_forms)
+                .iter()
+                .filter_map(|span| {
+                    Some(Diagnostic {
+                        tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                        ..diagnostic(span, Level::Hint, "unreachable code".into())?
+                    })
+                }),
+        );
+
+        if config.enable_eval_diagnostics {
+            // Like `eval_document`, evaluation runs on a blocking thread and
+            // stops at its next step once this future is dropped.
+            let ast = parse_result.ast.clone();
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+            let evaluation = tokio::task::spawn_blocking(move || {
+                eval_program(&ast, &mut Env::new().with_cancellation(cancelled)).err()
+            });
+            if let Ok(Some(err)) = evaluation.await {
+                diagnostics.extend(diagnostic(err.span(), Level::Error, err.to_string()));
+            }
+        }
+
+        merge_diagnostics(diagnostics)
+    }
+
+    /// Evaluates the document's top-level forms in order, with the numbers,
+    /// strings and booleans in `globals` defined. With a range, stops after
+    /// the forms it covers and returns the value of the last of them.
+    ///
+    /// Evaluation runs on a blocking thread and stops at its next step once
+    /// the returned future is dropped, like when the client cancels.
+    async fn eval_document(
+        &self,
+        uri: &str,
+        range: Option<Range>,
+        globals: HashMap<String, serde_json::Value>,
+    ) -> Result<Option<String>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let forms = self.forms_to_eval(uri, range, encoding, max_depth)?;
+        if forms.is_empty() {
+            return Ok(None);
+        }
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+        let evaluation = tokio::task::spawn_blocking(move || {
+            let globals = globals
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        serde_json::Value::Number(n) => n.as_f64().map(Value::Number),
+                        serde_json::Value::String(s) => Some(Value::Str(s)),
+                        serde_json::Value::Bool(b) => Some(Value::Bool(b)),
+                        _ => None,
+                    };
+                    value.map(|value| (name.clone(), value)).ok_or_else(|| {
+                        Error::invalid_params(format!("unsupported value for global '{}'", name))
+                    })
+                })
+                .collect::<Result<HashMap<_, _>>>()?;
+            let mut env = Env::new()
+                .with_globals(globals)
+                .with_cancellation(cancelled);
+            Ok(eval_program(&forms, &mut env)
+                .map(|value| value.map(|value| value.to_display_string())))
+        });
+
+        match evaluation.await.map_err(|_| Error::internal_error())?? {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                let location = self
+                    .rope_map
+                    .get(uri)
+                    .and_then(|rope| span_to_range(&rope, err.span(), encoding))
+                    .map(|range| {
+                        format!(" at {}:{}", range.start.line + 1, range.start.character + 1)
+                    })
+                    .unwrap_or_default();
+                Err(Error {
+                    // RequestFailed: the request was valid but evaluation failed.
+                    code: ErrorCode::ServerError(-32803),
+                    message: format!("{}{}", err, location).into(),
+                    data: None,
+                })
+            }
+        }
+    }
+
+    /// The top-level forms of `uri` to evaluate: all of them, or with a
+    /// range those up to the last one it covers. Empty when the range
+    /// covers none.
+    fn forms_to_eval(
+        &self,
+        uri: &str,
+        range: Option<Range>,
+        encoding: PositionEncoding,
+        max_depth: usize,
+    ) -> Result<Vec<Spanned<Expr>>> {
+        let Some(rope) = self.rope_map.get(uri) else {
+            return Err(Error::invalid_params(format!("unknown document {}", uri)));
+        };
+        let parsed = self.parsed(uri, &rope, max_depth);
+        let ast = parsed.ast();
+
+        let forms = match range {
+            Some(range) => {
+                let start = position_to_offset(&rope, range.start, encoding)
+                    .ok_or_else(|| Error::invalid_params("range out of bounds"))?;
+                let end = position_to_offset(&rope, range.end, encoding)
+                    .ok_or_else(|| Error::invalid_params("range out of bounds"))?;
+                let forms = ast
+                    .iter()
+                    .take_while(|(_, span)| span.start <= end)
+                    .collect::<Vec<_>>();
+                if forms.last().is_none_or(|(_, span)| span.end < start) {
+                    return Ok(vec![]);
+                }
+                forms.into_iter().cloned().collect()
+            }
+            None => ast.to_vec(),
+        };
+        Ok(forms)
     }
 
     pub async fn send_publish_diagnostics(&self, uri: Url, diagnostics: Vec<Diagnostic>) {
-        if *(self.publish_diagnostics_capable.lock().unwrap()) {
+        if *self.publish_diagnostics_capable.read().await
+            && self.config.read().await.enable_diagnostics
+        {
+            let version = self.version_map.get(uri.as_str()).map(|version| *version);
             self.client
-                .publish_diagnostics(uri, diagnostics, None)
+                .publish_diagnostics(uri, diagnostics, version)
                 .await;
         }
     }
@@ -85,19 +1254,68 @@ impl Backend {
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        *self.config.write().await = Config::from_value(params.initialization_options.as_ref());
+        *self.workspace_folders.write().await = params.workspace_folders.unwrap_or_default();
+        let semantic_tokens_refresh_capable = || -> Option<bool> {
+            params
+                .capabilities
+                .workspace
+                .as_ref()?
+                .semantic_tokens
+                .as_ref()?
+                .refresh_support
+        }()
+        .unwrap_or(false);
+        *self.semantic_tokens_refresh_capable.write().await = semantic_tokens_refresh_capable;
+        let work_done_progress_capable =
+            || -> Option<bool> { params.capabilities.window.as_ref()?.work_done_progress }()
+                .unwrap_or(false);
+        *self.work_done_progress_capable.write().await = work_done_progress_capable;
+        let position_encoding =
+            PositionEncoding::negotiate(|| -> Option<&[PositionEncodingKind]> {
+                params
+                    .capabilities
+                    .general
+                    .as_ref()?
+                    .position_encodings
+                    .as_deref()
+            }());
+        *self.position_encoding.write().await = position_encoding;
+
         let token_types = if let Some(text_document) = params.capabilities.text_document {
             let publish_diagnostics_capable = text_document.publish_diagnostics.is_some();
-            *self.publish_diagnostics_capable.lock().unwrap() = publish_diagnostics_capable;
+            *self.publish_diagnostics_capable.write().await = publish_diagnostics_capable;
+            let snippet_capable = || -> Option<bool> {
+                text_document
+                    .completion
+                    .as_ref()?
+                    .completion_item
+                    .as_ref()?
+                    .snippet_support
+            }()
+            .unwrap_or(false);
+            *self.snippet_capable.write().await = snippet_capable;
+            let definition_link_capable =
+                || -> Option<bool> { text_document.definition.as_ref()?.link_support }()
+                    .unwrap_or(false);
+            *self.definition_link_capable.write().await = definition_link_capable;
+            let multiline_token_capable = || -> Option<bool> {
+                text_document
+                    .semantic_tokens
+                    .as_ref()?
+                    .multiline_token_support
+            }()
+            .unwrap_or(false);
+            *self.multiline_token_capable.write().await = multiline_token_capable;
             let token_types =
                 || -> Option<_> { Some(text_document.semantic_tokens?.token_types) }()
                     .unwrap_or_default();
 
-            let mut token_types_map = self.token_types_map.lock().unwrap();
             token_types
                 .iter()
                 .enumerate()
                 .for_each(|(index, token_type)| {
-                    token_types_map.insert(token_type.clone(), index);
+                    self.token_types_map.insert(token_type.clone(), index);
                 });
 
             token_types
@@ -107,15 +1325,24 @@ impl LanguageServer for Backend {
 
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                position_encoding: Some(position_encoding.kind()),
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                        ..Default::default()
+                    },
                 )),
                 semantic_tokens_provider: Some(
                     SemanticTokensServerCapabilities::SemanticTokensOptions(
                         SemanticTokensOptions {
                             legend: SemanticTokensLegend {
                                 token_types,
-                                token_modifiers: vec![],
+                                token_modifiers: PAREN_DEPTH_MODIFIERS
+                                    .into_iter()
+                                    .map(SemanticTokenModifier::new)
+                                    .collect(),
                             },
                             range: Some(false),
                             full: Some(SemanticTokensFullOptions::Bool(true)),
@@ -123,88 +1350,348 @@ impl LanguageServer for Backend {
                         },
                     ),
                 ),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![EVAL_COMMAND.into()],
+                    work_done_progress_options: Default::default(),
+                }),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
+                document_highlight_provider: Some(OneOf::Left(true)),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec![" ".into()]),
+                    retrigger_characters: None,
+                    work_done_progress_options: Default::default(),
+                }),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                document_range_formatting_provider: Some(OneOf::Left(true)),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: "\n".into(),
+                    more_trigger_character: None,
+                }),
+                completion_provider: Some(CompletionOptions {
+                    resolve_provider: Some(true),
+                    ..Default::default()
+                }),
+                code_lens_provider: Some(CodeLensOptions {
+                    resolve_provider: Some(false),
+                }),
+                definition_provider: Some(OneOf::Left(true)),
+                call_hierarchy_provider: Some(CallHierarchyServerCapability::Simple(true)),
+                moniker_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                document_link_provider: Some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: Default::default(),
+                }),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                color_provider: Some(ColorProviderCapability::Simple(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                linked_editing_range_provider: Some(LinkedEditingRangeServerCapabilities::Simple(
+                    true,
+                )),
                 ..Default::default()
             },
             server_info: None,
         })
     }
     async fn initialized(&self, _: InitializedParams) {
+        let registration = Registration {
+            id: "workspace/didChangeConfiguration".into(),
+            method: "workspace/didChangeConfiguration".into(),
+            register_options: None,
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(MessageType::WARNING, err.to_string())
+                .await;
+        }
         self.client
             .log_message(MessageType::INFO, "server initialized!")
             .await;
+        self.index_workspace().await;
     }
+    /// Cancels the pending debounced compiles, so nothing is published to
+    /// the closing client, and forgets every document.
     async fn shutdown(&self) -> Result<()> {
+        self.pending_compile_map.retain(|_, (_, timer)| {
+            timer.abort();
+            false
+        });
+        self.rope_map.clear();
+        self.semantic_token_map.clear();
+        self.ast_map.clear();
+        self.token_map.clear();
+        self.diagnostic_map.clear();
+        self.source_hash_map.clear();
+        self.language_id_map.clear();
+        self.version_map.clear();
+        self.edit_map.clear();
         Ok(())
     }
 
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        let mut workspace_folders = self.workspace_folders.write().await;
+        let event = params.event;
+        workspace_folders.retain(|folder| !event.removed.contains(folder));
+        for folder in event.added {
+            if !workspace_folders.contains(&folder) {
+                workspace_folders.push(folder);
+            }
+        }
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        let config = Config::from_value(Some(&params.settings));
+        if self.config.read().await.enable_diagnostics && !config.enable_diagnostics {
+            for uri in self.open_documents() {
+                self.send_publish_diagnostics(uri, vec![]).await;
+            }
+        }
+        // Tokens already sent for open documents are stale once they would
+        // be produced differently.
+        let tokens_changed = {
+            let previous = self.config.read().await;
+            previous.rainbow_parens != config.rainbow_parens
+                || previous.max_depth != config.max_depth
+        };
+        *self.config.write().await = config;
+        self.recompile_all().await;
+
+        if tokens_changed && *self.semantic_tokens_refresh_capable.read().await {
+            if let Err(err) = self.client.semantic_tokens_refresh().await {
+                self.client
+                    .log_message(MessageType::WARNING, err.to_string())
+                    .await;
+            }
+        }
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         let uri = params.text_document.uri;
         let text = params.text_document.text;
+        self.language_id_map
+            .insert(uri.to_string(), params.text_document.language_id);
+        if !self.is_supported(&uri) {
+            return;
+        }
+        self.version_map
+            .insert(uri.to_string(), params.text_document.version);
+        self.edit_map.remove(uri.as_str());
+        // Requests can arrive before the compile is done, and find the
+        // document known.
+        self.rope_map.insert(uri.to_string(), Rope::from_str(&text));
         self.compile(uri, &text).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(content_change) = params.content_changes.last() {
-            let uri = params.text_document.uri;
-            let text = &content_change.text;
-            self.compile(uri, text).await;
+        let encoding = *self.position_encoding.read().await;
+        let uri = params.text_document.uri;
+        if !self.is_supported(&uri) {
+            return;
+        }
+        self.version_map
+            .insert(uri.to_string(), params.text_document.version);
+        let (text, edits) = {
+            let mut rope = self.rope_map.entry(uri.to_string()).or_default();
+            let edits: Vec<_> = params
+                .content_changes
+                .iter()
+                .map(|content_change| apply_content_change(&mut rope, content_change, encoding))
+                .collect();
+            (rope.to_string(), edits)
+        };
+        // Replacing the whole document forgets the edits, so the next
+        // compile parses it in full.
+        if let Some(mut entry) = self.edit_map.get_mut(uri.as_str()) {
+            let pending = edits.into_iter().try_fold(entry.0, |pending, edit| {
+                let edit = edit?;
+                Some(Some(pending.map_or(edit, |pending| pending.then(edit))))
+            });
+            match pending {
+                Some(pending) => *entry = (pending, source_hash(&text)),
+                None => {
+                    drop(entry);
+                    self.edit_map.remove(uri.as_str());
+                }
+            }
+        }
+        self.schedule_compile(uri, &text).await;
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri;
+        if !self.is_supported(&uri) {
+            return;
+        }
+        self.recompile(uri.clone()).await;
+
+        if self.config.read().await.eval_on_save {
+            let message = match self.eval_document(uri.as_str(), None, HashMap::new()).await {
+                Ok(Some(value)) => format!("{}: {}", uri, value),
+                Ok(None) => format!("{}: no result", uri),
+                Err(err) => format!("{}: {}", uri, err.message),
+            };
+            self.client.log_message(MessageType::INFO, message).await;
         }
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         let uri = params.text_document.uri;
-        self.send_publish_diagnostics(uri, vec![]).await;
+        let supported = self.is_supported(&uri);
+        self.language_id_map.remove(uri.as_str());
+        if !supported {
+            return;
+        }
+        self.source_hash_map.remove(uri.as_str());
+        self.edit_map.remove(uri.as_str());
+        self.send_publish_diagnostics(uri.clone(), vec![]).await;
+        self.version_map.remove(uri.as_str());
+
+        // What's cached was of the closed text. A file of the workspace is
+        // indexed from disk again, so workspace symbols keep covering it.
+        self.rope_map.remove(uri.as_str());
+        self.semantic_token_map.remove(uri.as_str());
+        self.ast_map.remove(uri.as_str());
+        self.token_map.remove(uri.as_str());
+        self.diagnostic_map.remove(uri.as_str());
+        if let Ok(path) = uri.to_file_path() {
+            let in_workspace = self.workspace_folders.read().await.iter().any(|folder| {
+                folder
+                    .uri
+                    .to_file_path()
+                    .is_ok_and(|dir| path.starts_with(dir))
+            });
+            if in_workspace && path.extension().is_some_and(|ext| ext == "ore") {
+                let max_depth = self.config.read().await.max_depth;
+                self.index_file(&path, max_depth);
+            }
+        }
     }
 
     async fn semantic_tokens_full(
         &self,
         params: SemanticTokensParams,
     ) -> Result<Option<SemanticTokensResult>> {
+        let encoding = *self.position_encoding.read().await;
         let uri = params.text_document.uri.to_string();
-        let token_types_map = self.token_types_map.lock().unwrap();
+        let (rainbow_parens, max_depth) = {
+            let config = self.config.read().await;
+            (config.rainbow_parens, config.max_depth)
+        };
+        let multiline_token_capable = *self.multiline_token_capable.read().await;
 
+        // Tokens that can't be delta encoded, because they overflow `u32` or
+        // start before the previous one, are skipped rather than failing the
+        // whole request.
+        let mut skipped = 0;
         let semantic_tokens = || -> Option<Vec<SemanticToken>> {
-            let binding = self.rope_map.lock().unwrap();
-            let rope = binding.get(&uri)?;
-            let binding = self.semantic_token_map.lock().unwrap();
-            let v = binding.get(&uri)?;
+            let rope = self.rope_map.get(&uri)?;
+            // A document whose compile of the current version hasn't
+            // finished yet is parsed here, since no tokens would clear the
+            // client's highlighting and older ones would be misplaced.
+            let version = self.version_map.get(&uri).map(|version| *version);
+            let compiled = self
+                .token_map
+                .get(&uri)
+                .is_some_and(|compiled| compiled.version == version);
+            let cached = self.semantic_token_map.get(&uri).filter(|_| compiled);
+            let parsed;
+            let v = match &cached {
+                Some(cached) => cached.as_slice(),
+                None => {
+                    parsed = parse_with_max_depth(&rope.to_string(), max_depth).semantic_tokens;
+                    &parsed
+                }
+            };
             let mut pre_line = 0;
             let mut pre_column = 0;
-            let semantic_tokens = v
-                .iter()
-                .filter_map(|token| {
-                    let line = rope.try_byte_to_line(token.start).ok()?;
-                    let line_first = rope.try_line_to_char(line).ok()?;
-                    let column = rope.try_byte_to_char(token.start).ok()? - line_first;
-                    let token_type = token_types_map.get(&token.token_type)?;
-
-                    let delta_line = line - pre_line;
-                    let delta_start = if delta_line == 0 {
-                        column - pre_column
+            let semantic_tokens = highlights(v)
+                .into_iter()
+                .filter(|(_, _, modifiers)| rainbow_parens || modifiers.is_empty())
+                .flat_map(|(span, token_type, modifiers)| {
+                    let spans = if multiline_token_capable {
+                        vec![span]
                     } else {
-                        column
+                        split_lines(&rope, span)
                     };
+                    spans
+                        .into_iter()
+                        .map(move |span| (span, token_type.clone(), modifiers.clone()))
+                })
+                .filter_map(|(span, token_type, modifiers)| {
+                    let Position {
+                        line,
+                        character: column,
+                    } = offset_to_position(&rope, span.start, encoding)?;
+                    // Clients without the `function` or `parameter` type
+                    // still see those identifiers as variables.
+                    let token_type = *self.token_types_map.get(&token_type).or_else(|| {
+                        [SemanticTokenType::FUNCTION, SemanticTokenType::PARAMETER]
+                            .contains(&token_type)
+                            .then(|| self.token_types_map.get(&SemanticTokenType::VARIABLE))?
+                    })?;
 
-                    let ret = Some(SemanticToken {
-                        delta_line: delta_line.try_into().unwrap(),
-                        delta_start: delta_start.try_into().unwrap(),
-                        length: token.length.try_into().unwrap(),
-                        token_type: *token_type as u32,
-                        token_modifiers_bitset: 0,
-                    });
+                    let encoded = || -> Option<SemanticToken> {
+                        let delta_line = line.checked_sub(pre_line)?;
+                        let delta_start = if delta_line == 0 {
+                            column.checked_sub(pre_column)?
+                        } else {
+                            column
+                        };
+                        Some(SemanticToken {
+                            delta_line,
+                            delta_start,
+                            length: encoding.len(&rope, &span)?.try_into().ok()?,
+                            token_type: token_type.try_into().ok()?,
+                            token_modifiers_bitset: modifiers
+                                .iter()
+                                .filter_map(|modifier| {
+                                    PAREN_DEPTH_MODIFIERS
+                                        .iter()
+                                        .position(|name| *name == modifier.as_str())
+                                })
+                                .fold(0, |bitset, index| bitset | 1 << index),
+                        })
+                    }();
+                    if encoded.is_none() {
+                        skipped += 1;
+                        return None;
+                    }
 
                     pre_line = line;
                     pre_column = column;
 
-                    ret
+                    encoded
                 })
                 .collect::<Vec<_>>();
 
             Some(semantic_tokens)
         }();
 
-        let result = semantic_tokens.map(|semantic_tokens| {
-            SemanticTokensResult::Tokens(SemanticTokens {
+        if skipped > 0 {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("{}: skipped {} malformed semantic tokens", uri, skipped),
+                )
+                .await;
+        }
+
+        let result = semantic_tokens.map(|semantic_tokens| {
+            SemanticTokensResult::Tokens(SemanticTokens {
                 result_id: None,
                 data: semantic_tokens,
             })
@@ -212,13 +1699,3840 @@ impl LanguageServer for Backend {
 
         Ok(result)
     }
+
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position_params.position;
+
+        let highlights = || -> Option<Vec<DocumentHighlight>> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let tokens = parsed.tokens();
+            let (token, span) = token_at_offset(tokens, offset)?;
+
+            let (spans, kind) = match token {
+                Token::LParen | Token::RParen => {
+                    let (open, close) = match_paren(tokens, span.start)?;
+                    (vec![open, close], DocumentHighlightKind::TEXT)
+                }
+                Token::Ident(name) => (
+                    find_identifier_occurrences(tokens, name),
+                    DocumentHighlightKind::READ,
+                ),
+                _ => return None,
+            };
+
+            spans
+                .iter()
+                .map(|span| {
+                    Some(DocumentHighlight {
+                        range: span_to_range(&rope, span, encoding)?,
+                        kind: Some(kind),
+                    })
+                })
+                .collect()
+        }();
+
+        Ok(highlights)
+    }
+
+    async fn document_color(&self, params: DocumentColorParams) -> Result<Vec<ColorInformation>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+        let Some(rope) = self.rope_map.get(&uri) else {
+            return Ok(vec![]);
+        };
+        let parsed = self.parsed(&uri, &rope, max_depth);
+        let ast = parsed.ast();
+
+        let colors = collect_colors(ast)
+            .into_iter()
+            .filter_map(|(span, [red, green, blue])| {
+                Some(ColorInformation {
+                    range: span_to_range(&rope, &span, encoding)?,
+                    color: Color {
+                        red: red as f32 / 255.0,
+                        green: green as f32 / 255.0,
+                        blue: blue as f32 / 255.0,
+                        alpha: 1.0,
+                    },
+                })
+            })
+            .collect();
+
+        Ok(colors)
+    }
+
+    async fn color_presentation(
+        &self,
+        params: ColorPresentationParams,
+    ) -> Result<Vec<ColorPresentation>> {
+        let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let Color {
+            red, green, blue, ..
+        } = params.color;
+        let [red, green, blue] = [channel(red), channel(green), channel(blue)];
+
+        let presentations = [
+            format!("(rgb {} {} {})", red, green, blue),
+            format!("\"#{:02x}{:02x}{:02x}\"", red, green, blue),
+        ]
+        .into_iter()
+        .map(|label| ColorPresentation {
+            text_edit: Some(TextEdit::new(params.range, label.clone())),
+            label,
+            additional_text_edits: None,
+        })
+        .collect();
+
+        Ok(presentations)
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+
+        let folding_ranges = || -> Option<Vec<FoldingRange>> {
+            let rope = self.rope_map.get(&uri)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let tokens = parsed.tokens();
+            // Lists fold from their `(` to their `)`, strings over their own
+            // span. Either only folds when it spans several lines.
+            let mut open = vec![];
+            let mut spans = vec![];
+            for (token, span) in tokens {
+                match token {
+                    Token::LParen => open.push(span.start),
+                    Token::RParen => spans.extend(open.pop().map(|start| start..span.end)),
+                    Token::Str(_) => spans.push(span.clone()),
+                    _ => {}
+                }
+            }
+            spans.sort_by_key(|span| span.start);
+
+            let folding_ranges = spans
+                .iter()
+                .filter_map(|span| {
+                    let range = span_to_range(&rope, span, encoding)?;
+                    (range.end.line > range.start.line).then_some(FoldingRange {
+                        start_line: range.start.line,
+                        start_character: Some(range.start.character),
+                        end_line: range.end.line,
+                        end_character: Some(range.end.character),
+                        kind: None,
+                        collapsed_text: None,
+                    })
+                })
+                .collect();
+            Some(folding_ranges)
+        }();
+
+        Ok(folding_ranges)
+    }
+
+    async fn linked_editing_range(
+        &self,
+        params: LinkedEditingRangeParams,
+    ) -> Result<Option<LinkedEditingRanges>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position_params.position;
+
+        let linked_editing_ranges = || -> Option<LinkedEditingRanges> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let tokens = parsed.tokens();
+            let (open, close) = match_paren(tokens, offset)?;
+
+            Some(LinkedEditingRanges {
+                ranges: vec![
+                    span_to_range(&rope, &open, encoding)?,
+                    span_to_range(&rope, &close, encoding)?,
+                ],
+                word_pattern: None,
+            })
+        }();
+
+        Ok(linked_editing_ranges)
+    }
+
+    /// Expands from the expression at each position through the lists and
+    /// quotes around it.
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+
+        let selection_ranges = || -> Option<Vec<SelectionRange>> {
+            let rope = self.rope_map.get(&uri)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let ast = parsed.ast();
+            params
+                .positions
+                .iter()
+                .map(|position| {
+                    let offset = position_to_offset(&rope, *position, encoding)?;
+                    let mut selection_range = None;
+                    for span in enclosing_spans(ast, offset).iter().rev() {
+                        selection_range = Some(SelectionRange {
+                            range: span_to_range(&rope, span, encoding)?,
+                            parent: selection_range.map(Box::new),
+                        });
+                    }
+                    // Without an expression there, the position alone.
+                    Some(selection_range.unwrap_or(SelectionRange {
+                        range: Range::new(*position, *position),
+                        parent: None,
+                    }))
+                })
+                .collect()
+        }();
+
+        Ok(selection_ranges)
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri;
+        let range = params.range;
+
+        let code_actions = || -> Option<CodeActionResponse> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let selection = position_to_offset(&rope, range.start, encoding)?
+                ..position_to_offset(&rope, range.end, encoding)?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let tokens = parsed.tokens();
+
+            let mut code_actions = paren_fix_actions(
+                &uri,
+                &rope,
+                tokens,
+                &selection,
+                &params.context.diagnostics,
+                encoding,
+            );
+            code_actions.extend(radix_actions(&uri, &rope, tokens, &selection, encoding));
+            code_actions.extend(extract_let_action(
+                &uri,
+                &rope,
+                tokens,
+                parsed.ast(),
+                &selection,
+                encoding,
+            ));
+            code_actions.extend(starter_template_action(&uri, &rope, encoding));
+            Some(code_actions)
+        }();
+
+        Ok(code_actions)
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let encoding = *self.position_encoding.read().await;
+        let uri = params.text_document.uri.to_string();
+        let config = self.config.read().await.clone();
+        let options = FormatOptions {
+            indent_width: config.indent_width,
+            reflow: config.reflow,
+            ..Default::default()
+        };
+
+        let edits = || -> Option<Vec<TextEdit>> {
+            let rope = self.rope_map.get(&uri)?;
+            let edits = if options.reflow {
+                let source = rope.to_string();
+                let formatted = format_source(&source, &options).ok()?;
+                if formatted == source {
+                    return Some(vec![]);
+                }
+                vec![(0..rope.len_chars(), formatted)]
+            } else {
+                trim_edits(&rope, &options)
+            };
+            edits
+                .into_iter()
+                .map(|(span, new_text)| {
+                    Some(TextEdit::new(
+                        span_to_range(&rope, &span, encoding)?,
+                        new_text,
+                    ))
+                })
+                .collect()
+        }();
+
+        Ok(edits)
+    }
+
+    /// Formats the top-level forms the range touches, or without `reflow`
+    /// trims the whitespace inside the range.
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let encoding = *self.position_encoding.read().await;
+        let uri = params.text_document.uri.to_string();
+        let config = self.config.read().await.clone();
+        let options = FormatOptions {
+            indent_width: config.indent_width,
+            reflow: config.reflow,
+            trailing_newline: false,
+            ..Default::default()
+        };
+
+        let edits = || -> Option<Vec<TextEdit>> {
+            let rope = self.rope_map.get(&uri)?;
+            let parsed = self.parsed(&uri, &rope, config.max_depth);
+            let ast = parsed.ast();
+            let selection = position_to_offset(&rope, params.range.start, encoding)?
+                ..position_to_offset(&rope, params.range.end, encoding)?;
+            let edits = if options.reflow {
+                ast.iter()
+                    .filter(|(_, span)| overlaps(span, &selection))
+                    .filter_map(|(_, span)| {
+                        let source = rope.get_slice(span.clone())?.to_string();
+                        let formatted = format_source(&source, &options).ok()?;
+                        (formatted != source).then(|| (span.clone(), formatted))
+                    })
+                    .collect()
+            } else {
+                trim_edits(&rope, &options)
+                    .into_iter()
+                    .filter(|(span, _)| overlaps(span, &selection))
+                    .collect::<Vec<_>>()
+            };
+            edits
+                .into_iter()
+                .map(|(span, new_text)| {
+                    Some(TextEdit::new(
+                        span_to_range(&rope, &span, encoding)?,
+                        new_text,
+                    ))
+                })
+                .collect()
+        }();
+
+        Ok(edits)
+    }
+
+    async fn on_type_formatting(
+        &self,
+        params: DocumentOnTypeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let encoding = *self.position_encoding.read().await;
+        let uri = params.text_document_position.text_document.uri.to_string();
+        let position = params.text_document_position.position;
+        let indent_width = self.config.read().await.indent_width;
+
+        let edits = || -> Option<Vec<TextEdit>> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            // Only the tokens before the cursor matter, so stop lexing there.
+            let source = rope.to_string();
+            let tokens: Vec<_> = tokenize(&source)
+                .take_while(|(_, span)| span.end <= offset)
+                .collect();
+            let depth = nesting_depth(&tokens, offset);
+
+            // Replace whatever indentation the editor already inserted.
+            let line = rope.get_line(position.line as usize)?;
+            let leading = line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+            Some(vec![TextEdit {
+                range: Range::new(
+                    Position::new(position.line, 0),
+                    Position::new(position.line, leading as u32),
+                ),
+                new_text: " ".repeat(depth * indent_width),
+            }])
+        }();
+
+        Ok(edits)
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri;
+
+        let code_lenses = || -> Option<Vec<CodeLens>> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let code_lenses = ast
+                .iter()
+                .filter_map(|(_, span)| {
+                    let range = span_to_range(&rope, span, encoding)?;
+                    Some(CodeLens {
+                        range,
+                        command: Some(Command {
+                            title: "▶ Evaluate".into(),
+                            command: EVAL_COMMAND.into(),
+                            arguments: Some(vec![
+                                serde_json::to_value(&uri).ok()?,
+                                serde_json::to_value(range).ok()?,
+                            ]),
+                        }),
+                        data: None,
+                    })
+                })
+                .collect();
+            Some(code_lenses)
+        }();
+
+        Ok(code_lenses)
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+
+        let inlay_hints = || -> Option<Vec<InlayHint>> {
+            let rope = self.rope_map.get(&uri)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let ast = parsed.ast();
+            let defuns = collect_defun_params(ast);
+
+            let inlay_hints = collect_parameter_hints(ast, &defuns)
+                .into_iter()
+                .filter_map(|(offset, param)| {
+                    let position = offset_to_position(&rope, offset, encoding)?;
+                    let in_range = params.range.start <= position && position <= params.range.end;
+                    in_range.then(|| InlayHint {
+                        position,
+                        label: InlayHintLabel::String(format!("{}:", param)),
+                        kind: Some(InlayHintKind::PARAMETER),
+                        text_edits: None,
+                        tooltip: None,
+                        padding_left: None,
+                        padding_right: Some(true),
+                        data: None,
+                    })
+                })
+                .collect();
+
+            Some(inlay_hints)
+        }();
+
+        Ok(inlay_hints)
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document_position.text_document.uri.to_string();
+        let position = params.text_document_position.position;
+        let snippet_capable = *self.snippet_capable.read().await;
+
+        let completions = || -> Option<Vec<CompletionItem>> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let prefix = completion_prefix(&rope, offset);
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let ast = parsed.ast();
+
+            let in_scope = names_in_scope(ast, offset)
+                .into_iter()
+                .map(|(name, kind)| match kind {
+                    NameKind::Function => (name, CompletionItemKind::FUNCTION),
+                    NameKind::Variable => (name, CompletionItemKind::VARIABLE),
+                });
+            let builtins = builtins().iter().map(|builtin| match builtin.kind {
+                BuiltinKind::Keyword => (builtin.name.to_string(), CompletionItemKind::KEYWORD),
+                BuiltinKind::Function => (builtin.name.to_string(), CompletionItemKind::FUNCTION),
+                BuiltinKind::Constant => (builtin.name.to_string(), CompletionItemKind::CONSTANT),
+            });
+            let snippets = SNIPPETS.iter().map(|(label, snippet, plain)| {
+                let (insert_text, insert_text_format) = if snippet_capable {
+                    (snippet, InsertTextFormat::SNIPPET)
+                } else {
+                    (plain, InsertTextFormat::PLAIN_TEXT)
+                };
+                CompletionItem {
+                    label: label.to_string(),
+                    kind: Some(CompletionItemKind::SNIPPET),
+                    detail: Some(format!("{} form", label)),
+                    insert_text: Some(insert_text.to_string()),
+                    insert_text_format: Some(insert_text_format),
+                    ..Default::default()
+                }
+            });
+            // Builtins are documented on resolve to keep this list small.
+            let builtins = builtins.map(|(label, kind)| CompletionItem {
+                label,
+                kind: Some(kind),
+                data: Some(serde_json::Value::from(BUILTIN_DATA)),
+                ..Default::default()
+            });
+            let completions = in_scope
+                .map(|(label, kind)| CompletionItem {
+                    label,
+                    kind: Some(kind),
+                    ..Default::default()
+                })
+                .chain(builtins)
+                .chain(snippets)
+                .filter(|item| item.label.starts_with(&prefix))
+                .collect();
+            Some(completions)
+        }();
+
+        // Typing more of the identifier only narrows the list further, which
+        // the client can do on its own, so the list is complete.
+        Ok(completions.map(|items| {
+            CompletionResponse::List(CompletionList {
+                is_incomplete: false,
+                items,
+            })
+        }))
+    }
+
+    async fn completion_resolve(&self, mut item: CompletionItem) -> Result<CompletionItem> {
+        let is_builtin = item.data.as_ref().and_then(|data| data.as_str()) == Some(BUILTIN_DATA);
+        if let Some(builtin) = builtin(&item.label).filter(|_| is_builtin) {
+            item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: builtin_documentation(builtin),
+            }));
+        }
+        Ok(item)
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let definition_link_capable = *self.definition_link_capable.read().await;
+
+        let definition = || -> Option<GotoDefinitionResponse> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let tokens = parsed.tokens();
+            let ast = parsed.ast();
+            let binding = find_definition(ast, offset)?;
+            let target_selection_range = span_to_range(&rope, &binding.span, encoding)?;
+            if !definition_link_capable {
+                return Some(GotoDefinitionResponse::Scalar(Location::new(
+                    uri.clone(),
+                    target_selection_range,
+                )));
+            }
+
+            let origin = token_at_offset(tokens, offset);
+            Some(GotoDefinitionResponse::Link(vec![LocationLink {
+                origin_selection_range: origin
+                    .and_then(|(_, span)| span_to_range(&rope, span, encoding)),
+                target_uri: uri.clone(),
+                target_range: span_to_range(&rope, &binding.form_span, encoding)?,
+                target_selection_range,
+            }]))
+        }();
+
+        Ok(definition)
+    }
+
+    async fn moniker(&self, params: MonikerParams) -> Result<Option<Vec<Moniker>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+
+        let moniker = || -> Option<Moniker> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let binding = find_definition(ast, offset)?;
+            let top_level = binding.kind == NameKind::Function
+                && ast.iter().any(|(_, span)| *span == binding.form_span);
+            // Only top-level defuns are visible from other files; everything
+            // else is told apart by where it is bound.
+            Some(if top_level {
+                Moniker {
+                    scheme: "orelang".into(),
+                    identifier: format!("{}#{}", uri, binding.name),
+                    unique: UniquenessLevel::Scheme,
+                    kind: Some(MonikerKind::Export),
+                }
+            } else {
+                Moniker {
+                    scheme: "orelang".into(),
+                    identifier: format!("{}#{}@{}", uri, binding.name, binding.span.start),
+                    unique: UniquenessLevel::Document,
+                    kind: Some(MonikerKind::Local),
+                }
+            })
+        }();
+
+        Ok(moniker.map(|moniker| vec![moniker]))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+        let rope = self
+            .rope_map
+            .get(&uri)
+            .ok_or_else(|| Error::invalid_params(format!("unknown document {}", uri)))?;
+        let offset = position_to_offset(&rope, params.position, encoding)
+            .ok_or_else(|| Error::invalid_params("position out of bounds"))?;
+        let parsed = self.parsed(&uri, &rope, max_depth);
+        let tokens = parsed.tokens();
+        let span = match token_at_offset(tokens, offset) {
+            Some((Token::Ident(name), _)) if builtin(name).is_some() => {
+                return Err(Error::invalid_params(format!("`{}` is built in", name)));
+            }
+            Some((Token::Ident(_), span)) => span,
+            _ => return Err(Error::invalid_params("only identifiers can be renamed")),
+        };
+        let ast = parsed.ast();
+        if find_definition(ast, offset).is_none() {
+            return Err(Error::invalid_params("no definition to rename"));
+        }
+        Ok(span_to_range(&rope, span, encoding).map(PrepareRenameResponse::Range))
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let new_name = params.new_name;
+        let renamed = tokenize(&new_name)
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+        if renamed != [Token::Ident(new_name.clone())] || builtin(&new_name).is_some() {
+            return Err(Error::invalid_params(format!(
+                "`{}` is not a valid name",
+                new_name
+            )));
+        }
+
+        let edit = || -> Option<WorkspaceEdit> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let binding = find_definition(ast, offset)?;
+            let edits = find_references(ast, &binding)
+                .iter()
+                .map(|span| {
+                    Some(TextEdit::new(
+                        span_to_range(&rope, span, encoding)?,
+                        new_name.clone(),
+                    ))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)])))
+        }();
+
+        Ok(edit)
+    }
+
+    async fn prepare_call_hierarchy(
+        &self,
+        params: CallHierarchyPrepareParams,
+    ) -> Result<Option<Vec<CallHierarchyItem>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+
+        let item = || -> Option<CallHierarchyItem> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let defun = find_definition(ast, offset)
+                .filter(|binding| binding.kind == NameKind::Function)?;
+            call_hierarchy_item(
+                &rope,
+                &uri,
+                &defun.name,
+                SymbolKind::FUNCTION,
+                &defun.form_span,
+                &defun.span,
+                encoding,
+            )
+        }();
+
+        Ok(item.map(|item| vec![item]))
+    }
+
+    async fn incoming_calls(
+        &self,
+        params: CallHierarchyIncomingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyIncomingCall>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let item = params.item;
+        let Some((target, top_level)) = || -> Option<_> {
+            let rope = self.rope_map.get(item.uri.as_str())?;
+            let offset = position_to_offset(&rope, item.selection_range.start, encoding)?;
+            let parsed = self.parsed(item.uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let target = find_definition(ast, offset)?;
+            let top_level = ast.iter().any(|(_, span)| *span == target.form_span);
+            Some((target, top_level))
+        }() else {
+            return Ok(None);
+        };
+
+        let mut incoming: Vec<CallHierarchyIncomingCall> = vec![];
+        let uris: Vec<String> = self
+            .rope_map
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in uris {
+            let (Ok(uri), Some(rope)) = (Url::parse(&key), self.rope_map.get(&key)) else {
+                continue;
+            };
+            let parsed = self.parsed(&key, &rope, max_depth);
+            let ast = parsed.ast();
+            for call in collect_calls(ast) {
+                // Other documents can only reach a top-level defun, through a
+                // name they don't bind themselves.
+                let callee = find_definition(ast, call.span.start);
+                let resolves = if uri == item.uri {
+                    callee.as_ref() == Some(&target)
+                } else {
+                    top_level && callee.is_none()
+                };
+                if call.name != target.name || !resolves {
+                    continue;
+                }
+                let Some(from_range) = span_to_range(&rope, &call.span, encoding) else {
+                    continue;
+                };
+                let from = match &call.caller {
+                    Some(caller) => call_hierarchy_item(
+                        &rope,
+                        &uri,
+                        &caller.name,
+                        SymbolKind::FUNCTION,
+                        &caller.form_span,
+                        &caller.span,
+                        encoding,
+                    ),
+                    None => ast
+                        .iter()
+                        .find(|(_, span)| {
+                            span.start <= call.span.start && call.span.end <= span.end
+                        })
+                        .and_then(|(_, span)| {
+                            let name = uri.path_segments()?.next_back()?;
+                            call_hierarchy_item(
+                                &rope,
+                                &uri,
+                                name,
+                                SymbolKind::FILE,
+                                span,
+                                &call.span,
+                                encoding,
+                            )
+                        }),
+                };
+                let Some(from) = from else {
+                    continue;
+                };
+                match incoming.iter_mut().find(|call| call.from == from) {
+                    Some(call) => call.from_ranges.push(from_range),
+                    None => incoming.push(CallHierarchyIncomingCall {
+                        from,
+                        from_ranges: vec![from_range],
+                    }),
+                }
+            }
+        }
+
+        Ok(Some(incoming))
+    }
+
+    async fn outgoing_calls(
+        &self,
+        params: CallHierarchyOutgoingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyOutgoingCall>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let item = params.item;
+
+        let outgoing = || -> Option<Vec<CallHierarchyOutgoingCall>> {
+            let rope = self.rope_map.get(item.uri.as_str())?;
+            let offset = position_to_offset(&rope, item.selection_range.start, encoding)?;
+            let parsed = self.parsed(item.uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let caller = find_definition(ast, offset)?;
+
+            let mut outgoing: Vec<CallHierarchyOutgoingCall> = vec![];
+            for call in collect_calls(ast) {
+                if call.caller.as_ref() != Some(&caller) {
+                    continue;
+                }
+                let Some(from_range) = span_to_range(&rope, &call.span, encoding) else {
+                    continue;
+                };
+                // Builtins have no definition, so they point at the call.
+                let to = match find_definition(ast, call.span.start) {
+                    Some(callee) => call_hierarchy_item(
+                        &rope,
+                        &item.uri,
+                        &callee.name,
+                        match callee.kind {
+                            NameKind::Function => SymbolKind::FUNCTION,
+                            NameKind::Variable => SymbolKind::VARIABLE,
+                        },
+                        &callee.form_span,
+                        &callee.span,
+                        encoding,
+                    ),
+                    None if builtin(&call.name).is_some() => call_hierarchy_item(
+                        &rope,
+                        &item.uri,
+                        &call.name,
+                        SymbolKind::FUNCTION,
+                        &call.span,
+                        &call.span,
+                        encoding,
+                    )
+                    .map(|item| CallHierarchyItem {
+                        detail: Some("builtin".into()),
+                        ..item
+                    }),
+                    None => None,
+                };
+                let Some(to) = to else {
+                    continue;
+                };
+                match outgoing
+                    .iter_mut()
+                    .find(|call| call.to.name == to.name && call.to.detail == to.detail)
+                {
+                    Some(call) => call.from_ranges.push(from_range),
+                    None => outgoing.push(CallHierarchyOutgoingCall {
+                        to,
+                        from_ranges: vec![from_range],
+                    }),
+                }
+            }
+            Some(outgoing)
+        }();
+
+        Ok(outgoing)
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri.to_string();
+
+        let symbols = || -> Option<DocumentSymbolResponse> {
+            let rope = self.rope_map.get(&uri)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let ast = parsed.ast();
+            let symbols = collect_symbols(ast)
+                .into_iter()
+                .filter_map(|symbol| document_symbol(&rope, symbol, encoding))
+                .collect();
+            Some(DocumentSymbolResponse::Nested(symbols))
+        }();
+
+        Ok(symbols)
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> Result<Option<Vec<DocumentLink>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params.text_document.uri;
+        let folders = self.workspace_folders.read().await.clone();
+
+        let links = || -> Option<Vec<DocumentLink>> {
+            let rope = self.rope_map.get(uri.as_str())?;
+            let parsed = self.parsed(uri.as_str(), &rope, max_depth);
+            let ast = parsed.ast();
+            let links = collect_requires(ast)
+                .into_iter()
+                .filter_map(|(path, span)| {
+                    Some(DocumentLink {
+                        range: span_to_range(&rope, &span, encoding)?,
+                        target: Some(resolve_require(&uri, &path, &folders)?),
+                        tooltip: None,
+                        data: None,
+                    })
+                })
+                .collect();
+            Some(links)
+        }();
+
+        Ok(links)
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position_params.position;
+
+        let hover = || -> Option<Hover> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let source = rope.to_string();
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let tokens = parsed.tokens();
+            let ast = parsed.ast();
+
+            let documentation = || -> Option<(String, Span)> {
+                let (Token::Ident(name), span) = token_at_offset(tokens, offset)? else {
+                    return None;
+                };
+                let defuns = collect_defun_params(ast);
+                let Some(params) = defuns.get(name) else {
+                    return Some((builtin_documentation(builtin(name)?), span.clone()));
+                };
+
+                let signature = std::iter::once(name)
+                    .chain(params)
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let mut value = format!("```orelang\n({})\n```", signature);
+                if let Some(doc) = collect_doc_comments(&source, tokens).get(name) {
+                    value.push_str("\n\n");
+                    value.push_str(doc);
+                }
+                Some((value, span.clone()))
+            }();
+            // The value of the innermost list around the cursor, when it
+            // can't depend on anything but literals.
+            let evaluation = constant_list_at(ast, offset).and_then(|expr| {
+                let value = eval(expr, &mut Env::new()).ok()?;
+                Some((format!("evaluates to `{}`", value), expr.1.clone()))
+            });
+
+            let (value, span) = match (documentation, evaluation) {
+                (Some((mut value, span)), Some((evaluation, _))) => {
+                    value.push_str("\n\n");
+                    value.push_str(&evaluation);
+                    (value, span)
+                }
+                (Some(documentation), None) => documentation,
+                (None, evaluation) => evaluation?,
+            };
+            Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: span_to_range(&rope, &span, encoding),
+            })
+        }();
+
+        Ok(hover)
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position_params.position;
+
+        let signature_help = || -> Option<SignatureHelp> {
+            let rope = self.rope_map.get(&uri)?;
+            let offset = position_to_offset(&rope, position, encoding)?;
+            let parsed = self.parsed(&uri, &rope, max_depth);
+            let tokens = parsed.tokens();
+            let (name, active_parameter) = call_context(tokens, offset)?;
+            let ast = parsed.ast();
+            let defuns = collect_defun_params(ast);
+            let params = defuns.get(&name)?;
+
+            let (label, parameters) = signature_label(&name, params, encoding)?;
+
+            Some(SignatureHelp {
+                signatures: vec![SignatureInformation {
+                    label,
+                    documentation: None,
+                    parameters: Some(parameters),
+                    active_parameter: None,
+                }],
+                active_signature: Some(0),
+                active_parameter: Some(active_parameter as u32),
+            })
+        }();
+
+        Ok(signature_help)
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        let encoding = *self.position_encoding.read().await;
+        let max_depth = self.config.read().await.max_depth;
+        let query = params.query.to_lowercase();
+        let matching = |uri: &str| -> Option<Vec<SymbolInformation>> {
+            let rope = self.rope_map.get(uri)?;
+            let parsed = self.parsed(uri, &rope, max_depth);
+            let ast = parsed.ast();
+            let url = Url::parse(uri).ok()?;
+            let symbols = collect_defuns(ast)
+                .into_iter()
+                .filter(|defun| defun.name.0.to_lowercase().contains(&query))
+                .filter_map(|defun| {
+                    #[allow(deprecated)]
+                    Some(SymbolInformation {
+                        name: defun.name.0,
+                        kind: SymbolKind::FUNCTION,
+                        tags: None,
+                        deprecated: None,
+                        location: Location::new(
+                            url.clone(),
+                            span_to_range(&rope, &defun.span, encoding)?,
+                        ),
+                        container_name: None,
+                    })
+                })
+                .collect::<Vec<_>>();
+            Some(symbols)
+        };
+
+        let uris: Vec<String> = self
+            .rope_map
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut symbols = vec![];
+        for uri in uris {
+            // A cancelled request is dropped at its next await, so yielding
+            // between documents stops the scan early.
+            tokio::task::yield_now().await;
+            symbols.extend(matching(&uri).unwrap_or_default());
+        }
+
+        symbols.sort_by(|a, b| {
+            (a.location.uri.as_str(), a.location.range.start)
+                .cmp(&(b.location.uri.as_str(), b.location.range.start))
+        });
+
+        Ok(Some(symbols))
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        if params.command != EVAL_COMMAND {
+            return Err(Error::invalid_params(format!(
+                "unknown command {}",
+                params.command
+            )));
+        }
+
+        let mut arguments = params.arguments.into_iter();
+        let uri = arguments
+            .next()
+            .and_then(|uri| serde_json::from_value::<Url>(uri).ok())
+            .ok_or_else(|| Error::invalid_params("expected a document uri"))?;
+        let range = arguments
+            .next()
+            .map(serde_json::from_value::<Option<Range>>)
+            .transpose()
+            .map_err(|_| Error::invalid_params("expected a range"))?
+            .flatten();
+        let globals = arguments
+            .next()
+            .map(serde_json::from_value::<HashMap<String, serde_json::Value>>)
+            .transpose()
+            .map_err(|_| Error::invalid_params("expected an object of globals"))?
+            .unwrap_or_default();
+
+        let result = self.eval_document(uri.as_str(), range, globals).await?;
+        Ok(result.map(serde_json::Value::String))
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
     let (service, socket) = LspService::new(Backend::new);
     Server::new(stdin, stdout, socket).serve(service).await;
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtins::Arity;
+    use crate::parser::{parse, DEFAULT_MAX_DEPTH};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tower_lsp::jsonrpc::{Request, Response};
+    use tower_service::Service;
+
+    const FACT: &str = "(defun fact (n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))\n";
+
+    /// Runs `initialize` and `initialized` through the service so the client
+    /// is live, then forwards everything the server sends to the returned
+    /// receiver. Requests from the server are answered with `null`.
+    async fn start(
+        initialization_options: serde_json::Value,
+    ) -> (LspService<Backend>, UnboundedReceiver<Request>) {
+        start_with_capabilities(
+            json!({ "textDocument": { "publishDiagnostics": {} } }),
+            initialization_options,
+        )
+        .await
+    }
+
+    /// Starts a server for a client with `capabilities`, recording what the
+    /// server sends it. Requests from the server are answered with `null`.
+    async fn start_with_capabilities(
+        capabilities: serde_json::Value,
+        initialization_options: serde_json::Value,
+    ) -> (LspService<Backend>, UnboundedReceiver<Request>) {
+        start_with_params(json!({
+            "capabilities": capabilities,
+            "initializationOptions": initialization_options,
+        }))
+        .await
+    }
+
+    /// Starts a server with the given `initialize` params.
+    async fn start_with_params(
+        params: serde_json::Value,
+    ) -> (LspService<Backend>, UnboundedReceiver<Request>) {
+        let (mut service, socket) = LspService::new(Backend::new);
+        let (sender, receiver) = unbounded_channel();
+        let (mut requests, mut responses) = socket.split();
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                if let Some(id) = request.id() {
+                    let response = Response::from_ok(id.clone(), serde_json::Value::Null);
+                    responses.send(response).await.unwrap();
+                }
+                if sender.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let initialize = Request::build("initialize").params(params).id(1).finish();
+        service.call(initialize).await.unwrap();
+        let initialized = Request::build("initialized").params(json!({})).finish();
+        service.call(initialized).await.unwrap();
+
+        (service, receiver)
+    }
+
+    /// Waits briefly for the next message with `method`, skipping others.
+    async fn next_message(
+        receiver: &mut UnboundedReceiver<Request>,
+        method: &str,
+    ) -> Option<serde_json::Value> {
+        loop {
+            let request = tokio::time::timeout(Duration::from_millis(200), receiver.recv())
+                .await
+                .ok()??;
+            if request.method() == method {
+                return Some(request.params().cloned().unwrap_or_default());
+            }
+        }
+    }
+
+    async fn code_actions(
+        service: &LspService<Backend>,
+        uri: &Url,
+        source: &str,
+        range: Range,
+    ) -> Vec<CodeActionOrCommand> {
+        open(service, uri, source).await;
+        service
+            .inner()
+            .code_action(CodeActionParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                range,
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn code_action_edit(uri: &Url, action: &CodeActionOrCommand) -> (String, TextEdit) {
+        let CodeActionOrCommand::CodeAction(action) = action else {
+            panic!("expected a code action");
+        };
+        let changes = action.edit.as_ref().unwrap().changes.as_ref().unwrap();
+        (action.title.clone(), changes[uri][0].clone())
+    }
+
+    async fn open(service: &LspService<Backend>, uri: &Url, text: &str) {
+        service
+            .inner()
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    uri.clone(),
+                    LANGUAGE_ID.into(),
+                    0,
+                    text.into(),
+                ),
+            })
+            .await;
+    }
+
+    fn position_params(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(line, character),
+        )
+    }
+
+    fn range(start_line: u32, start_column: u32, end_line: u32, end_column: u32) -> Range {
+        Range::new(
+            Position::new(start_line, start_column),
+            Position::new(end_line, end_column),
+        )
+    }
+
+    async fn diagnostics(backend: &Backend, uri: &Url, src: &str) -> Vec<Diagnostic> {
+        backend
+            .collect_diagnostics(uri, &Rope::from_str(src), &parse(src))
+            .await
+    }
+
+    async fn document_highlight(
+        service: &LspService<Backend>,
+        uri: &Url,
+        line: u32,
+        character: u32,
+    ) -> Option<Vec<DocumentHighlight>> {
+        service
+            .inner()
+            .document_highlight(DocumentHighlightParams {
+                text_document_position_params: position_params(uri, line, character),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn document_highlight_matching_paren() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let highlights = document_highlight(&service, &uri, 0, 12).await.unwrap();
+        let ranges: Vec<_> = highlights.iter().map(|h| h.range).collect();
+        assert_eq!(ranges, vec![range(0, 12, 0, 13), range(0, 14, 0, 15)]);
+        assert!(highlights
+            .iter()
+            .all(|h| h.kind == Some(DocumentHighlightKind::TEXT)));
+
+        let highlights = document_highlight(&service, &uri, 3, 27).await.unwrap();
+        let ranges: Vec<_> = highlights.iter().map(|h| h.range).collect();
+        assert_eq!(ranges, vec![range(0, 0, 0, 1), range(3, 27, 3, 28)]);
+    }
+
+    #[tokio::test]
+    async fn document_highlight_identifier() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let highlights = document_highlight(&service, &uri, 0, 13).await.unwrap();
+        let ranges: Vec<_> = highlights.iter().map(|h| h.range).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                range(0, 13, 0, 14),
+                range(1, 9, 1, 10),
+                range(3, 9, 3, 10),
+                range(3, 20, 3, 21),
+            ]
+        );
+        assert!(highlights
+            .iter()
+            .all(|h| h.kind == Some(DocumentHighlightKind::READ)));
+
+        assert_eq!(document_highlight(&service, &uri, 2, 6).await, None);
+    }
+
+    #[tokio::test]
+    async fn selection_range_expands_through_quotes() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///quote.ore").unwrap();
+        open(&service, &uri, "(print '(1 2 3))").await;
+
+        let selection_ranges = service
+            .inner()
+            .selection_range(SelectionRangeParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                positions: vec![Position::new(0, 11), Position::new(0, 7)],
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let chain = |mut selection_range: Option<&SelectionRange>| {
+            let mut ranges = vec![];
+            while let Some(current) = selection_range {
+                ranges.push(current.range);
+                selection_range = current.parent.as_deref();
+            }
+            ranges
+        };
+        assert_eq!(
+            chain(selection_ranges.first()),
+            vec![
+                range(0, 11, 0, 12),
+                range(0, 8, 0, 15),
+                range(0, 7, 0, 15),
+                range(0, 0, 0, 16),
+            ]
+        );
+        // On the quote itself, the quoted list isn't selected.
+        assert_eq!(
+            chain(selection_ranges.get(1)),
+            vec![range(0, 7, 0, 15), range(0, 0, 0, 16)]
+        );
+    }
+
+    #[tokio::test]
+    async fn linked_editing_range_balanced_pair() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let linked_editing_range = |line, character| {
+            service
+                .inner()
+                .linked_editing_range(LinkedEditingRangeParams {
+                    text_document_position_params: position_params(&uri, line, character),
+                    work_done_progress_params: Default::default(),
+                })
+        };
+
+        let ranges = linked_editing_range(1, 6).await.unwrap().unwrap().ranges;
+        assert_eq!(ranges, vec![range(1, 6, 1, 7), range(1, 12, 1, 13)]);
+
+        assert_eq!(linked_editing_range(0, 8).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn inlay_hint_parameter_names() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            &format!("{}(print (fact 5))\n(fact 1 2)\n", FACT),
+        )
+        .await;
+
+        let inlay_hints = service
+            .inner()
+            .inlay_hint(InlayHintParams {
+                work_done_progress_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                range: range(0, 0, 6, 0),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let hints: Vec<_> = inlay_hints
+            .iter()
+            .map(|hint| match &hint.label {
+                InlayHintLabel::String(label) => (hint.position, label.as_str()),
+                InlayHintLabel::LabelParts(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            hints,
+            vec![(Position::new(3, 17), "n:"), (Position::new(4, 13), "n:"),]
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_offers_names_in_scope() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, "(defun fact (n) )").await;
+
+        let Some(CompletionResponse::List(CompletionList { items, .. })) = service
+            .inner()
+            .completion(CompletionParams {
+                text_document_position: position_params(&uri, 0, 16),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected completion items");
+        };
+        let kind = |label: &str| {
+            items
+                .iter()
+                .find(|item| item.label == label)
+                .and_then(|item| item.kind)
+        };
+        assert_eq!(kind("n"), Some(CompletionItemKind::VARIABLE));
+        assert_eq!(kind("fact"), Some(CompletionItemKind::FUNCTION));
+        assert_eq!(kind("if"), Some(CompletionItemKind::KEYWORD));
+        assert_eq!(kind("+"), Some(CompletionItemKind::FUNCTION));
+    }
+
+    #[tokio::test]
+    async fn completion_filters_by_prefix() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(fa\n(\n(<\n(>=", FACT)).await;
+
+        let (service, uri) = (&service, &uri);
+        let labels = |line, character| async move {
+            let Some(CompletionResponse::List(list)) = service
+                .inner()
+                .completion(CompletionParams {
+                    text_document_position: position_params(uri, line, character),
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    context: None,
+                })
+                .await
+                .unwrap()
+            else {
+                panic!("expected a completion list");
+            };
+            assert!(!list.is_incomplete);
+            list.items
+                .into_iter()
+                .map(|item| item.label)
+                .collect::<HashSet<_>>()
+        };
+
+        let labels_after_fa = labels(4, 3).await;
+        assert!(labels_after_fa.contains("fact"));
+        assert!(labels_after_fa.contains("false"));
+        assert!(!labels_after_fa.contains("print"));
+
+        let labels_after_f = labels(4, 2).await;
+        assert!(labels_after_f.contains("fact"));
+        assert!(!labels_after_f.contains("print"));
+
+        let labels_after_paren = labels(5, 1).await;
+        assert!(labels_after_paren.contains("fact"));
+        assert!(labels_after_paren.contains("print"));
+        assert!(labels_after_paren.contains("+"));
+
+        assert_eq!(
+            labels(6, 2).await,
+            HashSet::from(["<".to_string(), "<=".to_string()])
+        );
+        assert_eq!(labels(7, 3).await, HashSet::from([">=".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn completion_snippets_follow_client_support() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, "").await;
+
+        let defun_snippet = || async {
+            let Some(CompletionResponse::List(CompletionList { items, .. })) = service
+                .inner()
+                .completion(CompletionParams {
+                    text_document_position: position_params(&uri, 0, 0),
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    context: None,
+                })
+                .await
+                .unwrap()
+            else {
+                panic!("expected completion items");
+            };
+            items
+                .into_iter()
+                .find(|item| {
+                    item.label == "defun" && item.kind == Some(CompletionItemKind::SNIPPET)
+                })
+                .unwrap()
+        };
+
+        let item = defun_snippet().await;
+        assert_eq!(item.insert_text_format, Some(InsertTextFormat::PLAIN_TEXT));
+        assert!(!item.insert_text.unwrap().contains('$'));
+
+        service
+            .inner()
+            .initialize(InitializeParams {
+                capabilities: serde_json::from_value(json!({
+                    "textDocument": { "completion": { "completionItem": { "snippetSupport": true } } }
+                }))
+                .unwrap(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let item = defun_snippet().await;
+        assert_eq!(item.insert_text_format, Some(InsertTextFormat::SNIPPET));
+        assert_eq!(
+            item.insert_text.as_deref(),
+            Some("(defun ${1:name} (${2:params})\n  $0)")
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_resolve_documents_builtins() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, "(+ 1 2)").await;
+
+        let Some(CompletionResponse::List(CompletionList { items, .. })) = service
+            .inner()
+            .completion(CompletionParams {
+                text_document_position: position_params(&uri, 0, 1),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected completion items");
+        };
+        assert!(items.iter().all(|item| item.documentation.is_none()));
+
+        let plus = items.into_iter().find(|item| item.label == "+").unwrap();
+        let resolved = service.inner().completion_resolve(plus).await.unwrap();
+        let Some(Documentation::MarkupContent(doc)) = resolved.documentation else {
+            panic!("expected markdown documentation");
+        };
+        assert!(doc.value.contains("Addition"));
+        assert!(doc.value.contains("at least 1"));
+
+        let local = CompletionItem::new_simple("+".into(), "a local".into());
+        let resolved = service.inner().completion_resolve(local).await.unwrap();
+        assert_eq!(resolved.documentation, None);
+    }
+
+    #[tokio::test]
+    async fn every_builtin_evaluates_and_completes() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///builtins.ore").unwrap();
+        open(&service, &uri, "()").await;
+        let Some(CompletionResponse::List(CompletionList { items, .. })) = service
+            .inner()
+            .completion(CompletionParams {
+                text_document_position: position_params(&uri, 0, 1),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected completion items");
+        };
+
+        for builtin in builtins() {
+            assert!(
+                items.iter().any(|item| item.label == builtin.name),
+                "'{}' isn't offered",
+                builtin.name
+            );
+            let source = match (builtin.kind, builtin.arity) {
+                (BuiltinKind::Function, Some(Arity::Exact(n) | Arity::AtLeast(n))) => {
+                    format!("({}{})", builtin.name, " 1".repeat(n))
+                }
+                _ => match builtin.name {
+                    "if" => "(if true 1 2)".into(),
+                    "defun" => "(defun f (x) x)".into(),
+                    "let" => "(let ((x 1)) x)".into(),
+                    "let*" => "(let* ((x 1)) x)".into(),
+                    "cond" | "else" => "(cond ((= 1 2) 1) (else 2))".into(),
+                    name => name.into(),
+                },
+            };
+            let result = eval_program(&parse(&source).ast, &mut Env::new());
+            let evaluated = match builtin.name {
+                // Failing with its argument is what `error` is for.
+                "error" => result.as_ref().is_err_and(|err| err.to_string() == "1"),
+                _ => result.is_ok(),
+            };
+            assert!(evaluated, "{} fails: {:?}", source, result);
+        }
+    }
+
+    #[tokio::test]
+    async fn hover_documents_builtins() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///builtins.ore").unwrap();
+        open(&service, &uri, "(print (+ 1 2))").await;
+
+        let Some(Hover {
+            contents: HoverContents::Markup(contents),
+            range: hover_range,
+        }) = service
+            .inner()
+            .hover(HoverParams {
+                text_document_position_params: position_params(&uri, 0, 1),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected a hover");
+        };
+        assert_eq!(contents.value, "Prints its argument.\n\nTakes 1 arguments.");
+        assert_eq!(hover_range, Some(range(0, 1, 0, 6)));
+    }
+
+    #[tokio::test]
+    async fn hover_evaluates_constant_lists() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///constant.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            "(defun f (n) (+ (* 2 3) (* n 3)))\n(print (/ 1 0))",
+        )
+        .await;
+        let (service, uri) = (&service, &uri);
+        let hover = |line, character| async move {
+            let Some(Hover {
+                contents: HoverContents::Markup(contents),
+                range: hover_range,
+            }) = service
+                .inner()
+                .hover(HoverParams {
+                    text_document_position_params: position_params(uri, line, character),
+                    work_done_progress_params: Default::default(),
+                })
+                .await
+                .unwrap()
+            else {
+                return None;
+            };
+            Some((contents.value, hover_range.unwrap()))
+        };
+
+        let (value, hover_range) = hover(0, 17).await.unwrap();
+        assert!(value.starts_with("Multiplication"));
+        assert!(value.ends_with("\n\nevaluates to `6`"));
+        assert_eq!(hover_range, range(0, 17, 0, 18));
+        assert_eq!(
+            hover(0, 19).await,
+            Some(("evaluates to `6`".into(), range(0, 16, 0, 23)))
+        );
+
+        let (value, _) = hover(0, 25).await.unwrap();
+        assert!(value.starts_with("Multiplication"));
+        assert!(!value.contains("evaluates to"));
+        assert_eq!(hover(0, 29).await, None);
+        // Errors are left to diagnostics.
+        assert_eq!(hover(1, 10).await, None);
+    }
+
+    #[tokio::test]
+    async fn goto_definition_resolves_locals() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(let ((n 5)) (fact n))\n", FACT)).await;
+
+        let goto_definition = |line, character| {
+            service.inner().goto_definition(GotoDefinitionParams {
+                text_document_position_params: position_params(&uri, line, character),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+        };
+        let target = |response: Option<GotoDefinitionResponse>| match response {
+            Some(GotoDefinitionResponse::Scalar(location)) => Some(location.range),
+            _ => None,
+        };
+
+        let n_in_body = goto_definition(1, 9).await.unwrap();
+        assert_eq!(target(n_in_body), Some(range(0, 13, 0, 14)));
+        let fact_call = goto_definition(3, 12).await.unwrap();
+        assert_eq!(target(fact_call), Some(range(0, 7, 0, 11)));
+        let n_in_let = goto_definition(4, 20).await.unwrap();
+        assert_eq!(target(n_in_let), Some(range(4, 7, 4, 8)));
+        assert_eq!(goto_definition(1, 7).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn goto_definition_location_link() {
+        let (service, _) = LspService::new(Backend::new);
+        service
+            .inner()
+            .initialize(InitializeParams {
+                capabilities: serde_json::from_value(json!({
+                    "textDocument": { "definition": { "linkSupport": true } }
+                }))
+                .unwrap(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(fact 5)\n", FACT)).await;
+
+        let response = service
+            .inner()
+            .goto_definition(GotoDefinitionParams {
+                text_document_position_params: position_params(&uri, 4, 3),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Some(GotoDefinitionResponse::Link(vec![LocationLink {
+                origin_selection_range: Some(range(4, 1, 4, 5)),
+                target_uri: uri.clone(),
+                target_range: range(0, 0, 3, 28),
+                target_selection_range: range(0, 7, 0, 11),
+            }]))
+        );
+    }
+
+    #[tokio::test]
+    async fn moniker_for_defuns_and_parameters() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let moniker = |line, character| {
+            service.inner().moniker(MonikerParams {
+                text_document_position_params: position_params(&uri, line, character),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+        };
+        assert_eq!(
+            moniker(3, 12).await.unwrap(),
+            Some(vec![Moniker {
+                scheme: "orelang".into(),
+                identifier: "file:///fact.ore#fact".into(),
+                unique: UniquenessLevel::Scheme,
+                kind: Some(MonikerKind::Export),
+            }])
+        );
+        let parameter = moniker(1, 9).await.unwrap().unwrap();
+        assert_eq!(parameter[0].identifier, "file:///fact.ore#n@13");
+        assert_eq!(parameter[0].kind, Some(MonikerKind::Local));
+        assert_eq!(moniker(1, 7).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn prepare_rename_rejects_non_identifiers() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let prepare_rename = |line, character| {
+            service
+                .inner()
+                .prepare_rename(position_params(&uri, line, character))
+        };
+        assert!(prepare_rename(0, 0).await.is_err());
+        assert!(prepare_rename(1, 4).await.is_err());
+        assert!(prepare_rename(1, 11).await.is_err());
+        assert_eq!(
+            prepare_rename(0, 9).await.unwrap(),
+            Some(PrepareRenameResponse::Range(range(0, 7, 0, 11)))
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_updates_references_in_scope() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(let ((n 5)) (fact n))\n", FACT)).await;
+
+        let rename = |line, character, new_name: &str| {
+            service.inner().rename(RenameParams {
+                text_document_position: position_params(&uri, line, character),
+                new_name: new_name.into(),
+                work_done_progress_params: Default::default(),
+            })
+        };
+        let edit = rename(4, 20, "m").await.unwrap().unwrap();
+        let edits = &edit.changes.unwrap()[&uri];
+        let ranges: Vec<_> = edits.iter().map(|edit| edit.range).collect();
+        assert_eq!(ranges, vec![range(4, 7, 4, 8), range(4, 19, 4, 20)]);
+        assert!(rename(4, 20, "if").await.is_err());
+        assert!(rename(4, 20, "two words").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rename_parameter_leaves_other_functions_alone() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///params.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            "(defun double (n) (* n 2))\n(defun inc (n) (+ n 1))\n",
+        )
+        .await;
+
+        let rename = |line, character| {
+            service.inner().rename(RenameParams {
+                text_document_position: position_params(&uri, line, character),
+                new_name: "x".into(),
+                work_done_progress_params: Default::default(),
+            })
+        };
+        for (line, character) in [(0, 16), (0, 22)] {
+            let edit = rename(line, character).await.unwrap().unwrap();
+            let ranges: Vec<_> = edit.changes.unwrap()[&uri]
+                .iter()
+                .map(|edit| edit.range)
+                .collect();
+            assert_eq!(ranges, vec![range(0, 15, 0, 16), range(0, 21, 0, 22)]);
+        }
+        let edit = rename(1, 19).await.unwrap().unwrap();
+        let ranges: Vec<_> = edit.changes.unwrap()[&uri]
+            .iter()
+            .map(|edit| edit.range)
+            .collect();
+        assert_eq!(ranges, vec![range(1, 12, 1, 13), range(1, 18, 1, 19)]);
+    }
+
+    #[tokio::test]
+    async fn call_hierarchy_of_fact() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(fact 5)\n", FACT)).await;
+        let other = Url::parse("file:///main.ore").unwrap();
+        open(&service, &other, "(defun twice (n) (fact (fact n)))\n").await;
+
+        let items = backend
+            .prepare_call_hierarchy(CallHierarchyPrepareParams {
+                text_document_position_params: position_params(&uri, 0, 8),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let [item] = items.as_slice() else {
+            panic!("expected a single item");
+        };
+        assert_eq!(item.name, "fact");
+        assert_eq!(item.range, range(0, 0, 3, 28));
+        assert_eq!(item.selection_range, range(0, 7, 0, 11));
+
+        let incoming = backend
+            .incoming_calls(CallHierarchyIncomingCallsParams {
+                item: item.clone(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut callers: Vec<_> = incoming
+            .iter()
+            .map(|call| {
+                (
+                    call.from.uri.path(),
+                    call.from.name.as_str(),
+                    call.from_ranges.clone(),
+                )
+            })
+            .collect();
+        callers.sort_by_key(|(path, _, _)| *path);
+        assert_eq!(
+            callers,
+            vec![
+                ("/fact.ore", "fact", vec![range(3, 12, 3, 16)]),
+                ("/fact.ore", "fact.ore", vec![range(4, 1, 4, 5)]),
+                (
+                    "/main.ore",
+                    "twice",
+                    vec![range(0, 18, 0, 22), range(0, 24, 0, 28)]
+                ),
+            ]
+        );
+
+        let outgoing = backend
+            .outgoing_calls(CallHierarchyOutgoingCallsParams {
+                item: item.clone(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let callees: Vec<_> = outgoing.iter().map(|call| call.to.name.as_str()).collect();
+        assert_eq!(callees, vec!["if", "=", "*", "fact", "-"]);
+        assert_eq!(outgoing[3].to.selection_range, range(0, 7, 0, 11));
+    }
+
+    #[tokio::test]
+    async fn document_symbol_outline() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///outline.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            "(defun area (r)\n  (let ((pi 3.14) (sq (* r r)))\n    (* pi sq)))\n",
+        )
+        .await;
+
+        let Some(DocumentSymbolResponse::Nested(symbols)) = service
+            .inner()
+            .document_symbol(DocumentSymbolParams {
+                text_document: TextDocumentIdentifier::new(uri),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected nested symbols");
+        };
+        assert_eq!(symbols.len(), 1);
+        let area = &symbols[0];
+        assert_eq!(area.kind, SymbolKind::FUNCTION);
+        assert_eq!(area.range, range(0, 0, 2, 15));
+        let children = area.children.as_ref().unwrap();
+        let names: Vec<_> = children
+            .iter()
+            .map(|child| (child.name.as_str(), child.kind, child.range))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("pi", SymbolKind::VARIABLE, range(1, 8, 1, 17)),
+                ("sq", SymbolKind::VARIABLE, range(1, 18, 1, 30)),
+            ]
+        );
+        assert_eq!(children[1].selection_range, range(1, 19, 1, 21));
+    }
+
+    #[tokio::test]
+    async fn hover_shows_doc_comment() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            &format!("; computes factorial\n{}(fact 5)\n", FACT),
+        )
+        .await;
+
+        let hover = |line, character| {
+            service.inner().hover(HoverParams {
+                text_document_position_params: position_params(&uri, line, character),
+                work_done_progress_params: Default::default(),
+            })
+        };
+
+        let Hover {
+            contents,
+            range: hover_range,
+        } = hover(5, 2).await.unwrap().unwrap();
+        assert_eq!(
+            contents,
+            HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "```orelang\n(fact n)\n```\n\ncomputes factorial".into(),
+            })
+        );
+        assert_eq!(hover_range, Some(range(5, 1, 5, 5)));
+
+        assert_eq!(hover(5, 6).await.unwrap(), None);
+        assert_eq!(hover(2, 9).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn signature_help_active_parameter() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(print (fact ", FACT)).await;
+
+        let signature_help = |line, character| {
+            service.inner().signature_help(SignatureHelpParams {
+                context: None,
+                text_document_position_params: position_params(&uri, line, character),
+                work_done_progress_params: Default::default(),
+            })
+        };
+
+        let help = signature_help(4, 13).await.unwrap().unwrap();
+        let signature = &help.signatures[0];
+        assert_eq!(signature.label, "(fact n)");
+        assert_eq!(help.active_parameter, Some(0));
+        assert_eq!(
+            signature.parameters.as_ref().unwrap()[0].label,
+            ParameterLabel::LabelOffsets([6, 7])
+        );
+
+        assert_eq!(signature_help(4, 7).await.unwrap(), None);
+        assert_eq!(signature_help(0, 0).await.unwrap(), None);
+    }
+
+    #[test]
+    fn signature_label_offsets_follow_encoding() {
+        let params = vec!["a😀".to_string(), "b".to_string()];
+        let offsets = |encoding| {
+            let (label, parameters) = signature_label("f😀", &params, encoding).unwrap();
+            assert_eq!(label, "(f😀 a😀 b)");
+            parameters
+                .into_iter()
+                .map(|param| param.label)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            offsets(PositionEncoding::Utf16),
+            vec![
+                ParameterLabel::LabelOffsets([5, 8]),
+                ParameterLabel::LabelOffsets([9, 10]),
+            ]
+        );
+        assert_eq!(
+            offsets(PositionEncoding::Utf32),
+            vec![
+                ParameterLabel::LabelOffsets([4, 6]),
+                ParameterLabel::LabelOffsets([7, 8]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_folders_follow_change_notifications() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let folder = |name: &str| WorkspaceFolder {
+            uri: Url::parse(&format!("file:///{}", name)).unwrap(),
+            name: name.into(),
+        };
+        backend
+            .initialize(InitializeParams {
+                workspace_folders: Some(vec![folder("a"), folder("b")]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            *backend.workspace_folders.read().await,
+            vec![folder("a"), folder("b")]
+        );
+
+        backend
+            .did_change_workspace_folders(DidChangeWorkspaceFoldersParams {
+                event: WorkspaceFoldersChangeEvent {
+                    added: vec![folder("c"), folder("b")],
+                    removed: vec![folder("a")],
+                },
+            })
+            .await;
+        assert_eq!(
+            *backend.workspace_folders.read().await,
+            vec![folder("b"), folder("c")]
+        );
+    }
+
+    #[tokio::test]
+    async fn document_links_to_required_files() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let root = std::env::temp_dir().join(format!("orelang-links-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("lib").join("util.ore"), "").unwrap();
+        backend
+            .initialize(InitializeParams {
+                workspace_folders: Some(vec![WorkspaceFolder {
+                    uri: Url::from_directory_path(&root).unwrap(),
+                    name: "root".into(),
+                }]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let uri = Url::from_file_path(root.join("src").join("main.ore")).unwrap();
+        open(
+            &service,
+            &uri,
+            "(require \"foo.ore\")\n(include \"lib/util.ore\")\n(print \"bar.ore\")\n",
+        )
+        .await;
+        let links = backend
+            .document_link(DocumentLinkParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let links: Vec<_> = links
+            .into_iter()
+            .map(|link| (link.range, link.target.unwrap()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (
+                    range(0, 9, 0, 18),
+                    Url::from_file_path(root.join("src").join("foo.ore")).unwrap()
+                ),
+                (
+                    range(1, 9, 1, 23),
+                    Url::from_file_path(root.join("lib").join("util.ore")).unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_scan_reports_progress() {
+        let root = std::env::temp_dir().join(format!("orelang-scan-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("main.ore"), FACT).unwrap();
+        std::fs::write(root.join("lib").join("util.ore"), "(defun facade () 1)").unwrap();
+        std::fs::write(root.join("notes.txt"), "(defun factoid () 1)").unwrap();
+        let folders = json!([{ "uri": Url::from_directory_path(&root).unwrap(), "name": "root" }]);
+
+        let (service, mut receiver) = start_with_params(json!({
+            "capabilities": { "window": { "workDoneProgress": true } },
+            "workspaceFolders": folders,
+        }))
+        .await;
+        let mut progress = vec![];
+        while let Some(params) = next_message(&mut receiver, "$/progress").await {
+            let value = &params["value"];
+            progress.push((value["kind"].clone(), value["message"].clone()));
+        }
+        assert_eq!(
+            progress,
+            vec![
+                (json!("begin"), json!("0/2")),
+                (json!("report"), json!("1/2")),
+                (json!("report"), json!("2/2")),
+                (json!("end"), json!("Indexed 2 files")),
+            ]
+        );
+
+        let names = || async {
+            let symbols = service
+                .inner()
+                .symbol(WorkspaceSymbolParams {
+                    query: "fac".into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            symbols
+                .into_iter()
+                .map(|symbol| symbol.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names().await, vec!["facade", "fact"]);
+
+        // Closing an edited file leaves what's on disk indexed.
+        let main = Url::from_file_path(root.join("main.ore")).unwrap();
+        open(&service, &main, "(defun factory () 1)").await;
+        assert_eq!(names().await, vec!["facade", "factory"]);
+        service
+            .inner()
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(main),
+            })
+            .await;
+        assert_eq!(names().await, vec!["facade", "fact"]);
+
+        // Without the capability the scan still runs, silently.
+        let (_, mut receiver) = start_with_params(json!({
+            "capabilities": {},
+            "workspaceFolders": folders,
+        }))
+        .await;
+        assert_eq!(next_message(&mut receiver, "$/progress").await, None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_workspace_symbol_stops_early() {
+        let (mut service, _) = start(json!(null)).await;
+        for index in 0..100 {
+            let uri = Url::parse(&format!("file:///doc{}.ore", index)).unwrap();
+            open(&service, &uri, FACT).await;
+        }
+
+        let request = Request::build("workspace/symbol")
+            .params(json!({ "query": "fact" }))
+            .id(2)
+            .finish();
+        let mut response = service.call(request);
+        // The scan yields after its first document instead of finishing.
+        assert!(futures::poll!(&mut response).is_pending());
+
+        let cancel = Request::build("$/cancelRequest")
+            .params(json!({ "id": 2 }))
+            .finish();
+        service.call(cancel).await.unwrap();
+        let response = tokio::time::timeout(Duration::from_millis(200), response)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            response.error().map(|err| err.code),
+            Some(ErrorCode::RequestCancelled)
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_symbol_across_documents() {
+        let (service, _) = LspService::new(Backend::new);
+        let fact = Url::parse("file:///fact.ore").unwrap();
+        let util = Url::parse("file:///util.ore").unwrap();
+        open(&service, &fact, FACT).await;
+        open(
+            &service,
+            &util,
+            "(defun factorial_iter (n acc) acc)\n(defun square (x) (* x x))\n",
+        )
+        .await;
+
+        let symbol = |query: &str| {
+            service.inner().symbol(WorkspaceSymbolParams {
+                query: query.into(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+        };
+
+        let symbols = symbol("FACT").await.unwrap().unwrap();
+        let found: Vec<_> = symbols
+            .iter()
+            .map(|symbol| (symbol.name.as_str(), symbol.location.uri.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![("fact", fact.clone()), ("factorial_iter", util.clone())]
+        );
+        assert_eq!(symbols[0].location.range, range(0, 0, 3, 28));
+
+        assert_eq!(symbol("").await.unwrap().unwrap().len(), 3);
+        assert!(symbol("nothing").await.unwrap().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn code_lens_per_top_level_form() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, &format!("{}(fact 5)\n", FACT)).await;
+
+        let code_lenses = service
+            .inner()
+            .code_lens(CodeLensParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let ranges: Vec<_> = code_lenses.iter().map(|lens| lens.range).collect();
+        assert_eq!(ranges, vec![range(0, 0, 3, 28), range(4, 0, 4, 8)]);
+
+        let command = code_lenses[1].command.clone().unwrap();
+        assert_eq!(command.command, EVAL_COMMAND);
+        let result = service
+            .inner()
+            .execute_command(ExecuteCommandParams {
+                command: command.command,
+                arguments: command.arguments.unwrap(),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, Some("120".into()));
+    }
+
+    #[tokio::test]
+    async fn execute_command_eval() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            &format!("{}(fact 5)\n(fact 3)\n(+ 1 x)\n", FACT),
+        )
+        .await;
+
+        let execute_command = |arguments: Vec<serde_json::Value>| {
+            service.inner().execute_command(ExecuteCommandParams {
+                command: EVAL_COMMAND.into(),
+                arguments,
+                work_done_progress_params: Default::default(),
+            })
+        };
+        let uri_argument = serde_json::to_value(&uri).unwrap();
+        let range_argument = |range| serde_json::to_value(range).unwrap();
+
+        let result = execute_command(vec![
+            uri_argument.clone(),
+            range_argument(range(4, 0, 4, 8)),
+        ]);
+        assert_eq!(result.await.unwrap(), Some("120".into()));
+
+        let result = execute_command(vec![
+            uri_argument.clone(),
+            range_argument(range(5, 1, 5, 1)),
+        ]);
+        assert_eq!(result.await.unwrap(), Some("6".into()));
+
+        let err = execute_command(vec![uri_argument.clone()])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::ServerError(-32803));
+        assert_eq!(err.message, "unbound variable 'x' at 7:6");
+
+        let result = execute_command(vec![uri_argument.clone(), json!(null), json!({ "x": 10 })]);
+        assert_eq!(result.await.unwrap(), Some("11".into()));
+        let err = execute_command(vec![uri_argument, json!(null), json!({ "x": [1] })])
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "unsupported value for global 'x'");
+
+        let err = execute_command(vec![]).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParams);
+    }
+
+    #[tokio::test]
+    async fn unbound_variable_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///unbound.ore").unwrap();
+        let src = "(defun f (x) (+ x 1))\n(print undefined_var)";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.severity, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                range(1, 7, 1, 20),
+                Some(DiagnosticSeverity::WARNING),
+                "unbound variable 'undefined_var'"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn lexer_error_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///lexer.ore").unwrap();
+        let src = "(print\n  @ 1) ]";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    range(1, 2, 1, 3),
+                    "unexpected character '@', expected '(', ')', an identifier, a number, a string or a comment"
+                ),
+                (
+                    range(1, 7, 1, 8),
+                    "unexpected character ']', expected '(', ')', an identifier, a number, a string, a comment or the end of input"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_code_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///unreachable.ore").unwrap();
+        let src = "(defun f (x)\n  (error \"x\")\n  (print x)\n  x)\n(f 1)";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.range, range(2, 2, 3, 3));
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::HINT));
+        assert_eq!(diagnostic.tags, Some(vec![DiagnosticTag::UNNECESSARY]));
+        assert_eq!(diagnostic.message, "unreachable code");
+    }
+
+    #[tokio::test]
+    async fn unused_variable_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///unused.ore").unwrap();
+        let src = "(defun f (n _m) 1)\n(let ((x 1) (_y 2)) (f 1 2))";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.severity, d.tags.clone(), d.message.as_str()))
+            .collect();
+        let unnecessary = Some(vec![DiagnosticTag::UNNECESSARY]);
+        assert_eq!(
+            found,
+            vec![
+                (
+                    range(0, 10, 0, 11),
+                    Some(DiagnosticSeverity::HINT),
+                    unnecessary.clone(),
+                    "unused variable 'n'"
+                ),
+                (
+                    range(1, 7, 1, 8),
+                    Some(DiagnosticSeverity::HINT),
+                    unnecessary,
+                    "unused variable 'x'"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn type_mismatch_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///types.ore").unwrap();
+        let src = "(defun f (n) (+ 1 n))\n(+ 1 \"x\")";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.severity, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                range(1, 5, 1, 8),
+                Some(DiagnosticSeverity::ERROR),
+                "'+' expects numbers, got a string"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_defun_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///dup.ore").unwrap();
+        let src = "(defun f (x) x)\n(defun f (y) (* y 2))\n";
+        let diagnostics = diagnostics(service.inner(), &uri, src).await;
+
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.range, range(1, 7, 1, 8));
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostic.message, "function 'f' is already defined");
+        let related = diagnostic.related_information.as_ref().unwrap();
+        assert_eq!(related[0].location, Location::new(uri, range(0, 7, 0, 8)));
+    }
+
+    #[tokio::test]
+    async fn arity_mismatch_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///arity.ore").unwrap();
+        let src = format!("{}(fact 1 2)\n(fact 1)\n(+ 1 2 3)\n", FACT);
+        let diagnostics = diagnostics(service.inner(), &uri, &src).await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.severity, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                range(4, 0, 4, 10),
+                Some(DiagnosticSeverity::ERROR),
+                "'fact' expects 1 arguments, got 2"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn eval_diagnostics() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///eval.ore").unwrap();
+
+        assert!(diagnostics(backend, &uri, "(+ 1 2)\n(/ 1 0)")
+            .await
+            .is_empty());
+
+        backend.config.write().await.enable_eval_diagnostics = true;
+        let diagnostics = diagnostics(backend, &uri, "(+ 1 2)\n(print (/ 1 0)) (").await;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.range, d.severity, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    range(1, 16, 1, 17),
+                    Some(DiagnosticSeverity::ERROR),
+                    "unclosed '('"
+                ),
+                (
+                    range(1, 7, 1, 14),
+                    Some(DiagnosticSeverity::ERROR),
+                    "division by zero"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn eval_diagnostics_leave_the_runtime_free() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///spin.ore").unwrap();
+        backend.config.write().await.enable_eval_diagnostics = true;
+
+        // A runaway program is evaluated on another thread, so collecting
+        // its diagnostics yields rather than blocking other requests.
+        let source = "(defun spin (n) (spin n))\n(spin 1)";
+        let (rope, parse_result) = (Rope::from_str(source), parse(source));
+        let collect = backend.collect_diagnostics(&uri, &rope, &parse_result);
+        tokio::pin!(collect);
+        assert!(tokio::time::timeout(Duration::ZERO, &mut collect)
+            .await
+            .is_err());
+        let found: Vec<_> = collect.await.into_iter().map(|d| d.message).collect();
+        assert_eq!(found, ["evaluation exceeded 1000000 steps"]);
+    }
+
+    #[tokio::test]
+    async fn diagnostics_of_the_same_range_merge() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///merge.ore").unwrap();
+        backend.config.write().await.enable_eval_diagnostics = true;
+        backend
+            .config
+            .write()
+            .await
+            .diagnostic_severities
+            .unbound_variable = Level::Warning;
+
+        // The unbound check and evaluation both report `x`.
+        let found: Vec<_> = diagnostics(backend, &uri, "(print x)")
+            .await
+            .into_iter()
+            .map(|d| (d.range, d.severity, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                range(0, 7, 0, 8),
+                Some(DiagnosticSeverity::ERROR),
+                "unbound variable 'x'".to_string()
+            )]
+        );
+
+        // Different messages for one range are combined.
+        let found: Vec<_> = diagnostics(backend, &uri, "(+ 1 \"a\")")
+            .await
+            .into_iter()
+            .map(|d| (d.range, d.severity, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(
+                range(0, 5, 0, 8),
+                Some(DiagnosticSeverity::ERROR),
+                "'+' expects numbers, got a string\n'+' expects numbers".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn compile_skips_identical_content() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///fact.ore").unwrap();
+
+        assert!(backend.compile(uri.clone(), FACT).await);
+        assert!(!backend.compile(uri.clone(), FACT).await);
+        assert!(backend.compile(uri.clone(), "(fact 5)").await);
+        assert!(backend.compile(uri.clone(), FACT).await);
+
+        backend
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+        assert!(backend.compile(uri, FACT).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn did_change_debounces_compiles() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///fact.ore").unwrap();
+
+        let (first, second, third) = tokio::join!(
+            backend.schedule_compile(uri.clone(), "(fact"),
+            backend.schedule_compile(uri.clone(), "(fact 5"),
+            backend.schedule_compile(uri.clone(), "(fact 5)"),
+        );
+        assert_eq!((first, second, third), (false, false, true));
+
+        let rope = backend.rope_map.get(uri.as_str()).unwrap().to_string();
+        assert_eq!(rope, "(fact 5)");
+        assert!(backend.pending_compile_map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_cancels_pending_compiles() {
+        let uri = Url::parse("file:///pending.ore").unwrap();
+        let (service, mut receiver) = start(json!({ "debounceMs": 50 })).await;
+        open(&service, &uri, "(print 1)").await;
+        next_message(&mut receiver, "textDocument/publishDiagnostics").await;
+
+        let backend = service.inner();
+        let (compiled, shutdown) =
+            tokio::join!(backend.schedule_compile(uri.clone(), "(+ 1"), async {
+                tokio::task::yield_now().await;
+                backend.shutdown().await
+            });
+        assert!(!compiled);
+        assert!(shutdown.is_ok());
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+        assert!(backend.pending_compile_map.is_empty());
+        assert!(backend.rope_map.is_empty());
+        assert!(backend.ast_map.is_empty());
+        assert!(backend.token_map.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn did_change_applies_every_content_change() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, "(print 1)").await;
+
+        service
+            .inner()
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![
+                    TextDocumentContentChangeEvent {
+                        range: None,
+                        range_length: None,
+                        text: "(fact 5)".into(),
+                    },
+                    TextDocumentContentChangeEvent {
+                        range: Some(range(0, 6, 0, 7)),
+                        range_length: None,
+                        text: "10".into(),
+                    },
+                    TextDocumentContentChangeEvent {
+                        range: Some(range(0, 0, 0, 0)),
+                        range_length: None,
+                        text: "; ten\n".into(),
+                    },
+                ],
+            })
+            .await;
+
+        let rope = service
+            .inner()
+            .rope_map
+            .get(uri.as_str())
+            .unwrap()
+            .to_string();
+        assert_eq!(rope, "; ten\n(fact 10)");
+    }
+
+    #[tokio::test]
+    async fn changes_during_a_compile_are_not_lost() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///edits.ore").unwrap();
+        open(&service, &uri, "(f 1)\n(g 2)\n").await;
+
+        // Applies a change and leaves its debounced compile pending.
+        let change = |version, range, text: &str| {
+            let params = DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(range),
+                    range_length: None,
+                    text: text.into(),
+                }],
+            };
+            async move {
+                let changed = tokio::time::timeout(Duration::ZERO, backend.did_change(params));
+                assert!(changed.await.is_err());
+            }
+        };
+
+        change(1, range(0, 3, 0, 4), "10").await;
+        // Holding the encoding stops the compile inside its diagnostics,
+        // after it took the edits.
+        let encoding = backend.position_encoding.write().await;
+        let compile = backend.compile(uri.clone(), "(f 10)\n(g 2)\n");
+        tokio::pin!(compile);
+        assert!(tokio::time::timeout(Duration::ZERO, &mut compile)
+            .await
+            .is_err());
+        drop(encoding);
+        change(2, range(1, 3, 1, 4), "20").await;
+        assert!(compile.await);
+
+        change(3, range(0, 1, 0, 2), "h").await;
+        let text = backend.rope_map.get(uri.as_str()).unwrap().to_string();
+        assert_eq!(text, "(h 10)\n(g 20)\n");
+        assert!(backend.compile(uri.clone(), &text).await);
+        let ast = backend.ast_map.get(uri.as_str()).unwrap().clone();
+        assert_eq!(ast, parse(&text).ast);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn incremental_edits_match_full_parses() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///edits.ore").unwrap();
+        open(&service, &uri, "(defun f (a) a)\n(defun g (b) (+ b 1))\n").await;
+
+        let edits = [
+            vec![(0, 13, 0, 14, "(* a a)"), (0, 10, 0, 11, "x")],
+            vec![(1, 14, 1, 14, "(")],
+            vec![(1, 14, 1, 15, "")],
+            vec![(0, 0, 0, 0, "; f\n")],
+        ];
+        for (version, changes) in edits.into_iter().enumerate() {
+            let content_changes = changes
+                .into_iter()
+                .map(|(line, character, end_line, end_character, text)| {
+                    TextDocumentContentChangeEvent {
+                        range: Some(range(line, character, end_line, end_character)),
+                        range_length: None,
+                        text: text.into(),
+                    }
+                })
+                .collect();
+            backend
+                .did_change(DidChangeTextDocumentParams {
+                    text_document: VersionedTextDocumentIdentifier::new(
+                        uri.clone(),
+                        version as i32 + 1,
+                    ),
+                    content_changes,
+                })
+                .await;
+
+            let text = backend.rope_map.get(uri.as_str()).unwrap().to_string();
+            let ast = backend.ast_map.get(uri.as_str()).unwrap().clone();
+            assert_eq!(ast, parse(&text).ast, "{}", text);
+            // Only an error-free parse can be edited incrementally.
+            let tracked = backend.edit_map.get(uri.as_str()).map(|entry| *entry);
+            let expected = parse(&text)
+                .parse_errors
+                .is_empty()
+                .then(|| (None, source_hash(&text)));
+            assert_eq!(tracked, expected);
+        }
+        let text = backend.rope_map.get(uri.as_str()).unwrap().to_string();
+        assert_eq!(text, "; f\n(defun f (x) (* a a))\n(defun g (b) (+ b 1))\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_delay_from_initialization_options() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend
+            .initialize(InitializeParams {
+                initialization_options: Some(serde_json::json!({ "debounceMs": 500 })),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(backend.config.read().await.debounce_ms, 500);
+
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        let start = tokio::time::Instant::now();
+        assert!(backend.schedule_compile(uri, "(fact 5)").await);
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn byte_order_mark_keeps_positions() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend.token_types_map.insert(SemanticTokenType::NUMBER, 0);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::FUNCTION, 1);
+        let uri = Url::parse("file:///bom.ore").unwrap();
+        let source = "\u{feff}(print 1)\n(+ 1 x)";
+        open(&service, &uri, source).await;
+
+        let result = backend
+            .semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await
+            .unwrap();
+        let Some(SemanticTokensResult::Tokens(tokens)) = result else {
+            panic!("expected tokens");
+        };
+        let data: Vec<_> = tokens
+            .data
+            .iter()
+            .map(|token| (token.delta_line, token.delta_start, token.length))
+            .collect();
+        assert_eq!(data, vec![(0, 2, 5), (0, 6, 1), (1, 3, 1)]);
+
+        let diagnostics = diagnostics(backend, &uri, source).await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "unbound variable 'x'");
+        assert_eq!(diagnostics[0].range, range(1, 5, 1, 6));
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_before_compile_finishes() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend.token_types_map.insert(SemanticTokenType::NUMBER, 0);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::KEYWORD, 1);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        let semantic_tokens = || async {
+            let result = backend
+                .semantic_tokens_full(SemanticTokensParams {
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    text_document: TextDocumentIdentifier::new(uri.clone()),
+                })
+                .await
+                .unwrap();
+            match result {
+                Some(SemanticTokensResult::Tokens(tokens)) => tokens.data,
+                _ => panic!("expected tokens"),
+            }
+        };
+
+        // The state `did_open` leaves while its compile is still running.
+        backend
+            .rope_map
+            .insert(uri.to_string(), Rope::from_str("(defun f () 1)"));
+        let data = semantic_tokens().await;
+        assert_eq!(data.len(), 2);
+        assert_eq!((data[0].delta_start, data[0].token_type), (1, 1));
+
+        open(&service, &uri, FACT).await;
+        assert!(!semantic_tokens().await.is_empty());
+
+        // Reopened with other text, the tokens of the closed one no longer
+        // apply, though the new version has the same number.
+        backend
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+        backend.version_map.insert(uri.to_string(), 0);
+        backend
+            .rope_map
+            .insert(uri.to_string(), Rope::from_str("1 2"));
+        let data = semantic_tokens().await;
+        assert_eq!(data.len(), 2);
+        assert_eq!((data[1].delta_start, data[1].token_type), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn parsed_reuses_the_compile_of_the_current_version() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///print.ore").unwrap();
+        open(&service, &uri, "(print 1)").await;
+        let rope = backend.rope_map.get(uri.as_str()).unwrap().clone();
+        let parsed = backend.parsed(uri.as_str(), &rope, DEFAULT_MAX_DEPTH);
+        assert!(matches!(parsed, Parsed::Compiled(..)));
+        assert_eq!(parsed.tokens().len(), 4);
+        drop(parsed);
+
+        // The state `did_change` leaves while its compile is pending: the
+        // text is parsed again rather than answered with the old tokens.
+        backend.version_map.insert(uri.to_string(), 1);
+        let rope = Rope::from_str("(print 12)");
+        let parsed = backend.parsed(uri.as_str(), &rope, DEFAULT_MAX_DEPTH);
+        assert!(matches!(parsed, Parsed::Fresh(_)));
+        assert_eq!(parsed.tokens()[2], (Token::Number("12".into()), 7..9));
+        assert_eq!(parsed.ast(), parse("(print 12)").ast);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_semantic_tokens_requests() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend.token_types_map.insert(SemanticTokenType::NUMBER, 0);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::VARIABLE, 1);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::KEYWORD, 2);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::OPERATOR, 3);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        open(&service, &uri, FACT).await;
+
+        let semantic_tokens_full = || {
+            backend.semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+        };
+        let (first, second) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(semantic_tokens_full(), semantic_tokens_full())
+        })
+        .await
+        .expect("semantic_tokens_full deadlocked");
+
+        let first = first.unwrap().unwrap();
+        assert_eq!(first, second.unwrap().unwrap());
+        let SemanticTokensResult::Tokens(tokens) = first else {
+            unreachable!()
+        };
+        assert_eq!(tokens.data.len(), 14);
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_with_crlf_line_endings() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::COMMENT, 0);
+        backend.token_types_map.insert(SemanticTokenType::NUMBER, 1);
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::OPERATOR, 2);
+        let uri = Url::parse("file:///crlf.ore").unwrap();
+        open(&service, &uri, "; hi\r\n(+ 1 ; one\r\n   2)\r\n").await;
+
+        let result = backend
+            .semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let SemanticTokensResult::Tokens(tokens) = result else {
+            unreachable!()
+        };
+        let data: Vec<_> = tokens
+            .data
+            .iter()
+            .map(|token| (token.delta_line, token.delta_start, token.length))
+            .collect();
+        assert_eq!(
+            data,
+            vec![(0, 0, 4), (1, 1, 1), (0, 2, 1), (0, 2, 5), (1, 3, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_limited_to_client_types() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let result = backend
+            .initialize(InitializeParams {
+                capabilities: serde_json::from_value(json!({
+                    "textDocument": { "semanticTokens": {
+                        "requests": { "full": true },
+                        "tokenTypes": ["number"],
+                        "tokenModifiers": [],
+                        "formats": ["relative"]
+                    } }
+                }))
+                .unwrap(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let Some(SemanticTokensServerCapabilities::SemanticTokensOptions(options)) =
+            result.capabilities.semantic_tokens_provider
+        else {
+            panic!("expected semantic token options");
+        };
+        assert_eq!(options.legend.token_types, vec![SemanticTokenType::NUMBER]);
+
+        let uri = Url::parse("file:///kinds.ore").unwrap();
+        open(
+            &service,
+            &uri,
+            "; kinds\n(defun f (x) (if true (+ x 1) \"s\"))\n(f 0x2)\n",
+        )
+        .await;
+        let result = backend
+            .semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let SemanticTokensResult::Tokens(tokens) = result else {
+            unreachable!()
+        };
+        let data: Vec<_> = tokens
+            .data
+            .iter()
+            .map(|token| {
+                (
+                    token.delta_line,
+                    token.delta_start,
+                    token.length,
+                    token.token_type,
+                )
+            })
+            .collect();
+        assert_eq!(data, vec![(1, 27, 1, 0), (1, 3, 3, 0)]);
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_skip_malformed_tokens() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend.token_types_map.insert(SemanticTokenType::NUMBER, 0);
+        let uri = Url::parse("file:///huge.ore").unwrap();
+        open(&service, &uri, "(f 1 2)").await;
+        backend.semantic_token_map.insert(
+            uri.to_string(),
+            [(3, 1), (4, usize::MAX), (5, 1)]
+                .into_iter()
+                .map(|(start, length)| ImCompleteSemanticToken {
+                    start,
+                    length,
+                    token_type: SemanticTokenType::NUMBER,
+                    paren_depth: None,
+                })
+                .collect(),
+        );
+
+        let result = backend
+            .semantic_tokens_full(SemanticTokensParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document: TextDocumentIdentifier::new(uri),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let SemanticTokensResult::Tokens(tokens) = result else {
+            unreachable!()
+        };
+        let data: Vec<_> = tokens
+            .data
+            .iter()
+            .map(|token| (token.delta_line, token.delta_start, token.length))
+            .collect();
+        assert_eq!(data, vec![(0, 3, 1), (0, 2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn document_color_for_rgb_forms() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///colors.ore").unwrap();
+        open(&service, &uri, "(fill\n  (rgb 255 0 0))\n").await;
+
+        let colors = backend
+            .document_color(DocumentColorParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        let red = Color {
+            red: 1.0,
+            green: 0.0,
+            blue: 0.0,
+            alpha: 1.0,
+        };
+        assert_eq!(
+            colors,
+            vec![ColorInformation {
+                range: range(1, 2, 1, 15),
+                color: red,
+            }]
+        );
+
+        let presentations = backend
+            .color_presentation(ColorPresentationParams {
+                text_document: TextDocumentIdentifier::new(uri),
+                color: red,
+                range: range(1, 2, 1, 15),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        let labels: Vec<_> = presentations
+            .iter()
+            .map(|presentation| presentation.label.as_str())
+            .collect();
+        assert_eq!(labels, vec!["(rgb 255 0 0)", "\"#ff0000\""]);
+    }
+
+    #[tokio::test]
+    async fn multi_line_strings_fold_and_highlight() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let params = json!({ "capabilities": { "textDocument": { "semanticTokens": {
+            "requests": { "full": true },
+            "tokenTypes": ["keyword", "variable", "string", "number"],
+            "tokenModifiers": [],
+            "formats": ["relative"],
+            "multilineTokenSupport": true
+        } } } });
+        backend
+            .initialize(serde_json::from_value(params).unwrap())
+            .await
+            .unwrap();
+        let uri = Url::parse("file:///strings.ore").unwrap();
+        open(&service, &uri, "(defun f ()\n  \"two\nlines\")\n(f 1)\n").await;
+
+        let folding_ranges = backend
+            .folding_range(FoldingRangeParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let folds: Vec<_> = folding_ranges
+            .iter()
+            .map(|fold| (fold.start_line, fold.end_line))
+            .collect();
+        assert_eq!(folds, vec![(0, 2), (1, 2)]);
+
+        let semantic_tokens = || async {
+            let result = backend
+                .semantic_tokens_full(SemanticTokensParams {
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    text_document: TextDocumentIdentifier::new(uri.clone()),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let SemanticTokensResult::Tokens(tokens) = result else {
+                unreachable!()
+            };
+            tokens
+                .data
+                .iter()
+                .map(|token| (token.delta_line, token.delta_start, token.length))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            semantic_tokens().await,
+            vec![(0, 1, 5), (0, 6, 1), (1, 2, 11), (2, 1, 1), (0, 2, 1)]
+        );
+
+        // Without multiline support, the string is a token on each line.
+        *backend.multiline_token_capable.write().await = false;
+        assert_eq!(
+            semantic_tokens().await,
+            vec![
+                (0, 1, 5),
+                (0, 6, 1),
+                (1, 2, 4),
+                (1, 0, 6),
+                (1, 1, 1),
+                (0, 2, 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_and_comment_only_documents() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::COMMENT, 0);
+        backend.config.write().await.enable_eval_diagnostics = true;
+
+        for (source, token_count) in [("", 0), ("  \n\t\n", 0), ("; only a comment", 1)] {
+            let uri = Url::parse("file:///empty.ore").unwrap();
+            open(&service, &uri, source).await;
+            assert!(backend.ast_map.get(uri.as_str()).unwrap().is_empty());
+            assert!(diagnostics(backend, &uri, source).await.is_empty());
+
+            let result = backend
+                .semantic_tokens_full(SemanticTokensParams {
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    text_document: TextDocumentIdentifier::new(uri),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let SemanticTokensResult::Tokens(tokens) = result else {
+                unreachable!()
+            };
+            assert_eq!(tokens.data.len(), token_count);
+        }
+    }
+
+    #[tokio::test]
+    async fn rainbow_paren_semantic_tokens() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        backend
+            .token_types_map
+            .insert(SemanticTokenType::OPERATOR, 0);
+        let uri = Url::parse("file:///parens.ore").unwrap();
+        open(&service, &uri, "((()))").await;
+
+        let semantic_tokens_full = || async {
+            let result = backend
+                .semantic_tokens_full(SemanticTokensParams {
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    text_document: TextDocumentIdentifier::new(uri.clone()),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let SemanticTokensResult::Tokens(tokens) = result else {
+                unreachable!()
+            };
+            tokens.data
+        };
+
+        assert!(semantic_tokens_full().await.is_empty());
+
+        backend.config.write().await.rainbow_parens = true;
+        let modifiers: Vec<_> = semantic_tokens_full()
+            .await
+            .iter()
+            .map(|token| token.token_modifiers_bitset)
+            .collect();
+        assert_eq!(modifiers, vec![1, 2, 4, 4, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn foreign_language_documents_are_ignored() {
+        let (service, mut receiver) = start(json!(null)).await;
+        let backend = service.inner();
+        let open_as = |uri: &str, language_id: &str| {
+            let uri = Url::parse(uri).unwrap();
+            let language_id = language_id.to_string();
+            async move {
+                backend
+                    .did_open(DidOpenTextDocumentParams {
+                        text_document: TextDocumentItem::new(
+                            uri.clone(),
+                            language_id,
+                            0,
+                            "(+ 1".into(),
+                        ),
+                    })
+                    .await;
+                uri
+            }
+        };
+
+        let uri = open_as("file:///script.py", "python").await;
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+        assert!(!backend.semantic_token_map.contains_key(uri.as_str()));
+        assert!(!backend.rope_map.contains_key(uri.as_str()));
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 1),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "(+ 1 2".into(),
+                }],
+            })
+            .await;
+        backend
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+        assert!(!backend.rope_map.contains_key(uri.as_str()));
+
+        // An `.ore` file is orelang whatever the client calls it.
+        let uri = open_as("file:///broken.ore", "plaintext").await;
+        let diagnostics = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(diagnostics["uri"], uri.as_str());
+    }
+
+    #[tokio::test]
+    async fn did_save_logs_eval_result() {
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        let (service, mut receiver) = start(json!({ "evalOnSave": true })).await;
+
+        let save = |source: String| {
+            let service = &service;
+            let uri = uri.clone();
+            async move {
+                open(service, &uri, &source).await;
+                service
+                    .inner()
+                    .did_save(DidSaveTextDocumentParams {
+                        text_document: TextDocumentIdentifier::new(uri),
+                        text: None,
+                    })
+                    .await;
+            }
+        };
+        let mut logs = vec![];
+        save(format!("{}(print (fact 5))\n", FACT)).await;
+        save("(+ 1 x)".into()).await;
+        while let Some(params) = next_message(&mut receiver, "window/logMessage").await {
+            logs.push(params["message"].as_str().unwrap().to_string());
+        }
+        assert!(logs.contains(&format!("{}: 120", uri)));
+        assert!(logs.contains(&format!("{}: unbound variable 'x' at 1:6", uri)));
+    }
+
+    #[tokio::test]
+    async fn initialization_options_disable_diagnostics() {
+        let uri = Url::parse("file:///broken.ore").unwrap();
+
+        let (service, mut receiver) = start(json!({ "enableDiagnostics": false })).await;
+        open(&service, &uri, "(+ 1").await;
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+
+        let (service, mut receiver) = start(json!({ "enableDiagnostics": "malformed" })).await;
+        open(&service, &uri, "(+ 1").await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["diagnostics"][0]["message"], "unclosed '('");
+    }
+
+    #[tokio::test]
+    async fn max_depth_from_initialization_options() {
+        let uri = Url::parse("file:///deep.ore").unwrap();
+        let (service, mut receiver) = start(json!({ "maxDepth": 3 })).await;
+        open(&service, &uri, "(f (g (h (i (j 1)))))").await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(
+            params["diagnostics"][0],
+            json!({
+                "range": range(0, 9, 0, 10),
+                "severity": 1,
+                "message": "nesting exceeds the maximum depth of 3",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn verbose_logs_parse_metrics() {
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        let parse_logs = |verbose: bool| {
+            let uri = uri.clone();
+            async move {
+                let (service, mut receiver) = start(json!({ "verbose": verbose })).await;
+                open(&service, &uri, FACT).await;
+                let mut logs = vec![];
+                while let Some(params) = next_message(&mut receiver, "window/logMessage").await {
+                    if params["type"] == json!(MessageType::LOG) {
+                        logs.push(params["message"].as_str().unwrap().to_string());
+                    }
+                }
+                logs
+            }
+        };
+
+        let logs = parse_logs(true).await;
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].starts_with(&format!("parsed {} in ", uri)));
+        // Every paren, name and number the lexer produced.
+        assert!(logs[0].ends_with(": 28 tokens, 0 errors"));
+        assert!(parse_logs(false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn diagnostic_severities_from_initialization_options() {
+        let uri = Url::parse("file:///severities.ore").unwrap();
+        let (service, mut receiver) = start(json!({
+            "diagnosticSeverities": {
+                "unbound-variable": "warning",
+                "arity-mismatch": "hint",
+                "duplicate-defun": "off",
+            }
+        }))
+        .await;
+        open(&service, &uri, "(defun f (x) x)\n(defun f (x) x)\n(f y 2)").await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        let found: Vec<_> = params["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| (d["severity"].clone(), d["message"].clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (json!(2), json!("unbound variable 'y'")),
+                (json!(4), json!("'f' expects 1 arguments, got 2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn did_change_configuration_disables_diagnostics() {
+        let uri = Url::parse("file:///broken.ore").unwrap();
+        let (service, mut receiver) = start(json!({})).await;
+        let registration = next_message(&mut receiver, "client/registerCapability")
+            .await
+            .unwrap();
+        assert_eq!(
+            registration["registrations"][0]["method"],
+            "workspace/didChangeConfiguration"
+        );
+
+        open(&service, &uri, "(+ 1").await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["diagnostics"].as_array().unwrap().len(), 1);
+
+        service
+            .inner()
+            .did_change_configuration(DidChangeConfigurationParams {
+                settings: json!({ "enableDiagnostics": false }),
+            })
+            .await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["diagnostics"], json!([]));
+        assert!(!service.inner().config.read().await.enable_diagnostics);
+
+        open(&service, &uri, "(+ 1 (").await;
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn config_changes_refresh_semantic_tokens() {
+        let capabilities = json!({ "workspace": { "semanticTokens": { "refreshSupport": true } } });
+        let (service, mut receiver) = start_with_capabilities(capabilities, json!({})).await;
+        let change = |settings| {
+            service
+                .inner()
+                .did_change_configuration(DidChangeConfigurationParams { settings })
+        };
+
+        change(json!({ "indentWidth": 4 })).await;
+        assert_eq!(
+            next_message(&mut receiver, "workspace/semanticTokens/refresh").await,
+            None
+        );
+        change(json!({ "rainbowParens": true })).await;
+        assert!(
+            next_message(&mut receiver, "workspace/semanticTokens/refresh")
+                .await
+                .is_some()
+        );
+
+        // Without refresh support the client is never asked.
+        let (service, mut receiver) = start(json!({})).await;
+        service
+            .inner()
+            .did_change_configuration(DidChangeConfigurationParams {
+                settings: json!({ "rainbowParens": true }),
+            })
+            .await;
+        assert_eq!(
+            next_message(&mut receiver, "workspace/semanticTokens/refresh").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnostics_carry_document_version() {
+        let uri = Url::parse("file:///versioned.ore").unwrap();
+        let (service, mut receiver) = start(json!({ "debounceMs": 0 })).await;
+        open(&service, &uri, "(+ 1").await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["version"], 0);
+
+        service
+            .inner()
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 7),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "(+ 1 2".into(),
+                }],
+            })
+            .await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["version"], 7);
+        assert_eq!(params["diagnostics"][0]["message"], "unclosed '('");
+
+        // Edits undone before their compile ran leave the compiled text,
+        // which is republished for the new version.
+        let backend = service.inner();
+        backend.version_map.insert(uri.to_string(), 9);
+        assert!(!backend.compile(uri.clone(), "(+ 1 2").await);
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["version"], 9);
+        assert_eq!(params["diagnostics"][0]["message"], "unclosed '('");
+        let compiled = backend.token_map.get(uri.as_str()).unwrap();
+        assert_eq!(compiled.version, Some(9));
+        drop(compiled);
+        assert!(!backend.compile(uri.clone(), "(+ 1 2").await);
+        assert_eq!(
+            next_message(&mut receiver, "textDocument/publishDiagnostics").await,
+            None
+        );
+
+        service
+            .inner()
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+        let params = next_message(&mut receiver, "textDocument/publishDiagnostics")
+            .await
+            .unwrap();
+        assert_eq!(params["version"], 9);
+        assert_eq!(params["diagnostics"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn code_action_balances_parens() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///unbalanced.ore").unwrap();
+
+        let actions = code_actions(&service, &uri, "(+ 1 2", range(0, 0, 0, 1)).await;
+        assert_eq!(actions.len(), 1);
+        let (title, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(title, "Add missing ')'");
+        let mut fixed = Rope::from_str("(+ 1 2");
+        let offset =
+            position_to_offset(&fixed, text_edit.range.start, PositionEncoding::Utf16).unwrap();
+        fixed.insert(offset, &text_edit.new_text);
+        assert_eq!(fixed.to_string(), "(+ 1 2)");
+
+        let actions = code_actions(&service, &uri, "(+ 1 2))", range(0, 7, 0, 7)).await;
+        let (title, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(title, "Remove extra ')'");
+        assert_eq!(text_edit.range, range(0, 7, 0, 8));
+
+        assert!(code_actions(&service, &uri, "(f x)", range(0, 0, 0, 5))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn code_action_converts_number_radix() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///radix.ore").unwrap();
+
+        let actions = code_actions(&service, &uri, "(+ 255 1.5)", range(0, 4, 0, 4)).await;
+        assert_eq!(actions.len(), 1);
+        let (title, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(title, "Convert 255 to 0xff");
+        assert_eq!(text_edit.range, range(0, 3, 0, 6));
+        assert_eq!(text_edit.new_text, "0xff");
+
+        let actions = code_actions(&service, &uri, "(+ 0xff 1)", range(0, 3, 0, 7)).await;
+        assert_eq!(actions.len(), 1);
+        let (title, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(title, "Convert 0xff to 255");
+        assert_eq!(text_edit.new_text, "255");
+
+        assert!(
+            code_actions(&service, &uri, "(+ 255 1.5)", range(0, 8, 0, 8))
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn code_action_extracts_let_binding() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///extract.ore").unwrap();
+
+        let actions = code_actions(&service, &uri, FACT, range(3, 17, 3, 24)).await;
+        let (title, text_edit) = actions
+            .iter()
+            .map(|action| code_action_edit(&uri, action))
+            .find(|(title, _)| title.starts_with("Extract"))
+            .unwrap();
+        assert_eq!(title, "Extract into let binding 'tmp'");
+        assert_eq!(text_edit.range, range(3, 11, 3, 25));
+        assert_eq!(text_edit.new_text, "(let ((tmp (- n 1))) (fact tmp))");
+
+        let source = "(defun f (tmp) (g (h tmp)))";
+        let actions = code_actions(&service, &uri, source, range(0, 18, 0, 25)).await;
+        let (_, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(text_edit.new_text, "(let ((tmp1 (h tmp))) (g tmp1))");
+    }
+
+    #[tokio::test]
+    async fn code_action_inserts_starter_template() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///empty.ore").unwrap();
+
+        let actions = code_actions(&service, &uri, "", range(0, 0, 0, 0)).await;
+        assert_eq!(actions.len(), 1);
+        let (title, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(title, "Insert starter template");
+        assert_eq!(text_edit.range, range(0, 0, 0, 0));
+        assert!(parse(&text_edit.new_text).parse_errors.is_empty());
+        let CodeActionOrCommand::CodeAction(action) = &actions[0] else {
+            unreachable!()
+        };
+        assert_eq!(action.kind, Some(CodeActionKind::EMPTY));
+
+        let actions = code_actions(&service, &uri, "\n  \n", range(1, 0, 1, 0)).await;
+        let (_, text_edit) = code_action_edit(&uri, &actions[0]);
+        assert_eq!(text_edit.range, range(0, 0, 2, 0));
+
+        assert!(code_actions(&service, &uri, "(print 1)", range(0, 0, 0, 0))
+            .await
+            .iter()
+            .all(|action| code_action_edit(&uri, action).0 != "Insert starter template"));
+    }
+
+    #[tokio::test]
+    async fn range_formatting_while_a_compile_is_pending() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///pending.ore").unwrap();
+        open(&service, &uri, "(+  1 2)\n").await;
+
+        // The state `did_change` leaves until its debounced compile runs.
+        backend.version_map.insert(uri.to_string(), 1);
+        backend
+            .rope_map
+            .insert(uri.to_string(), Rope::from_str("; sum\n(+  1 2)\n"));
+        let edits = backend
+            .range_formatting(DocumentRangeFormattingParams {
+                text_document: TextDocumentIdentifier::new(uri),
+                range: range(1, 0, 1, 1),
+                options: Default::default(),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            edits,
+            vec![TextEdit::new(range(1, 0, 1, 8), "(+ 1 2)".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn range_formatting_counts_utf16_code_units() {
+        let uri = Url::parse("file:///emoji.ore").unwrap();
+        let source = "(print \"😀\")  (+  1 2) ; ✓ 😀\n(print  1)\n";
+        let range_formatting = |service: LspService<Backend>| {
+            let uri = uri.clone();
+            async move {
+                open(&service, &uri, source).await;
+                service
+                    .inner()
+                    .range_formatting(DocumentRangeFormattingParams {
+                        text_document: TextDocumentIdentifier::new(uri),
+                        range: range(0, 15, 0, 16),
+                        options: Default::default(),
+                        work_done_progress_params: Default::default(),
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+
+        // The emoji is two UTF-16 code units, so `(+  1 2)` starts at 14.
+        let (service, _) = LspService::new(Backend::new);
+        assert_eq!(
+            range_formatting(service).await,
+            vec![TextEdit::new(range(0, 14, 0, 22), "(+ 1 2)".into())]
+        );
+
+        let (service, _) = start_with_capabilities(
+            json!({ "general": { "positionEncodings": ["utf-32", "utf-16"] } }),
+            json!(null),
+        )
+        .await;
+        assert_eq!(
+            range_formatting(service).await,
+            vec![TextEdit::new(range(0, 13, 0, 21), "(+ 1 2)".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn formatting_trims_without_reflow() {
+        let (service, _) = LspService::new(Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///fact.ore").unwrap();
+        let source = "(defun f (x)  \n      (print x))\t\n\n";
+        open(&service, &uri, source).await;
+        let formatting = || {
+            backend.formatting(DocumentFormattingParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                options: Default::default(),
+                work_done_progress_params: Default::default(),
+            })
+        };
+
+        let edits = formatting().await.unwrap().unwrap();
+        assert_eq!(
+            edits,
+            vec![TextEdit::new(
+                range(0, 0, 3, 0),
+                "(defun f (x)\n  (print x))\n".into()
+            )]
+        );
+
+        backend.config.write().await.reflow = false;
+        let edits = formatting().await.unwrap().unwrap();
+        assert_eq!(
+            edits,
+            vec![
+                TextEdit::new(range(0, 12, 0, 14), "".into()),
+                TextEdit::new(range(1, 16, 3, 0), "\n".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn on_type_formatting_indents_new_line() {
+        let (service, _) = LspService::new(Backend::new);
+        let uri = Url::parse("file:///fact.ore").unwrap();
+
+        let on_type_formatting = |line, character| {
+            service
+                .inner()
+                .on_type_formatting(DocumentOnTypeFormattingParams {
+                    text_document_position: position_params(&uri, line, character),
+                    ch: "\n".into(),
+                    options: Default::default(),
+                })
+        };
+
+        open(&service, &uri, "(defun fact (n)\n").await;
+        let edits = on_type_formatting(1, 0).await.unwrap().unwrap();
+        assert_eq!(
+            edits,
+            vec![TextEdit {
+                range: range(1, 0, 1, 0),
+                new_text: "  ".into(),
+            }]
+        );
+
+        service.inner().config.write().await.indent_width = 4;
+        open(&service, &uri, "(defun fact (n)\n  (if (= n 0)\n    ").await;
+        let edits = on_type_formatting(2, 4).await.unwrap().unwrap();
+        assert_eq!(edits[0].range, range(2, 0, 2, 4));
+        assert_eq!(edits[0].new_text, " ".repeat(8));
+    }
 }
@@ -1,97 +1,1142 @@
 use chumsky::prelude::*;
-use chumsky::Parser;
-use tower_lsp::lsp_types::SemanticTokenType;
+use chumsky::{Parser, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use tower_lsp::lsp_types::{SemanticTokenModifier, SemanticTokenType};
 
 pub type Span = std::ops::Range<usize>;
 
 // kind
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     LParen,
     RParen,
+    /// The `'` before a quoted expression.
+    Quote,
     Comment,
     Number(String),
+    /// An exact fraction like `3/4`.
+    Ratio(String),
+    Str(String),
     Ident(String),
 }
 
+/// The byte order mark some editors save UTF-8 files with.
+pub const BOM: char = '\u{feff}';
+
 pub fn lexer() -> impl Parser<char, Vec<(Token, Span)>, Error = Simple<char>> {
+    // A leading byte order mark is skipped without a token, so spans still
+    // count it and stay offsets into the document.
+    let bom = just(BOM).or_not();
+
+    // A `#!` line is only special at the very start of a script, where it
+    // reads as a comment.
+    let shebang = just("#!")
+        .then(filter(|c: &char| *c != '\n' && *c != '\r').repeated())
+        .map_with_span(|_, span| (Token::Comment, span));
+
+    bom.ignore_then(shebang.or_not())
+        .chain(token().recover_with(skip_then_retry_until([])).repeated())
+        .then_ignore(text::whitespace())
+        .then_ignore(
+            // Skipping junk at the end keeps the tokens before it. Offering a
+            // token here too makes the error list what could start one.
+            end()
+                .or(token().ignored())
+                .recover_with(skip_then_retry_until([])),
+        )
+}
+
+/// Lexes a single token along with the whitespace around it.
+fn token() -> impl Parser<char, (Token, Span), Error = Simple<char>> {
     let lparen = just("(").map(|_| Token::LParen);
     let rparen = just(")").map(|_| Token::RParen);
+    let quote = just("'").map(|_| Token::Quote);
 
+    // A comment stops before the line break, so neither `\n` nor the `\r` of
+    // a CRLF ending counts towards its length.
     let comment = just(";")
-        .then(take_until(text::newline().or(end())))
-        .padded()
+        .then(filter(|c: &char| *c != '\n' && *c != '\r').repeated())
         .map(|_| Token::Comment);
 
-    let number = text::int(10)
-        .chain::<char, _, _>(just('.').chain(text::digits(10)).or_not().flatten())
+    // An optional exponent like `e-3` follows the digits.
+    let exponent = one_of("eE")
+        .chain(one_of("+-").or_not())
+        .chain::<char, _, _>(text::digits(10))
         .collect::<String>()
-        .map(Token::Number);
+        .or_not()
+        .map(Option::unwrap_or_default);
+
+    // A `-` right before a digit makes a negative literal; anywhere else it
+    // is the subtraction builtin.
+    let sign = just('-')
+        .then_ignore(filter(char::is_ascii_digit).rewind())
+        .or_not()
+        .map(|sign| sign.map(String::from).unwrap_or_default());
 
-    let ident = text::ident()
-        .or(one_of("+-*/=").map(|c: char| c.to_string()))
+    // A `.` must be followed by digits; `12.` is kept as a number token but
+    // reported as malformed. A `0x` prefix is left to `hex`, even when no
+    // hex digit follows it.
+    let number = just("0x")
+        .not()
+        .rewind()
+        .ignore_then(sign)
+        .then(text::int(10))
+        .then(just('.').ignore_then(text::digits(10).or_not()).or_not())
+        .then(exponent)
+        .validate(
+            |(((sign, int), fraction), exponent), span: Span, emit| match fraction {
+                None => Token::Number(format!("{}{}{}", sign, int, exponent)),
+                Some(Some(digits)) => {
+                    Token::Number(format!("{}{}.{}{}", sign, int, digits, exponent))
+                }
+                Some(None) => {
+                    emit(Simple::custom(
+                        span,
+                        format!(
+                            "malformed number '{}{}.': expected digits after '.'",
+                            sign, int
+                        ),
+                    ));
+                    Token::Number(format!("{}{}.", sign, int))
+                }
+            },
+        );
+
+    // A `/` between two integers, without spaces, makes a ratio rather than
+    // a division, which is always written as a call like `(/ 3 4)`.
+    let ratio = sign
+        .then(text::int(10))
+        .then_ignore(just('/'))
+        .then(text::digits(10))
+        .map(|((sign, numerator), denominator)| {
+            Token::Ratio(format!("{}{}/{}", sign, numerator, denominator))
+        });
+
+    // `0x` with no hex digit after it is still taken as a (malformed) hex
+    // number rather than a `0` followed by an identifier.
+    let hex = just("0x").ignore_then(text::digits(16).or_not()).validate(
+        |digits: Option<String>, span: Span, emit| {
+            if digits.is_none() {
+                emit(Simple::custom(
+                    span,
+                    "malformed number '0x': expected hex digits after '0x'",
+                ));
+            }
+            Token::Number(format!("0x{}", digits.unwrap_or_default()))
+        },
+    );
+
+    let escape = just('\\').ignore_then(
+        just('\\')
+            .or(just('"'))
+            .or(just('n').to('\n'))
+            .or(just('t').to('\t')),
+    );
+
+    // An unterminated string still yields a token so it can be highlighted,
+    // but the missing quote is reported at the opening one.
+    let string = just('"')
+        .ignore_then(
+            filter(|c: &char| *c != '"' && *c != '\\')
+                .or(escape)
+                .repeated()
+                .collect::<String>(),
+        )
+        .then(just('"').or_not())
+        .validate(|(string, close), span: Span, emit| {
+            if close.is_none() {
+                emit(Simple::custom(
+                    span.start..span.start + 1,
+                    "unterminated string literal",
+                ));
+            }
+            Token::Str(string)
+        });
+
+    // Word identifiers can't start like any other token, so trying them
+    // early saves the most common tokens from failing every other
+    // alternative first. An operator may start a negative number instead.
+    // `let*` is the one identifier with a `*`, which would otherwise start
+    // a token of its own.
+    let ident = just("let*")
+        .map(String::from)
+        .or(text::ident())
+        .map(Token::Ident);
+    // The longest operator wins, so `<=` is one token and `< =` two.
+    let operator = choice((just("<="), just(">="), just("!="), just("==")))
+        .map(String::from)
+        .or(one_of("+-*/=<>").map(|c: char| c.to_string()))
         .map(Token::Ident);
 
-    let token = lparen.or(rparen).or(comment).or(number).or(ident);
+    let token = lparen
+        .or(rparen)
+        .or(ident)
+        .or(quote)
+        .or(comment)
+        .or(hex)
+        .or(ratio)
+        .or(number)
+        .or(string)
+        .or(operator);
 
-    token
-        .map_with_span(|tok, span| (tok, span))
-        .padded()
-        .repeated()
+    token.map_with_span(|tok, span| (tok, span)).padded()
 }
 
-#[derive(Debug)]
+/// Lazily lexes `source` one token at a time with the same rules as
+/// `lexer()`, skipping chars no token can start with the same way. Errors
+/// like malformed numbers are not reported.
+pub fn tokenize(source: &str) -> Tokens<'_> {
+    Tokens {
+        rest: source,
+        offset: 0,
+        token: token()
+            .recover_with(skip_then_retry_until([]))
+            .map_with_span(|token, span: Span| (token, span.end))
+            .boxed(),
+    }
+}
+
+/// How many chars a token is first lexed from. The window doubles until
+/// the token ends clear of its edge, so lexing never reads to the end of the
+/// source for one token.
+const TOKEN_WINDOW: usize = 64;
+
+/// How far past its end lexing a token may look, like `1e+` before finding
+/// no exponent digits, so a token ending closer to a window's edge might
+/// continue past it.
+const TOKEN_LOOKAHEAD: usize = 4;
+
+pub struct Tokens<'a> {
+    rest: &'a str,
+    /// Char offset of `rest` in the source.
+    offset: usize,
+    token: BoxedParser<'static, char, ((Token, Span), usize), Simple<char>>,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset == 0 {
+            if let Some(rest) = self.rest.strip_prefix(BOM) {
+                self.rest = rest;
+                self.offset = 1;
+            }
+            if self.rest.starts_with("#!") {
+                let start = self.offset;
+                let line = self.rest.split(['\n', '\r']).next().unwrap_or_default();
+                self.rest = &self.rest[line.len()..];
+                self.offset += line.chars().count();
+                return Some((Token::Comment, start..self.offset));
+            }
+        }
+        let mut window = TOKEN_WINDOW;
+        loop {
+            let (text, whole) = match self.rest.char_indices().nth(window) {
+                Some((bytes, _)) => (&self.rest[..bytes], false),
+                None => (self.rest, true),
+            };
+            let offset = self.offset;
+            let edge = offset + text.chars().count();
+            let chars = text
+                .chars()
+                .enumerate()
+                .map(move |(i, c)| (c, offset + i..offset + i + 1));
+            match self
+                .token
+                .parse_recovery(Stream::from_iter(edge..edge, chars))
+                .0
+            {
+                Some((token, end)) if whole || end + TOKEN_LOOKAHEAD < edge => {
+                    let bytes = self
+                        .rest
+                        .char_indices()
+                        .nth(end - offset)
+                        .map_or(self.rest.len(), |(bytes, _)| bytes);
+                    self.rest = &self.rest[bytes..];
+                    self.offset = end;
+                    return Some(token);
+                }
+                None if whole => return None,
+                _ => window *= 2,
+            }
+        }
+    }
+}
+
+/// Returns the value of a number literal, decimal or `0x` hex.
+pub fn number_value(literal: &str) -> Option<f64> {
+    match literal.strip_prefix("0x") {
+        Some(digits) => u64::from_str_radix(digits, 16).ok().map(|n| n as f64),
+        None => literal.parse().ok(),
+    }
+}
+
+/// Classifies an identifier for highlighting: special forms and booleans are
+/// keywords and the arithmetic and comparison builtins are operators.
+fn ident_token_type(name: &str) -> SemanticTokenType {
+    match name {
+        "defun" | "if" | "cond" | "else" | "let" | "let*" | "true" | "false" => {
+            SemanticTokenType::KEYWORD
+        }
+        "+" | "-" | "*" | "/" | "=" | "<" | ">" | "<=" | ">=" | "!=" | "==" => {
+            SemanticTokenType::OPERATOR
+        }
+        _ => SemanticTokenType::VARIABLE,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ImCompleteSemanticToken {
     pub start: usize,
     pub length: usize,
     pub token_type: SemanticTokenType,
+    /// Nesting depth of a paren, counted from 0 at the top level.
+    pub paren_depth: Option<usize>,
+}
+
+/// Semantic token modifiers for paren depth, repeating past the last one.
+pub const PAREN_DEPTH_MODIFIERS: [&str; 6] =
+    ["depth0", "depth1", "depth2", "depth3", "depth4", "depth5"];
+
+/// A highlighted span of the source, in chars, with its type and modifiers.
+pub type Highlight = (Span, SemanticTokenType, Vec<SemanticTokenModifier>);
+
+/// Highlights `source` like the semantic tokens the server sends, but with
+/// absolute spans rather than delta encoded positions, for tools outside an
+/// editor. Each paren has the modifier of its nesting depth.
+pub fn highlight(source: &str) -> Vec<Highlight> {
+    highlights(&parse(source).semantic_tokens)
+}
+
+/// The highlights of already parsed `tokens`. See `highlight`.
+pub fn highlights(tokens: &[ImCompleteSemanticToken]) -> Vec<Highlight> {
+    tokens
+        .iter()
+        .map(|token| {
+            let modifiers = token
+                .paren_depth
+                .map(|depth| {
+                    let modifier = PAREN_DEPTH_MODIFIERS[depth % PAREN_DEPTH_MODIFIERS.len()];
+                    SemanticTokenModifier::new(modifier)
+                })
+                .into_iter()
+                .collect();
+            (
+                token.start..token.start.saturating_add(token.length),
+                token.token_type.clone(),
+                modifiers,
+            )
+        })
+        .collect()
 }
 
 #[derive(Debug)]
 pub struct ParseResult {
+    /// How many chars the parsed source has.
+    pub len: usize,
+    /// Every token the lexer recovered, in source order.
+    pub tokens: Vec<(Token, Span)>,
     pub semantic_tokens: Vec<ImCompleteSemanticToken>,
     pub parse_errors: Vec<Simple<String>>,
+    pub ast: Vec<Spanned<Expr>>,
 }
 
+/// How deeply lists may nest in the AST unless configured otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 pub fn parse(source: &str) -> ParseResult {
+    parse_with_max_depth(source, DEFAULT_MAX_DEPTH)
+}
+
+/// Parses `source`, leaving lists nested deeper than `max_depth` out of the
+/// AST. See `parse_ast_with_max_depth`.
+pub fn parse_with_max_depth(source: &str, max_depth: usize) -> ParseResult {
+    // The lexer skips chars no token starts with, so the tokens around them
+    // are always recovered.
     let (tokens, errs) = lexer().parse_recovery(source);
+    let tokens = tokens.unwrap_or_default();
 
-    let semantic_tokens = if let Some(tokens) = tokens {
-        tokens
-            .iter()
-            .filter_map(|(token, span)| match token {
-                Token::LParen => None,
-                Token::RParen => None,
-                Token::Comment => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: SemanticTokenType::COMMENT,
-                }),
-                Token::Number(_) => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: SemanticTokenType::NUMBER,
-                }),
-                Token::Ident(_) => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: SemanticTokenType::VARIABLE,
-                }),
-            })
-            .collect()
-    } else {
-        vec![]
-    };
+    let (ast, ast_errs) = parse_ast_with_max_depth(&tokens, max_depth);
 
     let parse_errors = errs
         .into_iter()
         .map(|e| e.map(|c| c.to_string()))
+        .chain(ast_errs)
         .collect::<Vec<_>>();
 
+    // The AST only tells identifiers apart reliably when it covers the whole
+    // source; otherwise the tokens are highlighted on their own.
+    let semantic_tokens = if parse_errors.is_empty() {
+        let mut semantic_tokens = vec![];
+        for expr in &ast {
+            semantic_tokens_from_ast(expr, &mut semantic_tokens);
+        }
+        semantic_tokens.extend(
+            flat_semantic_tokens(&tokens)
+                .into_iter()
+                .filter(|token| token.token_type == SemanticTokenType::COMMENT),
+        );
+        semantic_tokens.sort_by_key(|token| token.start);
+        semantic_tokens
+    } else {
+        flat_semantic_tokens(&tokens)
+    };
+
     ParseResult {
+        len: source.chars().count(),
+        tokens,
         semantic_tokens,
         parse_errors,
+        ast,
+    }
+}
+
+/// A change to a source in char offsets: `start..old_end` of the old text
+/// became `start..new_end` of the new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl Edit {
+    /// How many chars longer the new text is than the old one.
+    fn delta(self) -> isize {
+        self.new_end as isize - self.old_end as isize
+    }
+
+    /// The single edit making `self` and then `next`, whose offsets are in
+    /// the text after `self`.
+    pub fn then(self, next: Edit) -> Edit {
+        let end = if self.new_end <= next.start {
+            self.new_end
+        } else if self.new_end >= next.old_end {
+            shift(self.new_end, next.delta())
+        } else {
+            next.new_end
+        };
+        let new_end = end.max(next.new_end);
+        Edit {
+            start: self.start.min(next.start),
+            old_end: shift(new_end, -(self.delta() + next.delta())),
+            new_end,
+        }
+    }
+}
+
+fn shift(offset: usize, delta: isize) -> usize {
+    (offset as isize + delta) as usize
+}
+
+/// Moves `expr` and everything inside it `delta` chars along.
+fn shift_spans(expr: &mut Spanned<Expr>, delta: isize) {
+    let (expr, span) = expr;
+    *span = shift(span.start, delta)..shift(span.end, delta);
+    match expr {
+        Expr::List(items) => {
+            for item in items {
+                shift_spans(item, delta);
+            }
+        }
+        Expr::Quote(quoted) => shift_spans(quoted, delta),
+        _ => {}
+    }
+}
+
+/// Parses `source`, the text `previous` was parsed from after `edit`. When
+/// the edit falls strictly inside one top-level list and `previous` had no
+/// errors, only that list is parsed again: its tokens and subtree are
+/// spliced into `previous` and everything after it moves by the change in
+/// length. Anything else, like an edit across lists, one that leaves the
+/// list unbalanced or one that doesn't account for the change in length
+/// from `previous` to `source`, parses `source` in full.
+pub fn reparse(previous: ParseResult, source: &str, edit: Edit, max_depth: usize) -> ParseResult {
+    let source_len = source.chars().count();
+    let ParseResult {
+        len: previous_len,
+        mut tokens,
+        mut semantic_tokens,
+        parse_errors,
+        mut ast,
+    } = previous;
+    let index = ast.iter().position(|(expr, span)| {
+        matches!(expr, Expr::List(_)) && span.start < edit.start && edit.old_end < span.end
+    });
+    let consistent = shift(previous_len, edit.delta()) == source_len;
+    let (Some(index), true, true) = (index, parse_errors.is_empty(), consistent) else {
+        return parse_with_max_depth(source, max_depth);
+    };
+
+    let old_span = ast[index].1.clone();
+    let delta = edit.delta();
+    let len = shift(old_span.end, delta) - old_span.start;
+    let text: String = source.chars().skip(old_span.start).take(len).collect();
+    let mut form = parse_with_max_depth(&text, max_depth);
+    let reparsed = match form.ast.as_slice() {
+        [(Expr::List(_), span)] => *span == (0..len) && form.parse_errors.is_empty(),
+        _ => false,
+    };
+    if !reparsed || text.chars().count() != len {
+        return parse_with_max_depth(source, max_depth);
+    }
+
+    let mut expr = form.ast.remove(0);
+    shift_spans(&mut expr, old_span.start as isize);
+    ast[index] = expr;
+    for sibling in &mut ast[index + 1..] {
+        shift_spans(sibling, delta);
+    }
+
+    let first = semantic_tokens.partition_point(|token| token.start < old_span.start);
+    let last = semantic_tokens.partition_point(|token| token.start < old_span.end);
+    for token in &mut semantic_tokens[last..] {
+        token.start = shift(token.start, delta);
+    }
+    for token in &mut form.semantic_tokens {
+        token.start += old_span.start;
+    }
+    semantic_tokens.splice(first..last, form.semantic_tokens);
+
+    let first = tokens.partition_point(|(_, span)| span.start < old_span.start);
+    let last = tokens.partition_point(|(_, span)| span.start < old_span.end);
+    for (_, span) in &mut tokens[last..] {
+        *span = shift(span.start, delta)..shift(span.end, delta);
+    }
+    for (_, span) in &mut form.tokens {
+        *span = span.start + old_span.start..span.end + old_span.start;
+    }
+    tokens.splice(first..last, form.tokens);
+
+    ParseResult {
+        len: source_len,
+        tokens,
+        semantic_tokens,
+        parse_errors,
+        ast,
+    }
+}
+
+/// Highlights each token by its kind alone, without the context of the tree.
+fn flat_semantic_tokens(tokens: &[(Token, Span)]) -> Vec<ImCompleteSemanticToken> {
+    let mut depth: usize = 0;
+    tokens
+        .iter()
+        .map(|(token, span)| {
+            let (token_type, paren_depth) = match token {
+                Token::LParen => {
+                    depth += 1;
+                    (SemanticTokenType::OPERATOR, Some(depth - 1))
+                }
+                Token::RParen => {
+                    depth = depth.saturating_sub(1);
+                    (SemanticTokenType::OPERATOR, Some(depth))
+                }
+                Token::Quote => (SemanticTokenType::OPERATOR, None),
+                Token::Comment => (SemanticTokenType::COMMENT, None),
+                Token::Number(_) | Token::Ratio(_) => (SemanticTokenType::NUMBER, None),
+                Token::Str(_) => (SemanticTokenType::STRING, None),
+                Token::Ident(name) => (ident_token_type(name), None),
+            };
+            ImCompleteSemanticToken {
+                start: span.start,
+                length: span.len(),
+                token_type,
+                paren_depth,
+            }
+        })
+        .collect()
+}
+
+/// Where an expression sits in its form, which decides how an identifier
+/// there is highlighted and how a list's items are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Value,
+    /// The head of a call.
+    Head,
+    /// The name a `defun` defines.
+    Function,
+    Parameter,
+    /// The parameter list of a `defun`.
+    Parameters,
+    /// The binding list of a `let`.
+    Bindings,
+    /// A single `(name value)` binding of a `let`.
+    Binding,
+}
+
+/// Appends the tokens of `expr`, a top-level form of a tree without parse
+/// errors, in source order. Unlike the tokens alone, the tree tells call
+/// heads and `defun` names (functions) and parameters apart from other
+/// identifiers. Comments are not part of the tree and aren't emitted.
+pub fn semantic_tokens_from_ast(expr: &Spanned<Expr>, tokens: &mut Vec<ImCompleteSemanticToken>) {
+    enum Item<'a> {
+        Expr(&'a Spanned<Expr>, usize, Role),
+        Close(usize, usize),
+    }
+
+    let mut stack = vec![Item::Expr(expr, 0, Role::Value)];
+    while let Some(item) = stack.pop() {
+        let ((expr, span), depth, role) = match item {
+            Item::Expr(expr, depth, role) => (expr, depth, role),
+            Item::Close(start, depth) => {
+                tokens.push(ImCompleteSemanticToken {
+                    start,
+                    length: 1,
+                    token_type: SemanticTokenType::OPERATOR,
+                    paren_depth: Some(depth),
+                });
+                continue;
+            }
+        };
+        let token_type = match expr {
+            Expr::Number(_) | Expr::Ratio(_) => SemanticTokenType::NUMBER,
+            Expr::Str(_) => SemanticTokenType::STRING,
+            Expr::Ident(name) => match ident_token_type(name) {
+                token_type if token_type != SemanticTokenType::VARIABLE => token_type,
+                _ => match role {
+                    Role::Head | Role::Function => SemanticTokenType::FUNCTION,
+                    Role::Parameter => SemanticTokenType::PARAMETER,
+                    _ => SemanticTokenType::VARIABLE,
+                },
+            },
+            Expr::Quote(quoted) => {
+                tokens.push(ImCompleteSemanticToken {
+                    start: span.start,
+                    length: 1,
+                    token_type: SemanticTokenType::OPERATOR,
+                    paren_depth: None,
+                });
+                stack.push(Item::Expr(quoted, depth, Role::Value));
+                continue;
+            }
+            Expr::List(items) => {
+                tokens.push(ImCompleteSemanticToken {
+                    start: span.start,
+                    length: 1,
+                    token_type: SemanticTokenType::OPERATOR,
+                    paren_depth: Some(depth),
+                });
+                stack.push(Item::Close(span.end - 1, depth));
+                let roles: Vec<Role> = match (role, items.as_slice()) {
+                    (Role::Parameters, _) => vec![Role::Parameter; items.len()],
+                    (Role::Bindings, _) => vec![Role::Binding; items.len()],
+                    (Role::Binding, _) => vec![Role::Value; items.len()],
+                    (_, [(Expr::Ident(keyword), _), _, (Expr::List(_), _), ..])
+                        if keyword == "defun" =>
+                    {
+                        [Role::Head, Role::Function, Role::Parameters].to_vec()
+                    }
+                    (_, [(Expr::Ident(keyword), _), (Expr::List(_), _), ..]) if is_let(keyword) => {
+                        [Role::Head, Role::Bindings].to_vec()
+                    }
+                    _ => vec![Role::Head],
+                };
+                for (index, item) in items.iter().enumerate().rev() {
+                    let role = roles.get(index).copied().unwrap_or(Role::Value);
+                    stack.push(Item::Expr(item, depth + 1, role));
+                }
+                continue;
+            }
+        };
+        tokens.push(ImCompleteSemanticToken {
+            start: span.start,
+            length: span.len(),
+            token_type,
+            paren_depth: None,
+        });
+    }
+}
+
+pub type Spanned<T> = (T, Span);
+
+/// The test and expression of a `cond` clause, `None` unless it is a list
+/// of exactly two.
+pub fn cond_clause(clause: &Expr) -> Option<&[Spanned<Expr>; 2]> {
+    match clause {
+        Expr::List(items) => items.as_slice().try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Whether `keyword` starts a `let` form, `(let ...)` or `(let* ...)`. The
+/// two only differ in what the binding values see.
+pub fn is_let(keyword: &str) -> bool {
+    keyword == "let" || keyword == "let*"
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(String),
+    Ratio(String),
+    Str(String),
+    Ident(String),
+    List(Vec<Spanned<Expr>>),
+    /// `'expr`, spanning from the quote to the end of `expr`.
+    Quote(Box<Spanned<Expr>>),
+}
+
+/// Canonical text of the expression: literals as written, strings escaped
+/// so they lex back to the same value and list items separated by a single
+/// space. Comments and line breaks are not kept.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(literal) | Expr::Ratio(literal) | Expr::Ident(literal) => {
+                f.write_str(literal)
+            }
+            Expr::Str(string) => {
+                f.write_str("\"")?;
+                for c in string.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Expr::List(items) => {
+                f.write_str("(")?;
+                for (index, (item, _)) in items.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(" ")?;
+                    }
+                    item.fmt(f)?;
+                }
+                f.write_str(")")
+            }
+            Expr::Quote(quoted) => write!(f, "'{}", quoted.0),
+        }
+    }
+}
+
+/// Builds the s-expression tree from the token stream, skipping comments.
+/// An unclosed list is closed at the end of input and a stray `)` is dropped,
+/// both with an error, so the tree is always available for analysis.
+pub fn parse_ast(tokens: &[(Token, Span)]) -> (Vec<Spanned<Expr>>, Vec<Simple<String>>) {
+    parse_ast_with_max_depth(tokens, DEFAULT_MAX_DEPTH)
+}
+
+/// Builds the AST like `parse_ast`, but a list opening past `max_depth`
+/// levels of nesting is reported at its `(` and skipped up to its `)`, so
+/// runaway input can't make the recursive passes over the AST overflow.
+pub fn parse_ast_with_max_depth(
+    tokens: &[(Token, Span)],
+    max_depth: usize,
+) -> (Vec<Spanned<Expr>>, Vec<Simple<String>>) {
+    let mut errors = vec![];
+    let mut stack: Vec<(usize, Vec<Spanned<Expr>>)> = vec![];
+    let mut top_level = vec![];
+    // The quotes waiting for an expression: where each starts and how many
+    // lists are open at it.
+    let mut quotes: Vec<(usize, usize)> = vec![];
+    // Parens still open inside a skipped list.
+    let mut skipped = 0;
+
+    for (token, span) in tokens {
+        if skipped > 0 {
+            match token {
+                Token::LParen => skipped += 1,
+                Token::RParen => skipped -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        let expr = match token {
+            Token::LParen if stack.len() >= max_depth => {
+                errors.push(Simple::custom(
+                    span.clone(),
+                    format!("nesting exceeds the maximum depth of {}", max_depth),
+                ));
+                // The skipped list is what the quotes right before it quote.
+                quotes.retain(|(_, depth)| *depth < stack.len());
+                skipped = 1;
+                continue;
+            }
+            Token::LParen => {
+                stack.push((span.start, vec![]));
+                continue;
+            }
+            Token::Quote => {
+                quotes.push((span.start, stack.len()));
+                continue;
+            }
+            Token::RParen => match stack.pop() {
+                Some((start, items)) => (Expr::List(items), start..span.end),
+                None => {
+                    errors.push(Simple::custom(span.clone(), "unexpected ')'"));
+                    continue;
+                }
+            },
+            Token::Comment => continue,
+            Token::Number(n) => (Expr::Number(n.clone()), span.clone()),
+            Token::Ratio(ratio) => (Expr::Ratio(ratio.clone()), span.clone()),
+            Token::Str(string) => (Expr::Str(string.clone()), span.clone()),
+            Token::Ident(ident) => (Expr::Ident(ident.clone()), span.clone()),
+        };
+        let expr = apply_quotes(expr, stack.len(), &mut quotes, &mut errors);
+        match stack.last_mut() {
+            Some((_, items)) => items.push(expr),
+            None => top_level.push(expr),
+        }
+    }
+
+    let end = tokens.last().map_or(0, |(_, span)| span.end);
+    while let Some((start, items)) = stack.pop() {
+        errors.push(Simple::custom(start..start + 1, "unclosed '('"));
+        let expr = apply_quotes(
+            (Expr::List(items), start..end),
+            stack.len(),
+            &mut quotes,
+            &mut errors,
+        );
+        match stack.last_mut() {
+            Some((_, items)) => items.push(expr),
+            None => top_level.push(expr),
+        }
+    }
+    for (start, _) in quotes {
+        errors.push(unquoted(start));
+    }
+
+    (top_level, errors)
+}
+
+/// Wraps `expr`, which has `depth` lists open around it, in the quotes
+/// right before it. Quotes still waiting inside it never got an expression.
+fn apply_quotes(
+    mut expr: Spanned<Expr>,
+    depth: usize,
+    quotes: &mut Vec<(usize, usize)>,
+    errors: &mut Vec<Simple<String>>,
+) -> Spanned<Expr> {
+    while let Some((start, quote_depth)) = quotes.last().copied() {
+        if quote_depth < depth {
+            break;
+        }
+        quotes.pop();
+        if quote_depth > depth {
+            errors.push(unquoted(start));
+            continue;
+        }
+        let end = expr.1.end;
+        expr = (Expr::Quote(Box::new(expr)), start..end);
+    }
+    expr
+}
+
+fn unquoted(start: usize) -> Simple<String> {
+    Simple::custom(start..start + 1, "expected an expression after the quote")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Defun {
+    pub name: Spanned<String>,
+    pub params: Vec<Spanned<String>>,
+    pub span: Span,
+}
+
+/// Collects every `(defun NAME (params...) body)` form in source order.
+pub fn collect_defuns(ast: &[Spanned<Expr>]) -> Vec<Defun> {
+    let mut defuns = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, span)) = stack.pop() {
+        if let Expr::List(items) = expr {
+            if let [(Expr::Ident(keyword), _), (Expr::Ident(name), name_span), (Expr::List(params), _), ..] =
+                items.as_slice()
+            {
+                if keyword == "defun" {
+                    let params = params
+                        .iter()
+                        .filter_map(|(param, span)| match param {
+                            Expr::Ident(param) => Some((param.clone(), span.clone())),
+                            _ => None,
+                        })
+                        .collect();
+                    defuns.push(Defun {
+                        name: (name.clone(), name_span.clone()),
+                        params,
+                        span: span.clone(),
+                    });
+                }
+            }
+            stack.extend(items.iter().rev());
+        }
+    }
+    defuns
+}
+
+/// Collects the path of every `(require "path")` or `(include "path")` form
+/// in source order, with the span of its string literal.
+pub fn collect_requires(ast: &[Spanned<Expr>]) -> Vec<Spanned<String>> {
+    let mut requires = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().rev().collect();
+    while let Some((expr, _)) = stack.pop() {
+        if let Expr::List(items) = expr {
+            if let [(Expr::Ident(keyword), _), (Expr::Str(path), span)] = items.as_slice() {
+                if keyword == "require" || keyword == "include" {
+                    requires.push((path.clone(), span.clone()));
+                }
+            }
+            stack.extend(items.iter().rev());
+        }
+    }
+    requires
+}
+
+/// Maps each defun name to its parameter names. The first definition wins.
+pub fn collect_defun_params(ast: &[Spanned<Expr>]) -> HashMap<String, Vec<String>> {
+    let mut defun_params = HashMap::new();
+    for defun in collect_defuns(ast) {
+        defun_params
+            .entry(defun.name.0)
+            .or_insert_with(|| defun.params.into_iter().map(|(param, _)| param).collect());
+    }
+    defun_params
+}
+
+/// Maps each defun name to the comment lines directly above its form, with
+/// the leading `;` stripped. Only comments on lines of their own count, and
+/// a blank line ends the block. The first definition wins.
+pub fn collect_doc_comments(source: &str, tokens: &[(Token, Span)]) -> HashMap<String, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let text = |span: Span| -> String { chars.get(span).unwrap_or_default().iter().collect() };
+    let line_breaks = |span: Span| text(span).matches('\n').count();
+
+    let mut docs = HashMap::new();
+    for (index, window) in tokens.windows(3).enumerate() {
+        let [(Token::LParen, open), (Token::Ident(keyword), _), (Token::Ident(name), _)] = window
+        else {
+            continue;
+        };
+        if keyword != "defun" || docs.contains_key(name) {
+            continue;
+        }
+
+        let mut lines = vec![];
+        let mut below = open.start;
+        for comment in (0..index).rev() {
+            let (Token::Comment, span) = &tokens[comment] else {
+                break;
+            };
+            let own_line = comment == 0 || line_breaks(tokens[comment - 1].1.end..span.start) > 0;
+            if !own_line || line_breaks(span.end..below) != 1 {
+                break;
+            }
+            lines.push(
+                text(span.clone())
+                    .trim_start_matches(';')
+                    .trim()
+                    .to_string(),
+            );
+            below = span.start;
+        }
+        if !lines.is_empty() {
+            lines.reverse();
+            docs.insert(name.clone(), lines.join("\n"));
+        }
     }
+    docs
+}
+
+/// Finds `(fname args...)` calls to known functions whose arity matches and
+/// returns the start offset of each argument with its parameter name.
+pub fn collect_parameter_hints(
+    ast: &[Spanned<Expr>],
+    defuns: &HashMap<String, Vec<String>>,
+) -> Vec<(usize, String)> {
+    let mut hints = vec![];
+    let mut stack: Vec<&Spanned<Expr>> = ast.iter().collect();
+    while let Some((expr, _)) = stack.pop() {
+        if let Expr::List(items) = expr {
+            if let Some(((Expr::Ident(name), _), args)) = items.split_first() {
+                match defuns.get(name) {
+                    Some(params) if params.len() == args.len() => hints.extend(
+                        args.iter()
+                            .zip(params)
+                            .map(|((_, span), param)| (span.start, param.clone())),
+                    ),
+                    _ => {}
+                }
+            }
+            stack.extend(items);
+        }
+    }
+    hints.sort();
+    hints
+}
+
+/// Counts the parens opened but not yet closed before `offset`.
+pub fn nesting_depth(tokens: &[(Token, Span)], offset: usize) -> usize {
+    tokens
+        .iter()
+        .take_while(|(_, span)| span.end <= offset)
+        .fold(0, |depth, (token, _)| match token {
+            Token::LParen => depth + 1,
+            Token::RParen => depth.saturating_sub(1),
+            _ => depth,
+        })
+}
+
+/// Returns the head identifier of the innermost call around `offset` and the
+/// index of the argument the cursor is on. Works on unclosed calls too, since
+/// it only looks at the tokens before the cursor.
+pub fn call_context(tokens: &[(Token, Span)], offset: usize) -> Option<(String, usize)> {
+    struct Frame {
+        head: Option<String>,
+        elements: usize,
+        complete: usize,
+    }
+    fn add_element(frame: Option<&mut Frame>, head: Option<&String>, complete: bool) {
+        if let Some(frame) = frame {
+            if frame.elements == 0 {
+                frame.head = head.cloned();
+            }
+            frame.elements += 1;
+            if complete {
+                frame.complete += 1;
+            }
+        }
+    }
+
+    let mut stack: Vec<Frame> = vec![];
+    for (token, span) in tokens.iter().take_while(|(_, span)| span.start < offset) {
+        match token {
+            Token::LParen => stack.push(Frame {
+                head: None,
+                elements: 0,
+                complete: 0,
+            }),
+            Token::RParen => {
+                stack.pop();
+                add_element(stack.last_mut(), None, span.end < offset);
+            }
+            Token::Comment | Token::Quote => {}
+            Token::Number(_) | Token::Ratio(_) | Token::Str(_) => {
+                add_element(stack.last_mut(), None, span.end < offset)
+            }
+            Token::Ident(ident) => add_element(stack.last_mut(), Some(ident), span.end < offset),
+        }
+    }
+
+    let frame = stack.pop()?;
+    Some((frame.head?, frame.complete.checked_sub(1)?))
+}
+
+/// Pairs every `(` with its matching `)`. Unmatched parens are left out.
+pub fn match_parens(tokens: &[(Token, Span)]) -> Vec<(Span, Span)> {
+    let mut stack = vec![];
+    let mut pairs = vec![];
+    for (token, span) in tokens {
+        match token {
+            Token::LParen => stack.push(span.clone()),
+            Token::RParen => {
+                if let Some(open) = stack.pop() {
+                    pairs.push((open, span.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+    pairs
+}
+
+/// Returns the `(`/`)` pair when `offset` is on either paren of a balanced pair.
+pub fn match_paren(tokens: &[(Token, Span)], offset: usize) -> Option<(Span, Span)> {
+    match_parens(tokens)
+        .into_iter()
+        .find(|(open, close)| open.contains(&offset) || close.contains(&offset))
+}
+
+/// How to balance an unmatched paren.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParenFix {
+    /// Insert a `)` at the offset, the end of the last non-comment token.
+    Insert(usize),
+    /// Delete the stray `)`.
+    Remove,
+}
+
+/// Returns a fix for every unmatched paren, keyed by the paren's span.
+pub fn paren_fixes(tokens: &[(Token, Span)]) -> Vec<(Span, ParenFix)> {
+    let mut stack = vec![];
+    let mut fixes = vec![];
+    for (token, span) in tokens {
+        match token {
+            Token::LParen => stack.push(span.clone()),
+            Token::RParen => {
+                let matched = stack.pop().is_some();
+                if !matched {
+                    fixes.push((span.clone(), ParenFix::Remove));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let end = tokens
+        .iter()
+        .rev()
+        .find(|(token, _)| *token != Token::Comment)
+        .map_or(0, |(_, span)| span.end);
+    fixes.extend(
+        stack
+            .into_iter()
+            .rev()
+            .map(|open| (open, ParenFix::Insert(end))),
+    );
+    fixes
+}
+
+/// Finds the outermost list inside `selection` that is an argument of a call,
+/// returning it with the call. Lists directly under `defun`, `let` or `if`
+/// are skipped, since moving them out would change scoping or evaluation.
+pub fn find_extractable<'a>(
+    ast: &'a [Spanned<Expr>],
+    selection: &Span,
+) -> Option<(&'a Spanned<Expr>, &'a Spanned<Expr>)> {
+    let covers = |outer: &Span, inner: &Span| outer.start <= inner.start && inner.end <= outer.end;
+    let mut form = ast.iter().find(|(_, span)| covers(span, selection))?;
+    loop {
+        let Expr::List(items) = &form.0 else {
+            return None;
+        };
+        let special = matches!(
+            items.first(),
+            Some((Expr::Ident(head), _)) if ["defun", "let", "let*", "if"].contains(&head.as_str())
+        );
+        let selected = items
+            .iter()
+            .find(|(expr, span)| matches!(expr, Expr::List(_)) && covers(selection, span));
+        if let Some(item) = selected {
+            return (!special).then_some((item, form));
+        }
+        form = items.iter().find(|(_, span)| covers(span, selection))?;
+    }
+}
+
+/// Returns the token whose half-open span contains `offset`. At a boundary
+/// between two adjacent tokens that is the one starting at `offset`; in
+/// whitespace between tokens there is none.
+pub fn token_at_offset(tokens: &[(Token, Span)], offset: usize) -> Option<&(Token, Span)> {
+    let index = tokens.partition_point(|(_, span)| span.end <= offset);
+    tokens.get(index).filter(|(_, span)| span.contains(&offset))
+}
+
+pub fn find_identifier_occurrences(tokens: &[(Token, Span)], name: &str) -> Vec<Span> {
+    tokens
+        .iter()
+        .filter_map(|(token, span)| match token {
+            Token::Ident(ident) if ident == name => Some(span.clone()),
+            _ => None,
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -182,4 +1227,805 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn ast_tokens_match_flat_tokens_without_context() {
+        let source = "; sum\n(print (+ 1 2.5 \"s\")) ; done\n(print x)";
+        let (tokens, _) = lexer().parse_recovery(source);
+        let flat = flat_semantic_tokens(&tokens.unwrap());
+        let from_ast = parse(source).semantic_tokens;
+        let describe = |tokens: &[ImCompleteSemanticToken]| {
+            tokens
+                .iter()
+                .map(|token| (token.start, token.length, token.paren_depth))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(describe(&from_ast), describe(&flat));
+        let types = |tokens: &[ImCompleteSemanticToken]| {
+            tokens
+                .iter()
+                .map(|token| token.token_type.clone())
+                .collect::<Vec<_>>()
+        };
+        let mut expected = types(&flat);
+        // Only the call heads differ: `print` twice.
+        expected[2] = SemanticTokenType::FUNCTION;
+        expected[12] = SemanticTokenType::FUNCTION;
+        assert_eq!(types(&from_ast), expected);
+
+        // With a parse error the tokens are highlighted on their own.
+        let result = parse("(defun f (x) (g x)");
+        assert_eq!(result.parse_errors.len(), 1);
+        assert!(result
+            .semantic_tokens
+            .iter()
+            .all(|token| token.token_type != SemanticTokenType::FUNCTION
+                && token.token_type != SemanticTokenType::PARAMETER));
+    }
+
+    #[test]
+    fn call_heads_are_functions() {
+        let source = "(defun fact (n) n)\n(fact 5)\n(print fact)\n(let ((x (fact 1))) (+ x 1))";
+        let result = parse(source);
+        let idents: Vec<_> = result
+            .semantic_tokens
+            .iter()
+            .filter(|token| token.paren_depth.is_none())
+            .filter(|token| token.token_type != SemanticTokenType::NUMBER)
+            .map(|token| {
+                let text: String = source
+                    .chars()
+                    .skip(token.start)
+                    .take(token.length)
+                    .collect();
+                (text, token.token_type.clone())
+            })
+            .collect();
+        assert_eq!(
+            idents,
+            vec![
+                ("defun".into(), SemanticTokenType::KEYWORD),
+                ("fact".into(), SemanticTokenType::FUNCTION),
+                ("n".into(), SemanticTokenType::PARAMETER),
+                ("n".into(), SemanticTokenType::VARIABLE),
+                ("fact".into(), SemanticTokenType::FUNCTION),
+                ("print".into(), SemanticTokenType::FUNCTION),
+                ("fact".into(), SemanticTokenType::VARIABLE),
+                ("let".into(), SemanticTokenType::KEYWORD),
+                ("x".into(), SemanticTokenType::VARIABLE),
+                ("fact".into(), SemanticTokenType::FUNCTION),
+                ("+".into(), SemanticTokenType::OPERATOR),
+                ("x".into(), SemanticTokenType::VARIABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn paren_semantic_token_depths() {
+        let depths: Vec<_> = parse("((()))")
+            .semantic_tokens
+            .iter()
+            .map(|token| token.paren_depth)
+            .collect();
+        assert_eq!(depths, [0, 1, 2, 2, 1, 0].map(Some).to_vec());
+    }
+
+    #[test]
+    fn lex_malformed_numbers() {
+        let result = parse("(+ 12.0 1)");
+        assert!(result.parse_errors.is_empty());
+        let (tokens, _) = lexer().parse_recovery("12.0");
+        assert_eq!(tokens.unwrap(), vec![(Token::Number("12.0".into()), 0..4)]);
+
+        let result = parse("(+ 12. 1)");
+        assert_eq!(result.parse_errors.len(), 1);
+        assert_eq!(result.parse_errors[0].span(), 3..6);
+        assert_eq!(
+            result.parse_errors[0].reason(),
+            &chumsky::error::SimpleReason::Custom(
+                "malformed number '12.': expected digits after '.'".into()
+            )
+        );
+        assert_eq!(
+            result.semantic_tokens[2].token_type,
+            SemanticTokenType::NUMBER
+        );
+        assert_eq!(result.semantic_tokens[2].length, 3);
+    }
+
+    #[test]
+    fn tokenize_matches_lexer() {
+        let source = "; fact\n(defun fact (n)\n  (if (= n 0) 1 (* n (fact (- n 1)))))\n(print \"é\" 0xff 1.5)  ";
+        let tokens: Vec<_> = tokenize(source).collect();
+        assert_eq!(tokens, lexer().parse(source).unwrap());
+        assert_eq!(tokens.len(), 35);
+
+        let mut tokens = tokenize("(f x) (g");
+        assert_eq!(tokens.next(), Some((Token::LParen, 0..1)));
+        assert_eq!(tokens.nth(3), Some((Token::LParen, 6..7)));
+        assert_eq!(tokens.next(), Some((Token::Ident("g".into()), 7..8)));
+        assert_eq!(tokens.next(), None);
+
+        // Chars no token starts with are skipped, like `lexer()` does.
+        let source = "(f # x) @@ 1. (g 0x) é $";
+        let tokens: Vec<_> = tokenize(source).collect();
+        assert_eq!(tokens, lexer().parse_recovery(source).0.unwrap());
+        assert_eq!(tokens.len(), 9);
+
+        // Tokens longer than the window first lexed from still come whole.
+        let source = format!("(print \"{}\") ; {}", "s".repeat(200), "c".repeat(200));
+        let tokens: Vec<_> = tokenize(&source).collect();
+        assert_eq!(tokens, lexer().parse(source.as_str()).unwrap());
+        assert_eq!(tokens[2], (Token::Str("s".repeat(200)), 7..209));
+    }
+
+    #[test]
+    fn parse_returns_flat_tokens() {
+        assert_eq!(
+            parse("(+ 1 2)").tokens,
+            vec![
+                (Token::LParen, 0..1),
+                (Token::Ident("+".into()), 1..2),
+                (Token::Number("1".into()), 3..4),
+                (Token::Number("2".into()), 5..6),
+                (Token::RParen, 6..7),
+            ]
+        );
+        // Recovery keeps the tokens around what couldn't be lexed or parsed.
+        assert_eq!(parse("(+ 1 @").tokens.len(), 3);
+    }
+
+    #[test]
+    fn parse_keeps_tokens_around_bad_chars() {
+        let result = parse("(+ 1 @");
+        let tokens: Vec<_> = result
+            .semantic_tokens
+            .iter()
+            .map(|token| (token.start, token.token_type.clone()))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (0, SemanticTokenType::OPERATOR),
+                (1, SemanticTokenType::OPERATOR),
+                (3, SemanticTokenType::NUMBER),
+            ]
+        );
+        let spans: Vec<_> = result.parse_errors.iter().map(|err| err.span()).collect();
+        assert_eq!(spans, vec![5..6, 0..1]);
+
+        // Bad chars at the end of input are skipped like those before a token.
+        let (tokens, errors) = lexer().parse_recovery("(a)#");
+        assert_eq!(tokens.unwrap().len(), 3);
+        assert_eq!(errors[0].span(), 3..4);
+        assert_eq!(parse("@").parse_errors.len(), 1);
+    }
+
+    #[test]
+    fn lex_hex_numbers() {
+        let tokens = lexer().parse("(+ 0xff 0x1A)").unwrap();
+        assert_eq!(tokens[2], (Token::Number("0xff".into()), 3..7));
+        assert_eq!(tokens[3], (Token::Number("0x1A".into()), 8..12));
+        assert_eq!(number_value("0xff"), Some(255.0));
+        assert_eq!(number_value("0x1A"), Some(26.0));
+        assert_eq!(number_value("1.5"), Some(1.5));
+    }
+
+    #[test]
+    fn parse_past_max_depth() {
+        let result = parse_with_max_depth("(a (b (c (d)) e) f)", 2);
+        assert_eq!(result.parse_errors.len(), 1);
+        assert_eq!(result.parse_errors[0].span(), 6..7);
+        assert_eq!(
+            result.parse_errors[0].reason(),
+            &chumsky::error::SimpleReason::Custom("nesting exceeds the maximum depth of 2".into())
+        );
+        let (Expr::List(items), _) = &result.ast[0] else {
+            panic!("expected a list");
+        };
+        let (Expr::List(inner), _) = &items[1] else {
+            panic!("expected a list");
+        };
+        assert_eq!(
+            inner
+                .iter()
+                .map(|(expr, _)| expr.clone())
+                .collect::<Vec<_>>(),
+            vec![Expr::Ident("b".into()), Expr::Ident("e".into())]
+        );
+
+        let deep = format!("{}{}", "(".repeat(100_000), ")".repeat(100_000));
+        let result = parse(&deep);
+        assert_eq!(result.parse_errors.len(), 1);
+    }
+
+    #[test]
+    fn parse_deeply_nested_input() {
+        let deep = format!("{}{}", "(".repeat(50_000), ")".repeat(50_000));
+        let result = parse(&deep);
+        assert_eq!(result.parse_errors.len(), 1);
+        assert_eq!(result.ast.len(), 1);
+        assert_eq!(result.ast[0].1, 0..100_000);
+
+        let unclosed = "(".repeat(50_000);
+        let result = parse(&unclosed);
+        assert_eq!(result.parse_errors.len(), DEFAULT_MAX_DEPTH + 1);
+        assert_eq!(result.ast.len(), 1);
+    }
+
+    #[test]
+    fn parse_time_grows_linearly() {
+        // 100k tokens take a few seconds in a debug build; anything
+        // quadratic in the input would take hours.
+        let source = "(f x 1)\n(g \"s\") ; c\n".repeat(8_000);
+        let start = std::time::Instant::now();
+        let result = parse(&source);
+        assert!(result.parse_errors.is_empty());
+        assert_eq!(result.ast.len(), 16_000);
+        assert!(start.elapsed() < std::time::Duration::from_secs(60));
+    }
+
+    #[test]
+    fn lex_shebang_line() {
+        let source = "#!/usr/bin/env orelang\n(print 1)\n";
+        let tokens = lexer().parse(source).unwrap();
+        assert_eq!(tokens[0], (Token::Comment, 0..22));
+        assert_eq!(tokens[1], (Token::LParen, 23..24));
+        assert_eq!(tokenize(source).collect::<Vec<_>>(), tokens);
+
+        let result = parse(source);
+        assert!(result.parse_errors.is_empty());
+        assert_eq!(result.ast.len(), 1);
+        assert_eq!(
+            result.semantic_tokens[0].token_type,
+            SemanticTokenType::COMMENT
+        );
+
+        // Anywhere else `#` can't start a token.
+        let (tokens, errors) = lexer().parse_recovery(" #!/usr/bin/env orelang");
+        assert!(!tokens.unwrap().contains(&(Token::Comment, 1..23)));
+        assert_eq!(errors[0].span(), 1..2);
+        let (tokens, errors) = lexer().parse_recovery("(f)\n#!x");
+        assert!(!tokens
+            .unwrap()
+            .iter()
+            .any(|(token, _)| *token == Token::Comment));
+        assert_eq!(errors[0].span(), 4..5);
+    }
+
+    #[test]
+    fn lex_byte_order_mark() {
+        let source = "\u{feff}(print 1)\n";
+        let tokens = lexer().parse(source).unwrap();
+        assert_eq!(tokens[0], (Token::LParen, 1..2));
+        assert_eq!(tokens[1], (Token::Ident("print".into()), 2..7));
+        assert_eq!(tokenize(source).collect::<Vec<_>>(), tokens);
+        assert!(parse(source).parse_errors.is_empty());
+
+        let source = "\u{feff}#!/usr/bin/env orelang\n(print 1)";
+        let tokens = lexer().parse(source).unwrap();
+        assert_eq!(tokens[0], (Token::Comment, 1..23));
+        assert_eq!(tokenize(source).collect::<Vec<_>>(), tokens);
+
+        // Past the start it is just a char no token starts with.
+        let (_, errors) = lexer().parse_recovery("(f)\u{feff}");
+        assert_eq!(errors[0].span(), 3..4);
+    }
+
+    #[test]
+    fn lex_ratios() {
+        let tokens = lexer().parse("(+ 3/4 (/ 3 4))").unwrap();
+        assert_eq!(tokens[2], (Token::Ratio("3/4".into()), 3..6));
+        assert_eq!(tokens[4], (Token::Ident("/".into()), 8..9));
+        assert_eq!(tokens[5], (Token::Number("3".into()), 10..11));
+        let result = parse("3/4");
+        assert_eq!(
+            result.semantic_tokens[0].token_type,
+            SemanticTokenType::NUMBER
+        );
+        assert_eq!(result.ast, vec![(Expr::Ratio("3/4".into()), 0..3)]);
+        assert_eq!(
+            lexer().parse("1/0").unwrap(),
+            vec![(Token::Ratio("1/0".into()), 0..3)]
+        );
+    }
+
+    #[test]
+    fn lex_precedence() {
+        let lex = |source| lexer().parse_recovery(source);
+
+        // Keywords are plain identifiers, so a longer name is never split.
+        assert_eq!(
+            lex("truent"),
+            (Some(vec![(Token::Ident("truent".into()), 0..6)]), vec![])
+        );
+        assert_eq!(
+            lex("true"),
+            (Some(vec![(Token::Ident("true".into()), 0..4)]), vec![])
+        );
+
+        // `-` is an operator unless a digit follows it right away.
+        assert_eq!(
+            lex("-"),
+            (Some(vec![(Token::Ident("-".into()), 0..1)]), vec![])
+        );
+        assert_eq!(
+            lex("(- 5)").0.unwrap()[1..3],
+            [
+                (Token::Ident("-".into()), 1..2),
+                (Token::Number("5".into()), 3..4)
+            ]
+        );
+        assert_eq!(
+            lex("-5"),
+            (Some(vec![(Token::Number("-5".into()), 0..2)]), vec![])
+        );
+        assert_eq!(
+            lex("-1/2 -2.5e3"),
+            (
+                Some(vec![
+                    (Token::Ratio("-1/2".into()), 0..4),
+                    (Token::Number("-2.5e3".into()), 5..11)
+                ]),
+                vec![]
+            )
+        );
+        assert_eq!(
+            lex("-x").0.unwrap(),
+            vec![
+                (Token::Ident("-".into()), 0..1),
+                (Token::Ident("x".into()), 1..2)
+            ]
+        );
+
+        // Comparisons of two chars are one token unless spaced apart.
+        assert_eq!(
+            lex("(<= 1 2)").0.unwrap()[1],
+            (Token::Ident("<=".into()), 1..3)
+        );
+        for operator in [">=", "!=", "=="] {
+            assert_eq!(
+                lex(operator),
+                (Some(vec![(Token::Ident(operator.into()), 0..2)]), vec![])
+            );
+        }
+        assert_eq!(
+            lex("< =").0.unwrap(),
+            vec![
+                (Token::Ident("<".into()), 0..1),
+                (Token::Ident("=".into()), 2..3)
+            ]
+        );
+        assert_eq!(
+            lex("(= 1 2)").0.unwrap()[1],
+            (Token::Ident("=".into()), 1..2)
+        );
+
+        // `0x` always starts a hex number and needs a hex digit after it.
+        let (tokens, errors) = lex("0xg");
+        assert_eq!(
+            tokens.unwrap(),
+            vec![
+                (Token::Number("0x".into()), 0..2),
+                (Token::Ident("g".into()), 2..3)
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span(), 0..2);
+        assert_eq!(
+            errors[0].reason(),
+            &chumsky::error::SimpleReason::Custom(
+                "malformed number '0x': expected hex digits after '0x'".into()
+            )
+        );
+        assert_eq!(
+            lex("0xfg").0.unwrap(),
+            vec![
+                (Token::Number("0xf".into()), 0..3),
+                (Token::Ident("g".into()), 3..4)
+            ]
+        );
+
+        // A trailing `.` stays part of the number but is reported.
+        let (tokens, errors) = lex("1.");
+        assert_eq!(tokens.unwrap(), vec![(Token::Number("1.".into()), 0..2)]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span(), 0..2);
+        assert_eq!(
+            lex("1/ 2").0.unwrap(),
+            vec![
+                (Token::Number("1".into()), 0..1),
+                (Token::Ident("/".into()), 1..2),
+                (Token::Number("2".into()), 3..4)
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_exponents() {
+        let tokens = lexer().parse("(+ 1.5e-3 2E10 3e+2)").unwrap();
+        assert_eq!(tokens[2], (Token::Number("1.5e-3".into()), 3..9));
+        assert_eq!(tokens[3], (Token::Number("2E10".into()), 10..14));
+        assert_eq!(tokens[4], (Token::Number("3e+2".into()), 15..19));
+        assert_eq!(number_value("1.5e-3"), Some(0.0015));
+    }
+
+    #[test]
+    fn lex_strings() {
+        let result = lexer().parse(r#""a \"b\"\n""#).unwrap();
+        assert_eq!(result, vec![(Token::Str("a \"b\"\n".into()), 0..11)]);
+
+        // The string swallows the rest of the input, leaving `(print` unclosed.
+        let result = parse("(print \"abc)\n(+ 1 2)");
+        assert_eq!(result.parse_errors.len(), 2);
+        assert_eq!(result.parse_errors[0].span(), 7..8);
+        assert_eq!(
+            result.parse_errors[0].reason(),
+            &chumsky::error::SimpleReason::Custom("unterminated string literal".into())
+        );
+        assert_eq!(
+            result.semantic_tokens[2].token_type,
+            SemanticTokenType::STRING
+        );
+        assert_eq!(result.semantic_tokens[2].start, 7);
+    }
+
+    fn without_spans(ast: &[Spanned<Expr>]) -> Vec<Expr> {
+        ast.iter()
+            .map(|(expr, _)| match expr {
+                Expr::List(items) => Expr::List(
+                    without_spans(items)
+                        .into_iter()
+                        .map(|item| (item, 0..0))
+                        .collect(),
+                ),
+                expr => expr.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let source = "; fact\n(defun fact (n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))\n(print \"a \\\"q\\\" \\\\ \\n\tb\" 0xff -1.5e3 3/4 ())";
+        let ast = parse(source).ast;
+        let printed: Vec<_> = ast.iter().map(|(expr, _)| expr.to_string()).collect();
+        assert_eq!(
+            printed,
+            vec![
+                "(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))",
+                "(print \"a \\\"q\\\" \\\\ \\n\\tb\" 0xff -1.5e3 3/4 ())",
+            ]
+        );
+
+        let reparsed = parse(&printed.join("\n"));
+        assert!(reparsed.parse_errors.is_empty());
+        assert_eq!(without_spans(&reparsed.ast), without_spans(&ast));
+    }
+
+    #[test]
+    fn parse_ast_list() {
+        let tokens = lexer().parse("(+ 1 (f x))").unwrap();
+        let (ast, errors) = parse_ast(&tokens);
+        assert!(errors.is_empty());
+        assert_eq!(
+            ast,
+            vec![(
+                Expr::List(vec![
+                    (Expr::Ident("+".into()), 1..2),
+                    (Expr::Number("1".into()), 3..4),
+                    (
+                        Expr::List(vec![
+                            (Expr::Ident("f".into()), 6..7),
+                            (Expr::Ident("x".into()), 8..9),
+                        ]),
+                        5..10
+                    ),
+                ]),
+                0..11
+            )]
+        );
+
+        let tokens = lexer().parse("(a (b) c").unwrap();
+        let (ast, errors) = parse_ast(&tokens);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span(), 0..1);
+        assert_eq!(ast[0].1, 0..8);
+
+        let tokens = lexer().parse("a)").unwrap();
+        let (ast, errors) = parse_ast(&tokens);
+        assert_eq!(errors[0].span(), 1..2);
+        assert_eq!(ast, vec![(Expr::Ident("a".into()), 0..1)]);
+    }
+
+    /// Applies `edit` to `source`, replacing the edited chars with `text`.
+    fn apply(source: &str, start: usize, end: usize, text: &str) -> (String, Edit) {
+        let chars: Vec<char> = source.chars().collect();
+        let edited = chars[..start]
+            .iter()
+            .chain(text.chars().collect::<Vec<_>>().iter())
+            .chain(&chars[end..])
+            .collect();
+        let edit = Edit {
+            start,
+            old_end: end,
+            new_end: start + text.chars().count(),
+        };
+        (edited, edit)
+    }
+
+    fn assert_same_parse(incremental: &ParseResult, full: &ParseResult) {
+        assert_eq!(incremental.ast, full.ast);
+        assert_eq!(incremental.len, full.len);
+        assert_eq!(incremental.tokens, full.tokens);
+        assert_eq!(incremental.parse_errors, full.parse_errors);
+        let tokens = |result: &ParseResult| {
+            result
+                .semantic_tokens
+                .iter()
+                .map(|token| {
+                    let type_ = token.token_type.clone();
+                    (token.start, token.length, type_, token.paren_depth)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tokens(incremental), tokens(full));
+    }
+
+    #[test]
+    fn reparse_only_the_edited_form() {
+        let source = "(defun f (a) (+ a 1))\n; between\n(defun g (b) (* b 2))\n(defun h (c) c)\n";
+        let previous = parse(source);
+        let Expr::List(first_items) = &previous.ast[0].0 else {
+            panic!("expected a list");
+        };
+        let first_items = first_items.as_ptr();
+        let mut third = previous.ast[2].clone();
+
+        // Renaming `b` to `bee` in the body of `g`.
+        let (edited, edit) = apply(source, 48, 49, "bee");
+        let result = reparse(previous, &edited, edit, DEFAULT_MAX_DEPTH);
+        assert_same_parse(&result, &parse(&edited));
+        // The sibling before the edit is the same node, not a reparsed copy.
+        let Expr::List(items) = &result.ast[0].0 else {
+            panic!("expected a list");
+        };
+        assert_eq!(items.as_ptr(), first_items);
+        shift_spans(&mut third, 2);
+        assert_eq!(result.ast[2], third);
+
+        // Edits across forms, between them or unbalancing one parse in full.
+        for (start, end, text) in [(15, 40, ""), (22, 22, "x"), (45, 45, "("), (30, 31, "")] {
+            let previous = parse(source);
+            let (edited, edit) = apply(source, start, end, text);
+            let result = reparse(previous, &edited, edit, DEFAULT_MAX_DEPTH);
+            assert_same_parse(&result, &parse(&edited));
+        }
+
+        // So does an edit that doesn't lead from the previous text to the
+        // new one, like one missing a change made before it.
+        let (edited, edit) = apply(source, 48, 49, "bee");
+        let edited = format!("{}(i)\n", edited);
+        let result = reparse(parse(source), &edited, edit, DEFAULT_MAX_DEPTH);
+        assert_same_parse(&result, &parse(&edited));
+    }
+
+    #[test]
+    fn edits_compose() {
+        let source = "(a b c d e)";
+        let edits = [(3, 4, "xyz"), (1, 2, ""), (5, 8, "q"), (9, 9, "!!")];
+        let mut text = source.to_string();
+        let mut combined: Option<Edit> = None;
+        for (start, end, inserted) in edits {
+            let (edited, edit) = apply(&text, start, end, inserted);
+            text = edited;
+            combined = Some(combined.map_or(edit, |combined| combined.then(edit)));
+        }
+        let combined = combined.unwrap();
+        let replacement: String = text
+            .chars()
+            .skip(combined.start)
+            .take(combined.new_end - combined.start)
+            .collect();
+        let (edited, _) = apply(source, combined.start, combined.old_end, &replacement);
+        assert_eq!(edited, text);
+    }
+
+    #[test]
+    fn parse_ast_skips_comments_inside_lists() {
+        let result = parse("(+ 1 ; c\n 2)");
+        assert!(result.parse_errors.is_empty());
+        assert_eq!(
+            result.ast,
+            vec![(
+                Expr::List(vec![
+                    (Expr::Ident("+".into()), 1..2),
+                    (Expr::Number("1".into()), 3..4),
+                    (Expr::Number("2".into()), 10..11),
+                ]),
+                0..12
+            )]
+        );
+        let comment = result
+            .semantic_tokens
+            .iter()
+            .find(|token| token.token_type == SemanticTokenType::COMMENT)
+            .unwrap();
+        assert_eq!((comment.start, comment.length), (5, 3));
+    }
+
+    #[test]
+    fn highlight_fact() {
+        let source =
+            "; factorial\n(defun fact (n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))\n";
+        let highlights = highlight(source);
+        let of_type = |token_type: SemanticTokenType| {
+            highlights
+                .iter()
+                .filter(|(_, highlighted, _)| *highlighted == token_type)
+                .map(|(span, _, _)| span.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(of_type(SemanticTokenType::COMMENT), vec![0..11]);
+        assert_eq!(
+            of_type(SemanticTokenType::NUMBER),
+            vec![39..40, 48..49, 72..73]
+        );
+        assert_eq!(
+            highlights[1],
+            (
+                12..13,
+                SemanticTokenType::OPERATOR,
+                vec![SemanticTokenModifier::new("depth0")]
+            )
+        );
+        assert_eq!(highlights[2], (13..18, SemanticTokenType::KEYWORD, vec![]));
+    }
+
+    #[test]
+    fn parse_quotes() {
+        let result = parse("(f '(1 2) ''x)");
+        assert!(result.parse_errors.is_empty());
+        let quoted_list = (
+            Expr::Quote(Box::new((
+                Expr::List(vec![
+                    (Expr::Number("1".into()), 5..6),
+                    (Expr::Number("2".into()), 7..8),
+                ]),
+                4..9,
+            ))),
+            3..9,
+        );
+        let quoted_quote = (
+            Expr::Quote(Box::new((
+                Expr::Quote(Box::new((Expr::Ident("x".into()), 12..13))),
+                11..13,
+            ))),
+            10..13,
+        );
+        assert_eq!(
+            result.ast,
+            vec![(
+                Expr::List(vec![
+                    (Expr::Ident("f".into()), 1..2),
+                    quoted_list,
+                    quoted_quote
+                ]),
+                0..14
+            )]
+        );
+        assert_eq!(result.ast[0].0.to_string(), "(f '(1 2) ''x)");
+
+        // A quote needs an expression after it in the same list.
+        let result = parse("(a ') '");
+        let spans: Vec<_> = result.parse_errors.iter().map(|err| err.span()).collect();
+        assert_eq!(spans, vec![3..4, 6..7]);
+        assert_eq!(
+            result.parse_errors[0].reason(),
+            &chumsky::error::SimpleReason::Custom("expected an expression after the quote".into())
+        );
+        // An unclosed list is still quoted.
+        assert!(matches!(parse("'(a").ast[0], (Expr::Quote(_), ref span) if *span == (0..3)));
+    }
+
+    #[test]
+    fn collect_defuns_in_source_order() {
+        let source = "(defun f (x y) (defun g () x))\n(defun h (z) z)";
+        let ast = parse(source).ast;
+        let names: Vec<_> = collect_defuns(&ast)
+            .into_iter()
+            .map(|defun| defun.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("f".into(), 7..8),
+                ("g".into(), 22..23),
+                ("h".into(), 38..39)
+            ]
+        );
+
+        let params = collect_defun_params(&ast);
+        assert_eq!(params["f"], vec!["x".to_string(), "y".to_string()]);
+        assert!(params["g"].is_empty());
+    }
+
+    #[test]
+    fn collect_doc_comments_above_defuns() {
+        let source = "; computes factorial\n;  of n\n(defun fact (n) n)\n\
+            ; detached\n\n(defun f () 1)\n\
+            (print 1) ; trailing\n(defun g () 1)\r\n\
+            ; crlf\r\n(defun h () 1)";
+        let tokens = lexer().parse(source).unwrap();
+        let docs = collect_doc_comments(source, &tokens);
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs["fact"], "computes factorial\nof n");
+        assert_eq!(docs["h"], "crlf");
+    }
+
+    #[test]
+    fn call_context_active_argument() {
+        let tokens = lexer().parse("(f (g 1) (h x").unwrap();
+        assert_eq!(call_context(&tokens, 2), None);
+        assert_eq!(call_context(&tokens, 3), Some(("f".into(), 0)));
+        assert_eq!(call_context(&tokens, 8), Some(("f".into(), 0)));
+        assert_eq!(call_context(&tokens, 9), Some(("f".into(), 1)));
+        assert_eq!(call_context(&tokens, 6), Some(("g".into(), 0)));
+        assert_eq!(call_context(&tokens, 13), Some(("h".into(), 0)));
+        assert_eq!(call_context(&tokens, 14), Some(("h".into(), 1)));
+        assert_eq!(call_context(&tokens, 11), None);
+        assert_eq!(call_context(&tokens, 0), None);
+    }
+
+    #[test]
+    fn nesting_depth_before_offset() {
+        let tokens = lexer().parse("(defun f (x)\n  (g (h x)))").unwrap();
+        assert_eq!(nesting_depth(&tokens, 0), 0);
+        assert_eq!(nesting_depth(&tokens, 13), 1);
+        assert_eq!(nesting_depth(&tokens, 20), 3);
+        assert_eq!(nesting_depth(&tokens, 25), 0);
+    }
+
+    #[test]
+    fn token_at_offset_boundaries() {
+        let tokens = lexer().parse("(fact  10)").unwrap();
+        let token = |offset| token_at_offset(&tokens, offset).map(|(token, _)| token);
+        assert_eq!(token(0), Some(&LParen));
+        assert_eq!(token(1), Some(&Ident("fact".into())));
+        assert_eq!(token(3), Some(&Ident("fact".into())));
+        assert_eq!(token(5), None);
+        assert_eq!(token(6), None);
+        assert_eq!(token(7), Some(&Number("10".into())));
+        assert_eq!(token(9), Some(&RParen));
+        assert_eq!(token(10), None);
+    }
+
+    #[test]
+    fn match_paren_pair() {
+        let tokens = lexer().parse("(a (b) c").unwrap();
+        assert_eq!(match_paren(&tokens, 3), Some((3..4, 5..6)));
+        assert_eq!(match_paren(&tokens, 5), Some((3..4, 5..6)));
+        assert_eq!(match_paren(&tokens, 0), None);
+        assert_eq!(match_paren(&tokens, 1), None);
+    }
+
+    #[test]
+    fn paren_fixes_for_unbalanced_parens() {
+        let tokens = lexer().parse("(+ 1 (f 2) ; done").unwrap();
+        assert_eq!(paren_fixes(&tokens), vec![(0..1, ParenFix::Insert(10))]);
+
+        let tokens = lexer().parse("(f)) (g)").unwrap();
+        assert_eq!(paren_fixes(&tokens), vec![(3..4, ParenFix::Remove)]);
+        assert!(paren_fixes(&lexer().parse("(f (g))").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn find_extractable_subexpression() {
+        let ast = parse("(defun fact (n) (* n (fact (- n 1))))").ast;
+        let (expr, form) = find_extractable(&ast, &(27..34)).unwrap();
+        assert_eq!(expr.1, 27..34);
+        assert_eq!(form.1, 21..35);
+
+        assert_eq!(find_extractable(&ast, &(16..36)), None);
+        assert_eq!(find_extractable(&ast, &(28..29)), None);
+    }
 }
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("orelang-cli-{}-{}", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_orelang-but-rust"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn parse_prints_ast() {
+    let path = write_source("fact.ore", "; five\n(print 5)\n");
+    let output = run(&["parse", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(List([(Ident(\"print\"), 8..13), (Number(\"5\"), 14..15)]), 7..16)\n"
+    );
+}
+
+#[test]
+fn parse_reports_errors() {
+    let path = write_source("broken.ore", "(print 5\n  (+ 1 2))\n)");
+    let output = run(&["parse", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let errors: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("error:"))
+        .collect();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].ends_with(":3:1: unexpected ')'"));
+}
+
+#[test]
+fn highlight_prints_spans() {
+    let path = write_source("highlight.ore", "; five\n(print 5)\n");
+    let output = run(&["highlight", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "0..6 comment\n7..8 operator depth0\n8..13 function\n14..15 number\n15..16 operator depth0\n"
+    );
+}
+
+#[test]
+fn unknown_command_prints_usage() {
+    let output = run(&["frobnicate"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("usage:"));
+}
+
+const MESSY: &str = "(defun   square (x)\n(* x x))\n\n\n( print(square 5) )  ";
+const FORMATTED: &str = "(defun square (x)\n  (* x x))\n\n(print (square 5))\n";
+
+#[test]
+fn fmt_prints_formatted_source() {
+    let path = write_source("messy.ore", MESSY);
+    let output = run(&["fmt", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), FORMATTED);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), MESSY);
+}
+
+#[test]
+fn fmt_check_and_write() {
+    let path = write_source("check.ore", MESSY);
+    let file = path.to_str().unwrap();
+    let output = run(&["fmt", file, "--check"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), MESSY);
+
+    assert!(run(&["fmt", file, "--write"]).status.success());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), FORMATTED);
+    assert!(run(&["fmt", file, "--check"]).status.success());
+}
@@ -0,0 +1,275 @@
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+const FACT: &str = "(defun fact (n)\n  (if (= n 0)\n      1\n      (* n (fact (- n 1)))))\n";
+
+/// Frames `message` as a JSON-RPC message with its `Content-Length` header.
+fn frame(message: &Value) -> Vec<u8> {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+/// Reads one framed message, or `None` once the stream ends.
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length: ") {
+            length = value.parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// The language server running as a child process, spoken to over stdio.
+/// Dropping it closes the server's stdin, which stops it.
+struct Server {
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Value>,
+    next_id: u64,
+    notifications: Vec<Value>,
+}
+
+impl Server {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_orelang-but-rust"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        // Reading on a thread lets `receive` time out instead of hanging the
+        // test run when the server goes quiet.
+        let (sender, messages) = channel();
+        thread::spawn(move || {
+            while let Some(message) = read_message(&mut stdout) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Server {
+            child,
+            stdin,
+            messages,
+            next_id: 0,
+            notifications: vec![],
+        }
+    }
+
+    fn send(&mut self, message: Value) {
+        self.stdin.write_all(&frame(&message)).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        self.messages
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the server didn't answer")
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Receives messages until one matches `wanted`. Requests from the
+    /// server meanwhile are answered with `null` and other messages are kept
+    /// in `notifications`.
+    fn receive_until(&mut self, wanted: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let message = self.receive();
+            if wanted(&message) {
+                return message;
+            }
+            match (message.get("id"), message.get("method")) {
+                (Some(id), Some(_)) => {
+                    let id = id.clone();
+                    self.send(json!({ "jsonrpc": "2.0", "id": id, "result": null }));
+                }
+                _ => self.notifications.push(message),
+            }
+        }
+    }
+
+    /// Sends a request and waits for its response.
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = json!(self.next_id);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        self.receive_until(|message| message["id"] == id && message.get("method").is_none())
+    }
+
+    fn notification(&mut self, method: &str) -> Value {
+        self.receive_until(|message| message["method"] == method && message.get("id").is_none())
+    }
+
+    fn initialize(&mut self, capabilities: Value) -> Value {
+        let response = self.request("initialize", json!({ "capabilities": capabilities }));
+        self.notify("initialized", json!({}));
+        response
+    }
+
+    fn open(&mut self, uri: &str, text: &str) {
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "orelang", "version": 0, "text": text }
+            }),
+        );
+    }
+
+    fn shutdown(mut self) {
+        self.request("shutdown", json!(null));
+        self.notify("exit", json!(null));
+        let Server {
+            mut child, stdin, ..
+        } = self;
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+    }
+}
+
+#[test]
+fn semantic_tokens_over_stdio() {
+    let mut server = Server::start();
+    let response = server.initialize(json!({
+        "textDocument": { "semanticTokens": {
+            "requests": { "full": true },
+            "tokenTypes": ["keyword", "variable", "number", "operator"],
+            "tokenModifiers": [],
+            "formats": ["relative"]
+        } }
+    }));
+    assert_eq!(
+        response["result"]["capabilities"]["semanticTokensProvider"]["legend"]["tokenTypes"],
+        json!(["keyword", "variable", "number", "operator"])
+    );
+
+    server.open("file:///fact.ore", FACT);
+    let response = server.request(
+        "textDocument/semanticTokens/full",
+        json!({ "textDocument": { "uri": "file:///fact.ore" } }),
+    );
+    let data: Vec<u64> = serde_json::from_value(response["result"]["data"].clone()).unwrap();
+    let tokens: Vec<_> = data.chunks(5).map(|token| token.to_vec()).collect();
+    assert_eq!(
+        tokens,
+        vec![
+            vec![0, 1, 5, 0, 0],
+            vec![0, 6, 4, 1, 0],
+            vec![0, 6, 1, 1, 0],
+            vec![1, 3, 2, 0, 0],
+            vec![0, 4, 1, 3, 0],
+            vec![0, 2, 1, 1, 0],
+            vec![0, 2, 1, 2, 0],
+            vec![1, 6, 1, 2, 0],
+            vec![1, 7, 1, 3, 0],
+            vec![0, 2, 1, 1, 0],
+            vec![0, 3, 4, 1, 0],
+            vec![0, 6, 1, 3, 0],
+            vec![0, 2, 1, 1, 0],
+            vec![0, 2, 1, 2, 0],
+        ]
+    );
+
+    server.shutdown();
+}
+
+#[test]
+fn diagnostics_published_on_open() {
+    let mut server = Server::start();
+    server.initialize(json!({ "textDocument": { "publishDiagnostics": {} } }));
+    server.open("file:///broken.ore", "(+ 1");
+
+    let diagnostics = server.notification("textDocument/publishDiagnostics");
+    assert_eq!(diagnostics["params"]["uri"], "file:///broken.ore");
+    assert_eq!(
+        diagnostics["params"]["diagnostics"][0]["message"],
+        "unclosed '('"
+    );
+    server.shutdown();
+}
+
+#[test]
+fn incremental_changes_over_stdio() {
+    let mut server = Server::start();
+    let response = server.initialize(json!({
+        "textDocument": {
+            "publishDiagnostics": {},
+            "documentSymbol": { "hierarchicalDocumentSymbolSupport": true }
+        }
+    }));
+    assert_eq!(
+        response["result"]["capabilities"]["textDocumentSync"]["change"],
+        json!(2)
+    );
+
+    server.open("file:///edited.ore", FACT);
+    server.notification("textDocument/publishDiagnostics");
+    let change = |line: u32, start: u32, end: u32, text: &str| {
+        json!({
+            "range": {
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end }
+            },
+            "text": text
+        })
+    };
+    // Renames `n` to `k`, one occurrence per change.
+    server.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": "file:///edited.ore", "version": 1 },
+            "contentChanges": [
+                change(0, 13, 14, "k"),
+                change(1, 9, 10, "k"),
+                change(3, 9, 10, "k"),
+                change(3, 20, 21, "k"),
+            ]
+        }),
+    );
+    let diagnostics = server.receive_until(|message| {
+        message["method"] == "textDocument/publishDiagnostics" && message["params"]["version"] == 1
+    });
+    assert_eq!(diagnostics["params"]["diagnostics"], json!([]));
+
+    let edited = "(defun fact (k)\n  (if (= k 0)\n      1\n      (* k (fact (- k 1)))))\n";
+    server.open("file:///full.ore", edited);
+    server.notification("textDocument/publishDiagnostics");
+
+    // The edited document parsed to the same as its text parsed in full.
+    let parsed = |server: &mut Server, uri: &str| {
+        let document = json!({ "textDocument": { "uri": uri } });
+        let position = json!({
+            "textDocument": { "uri": uri },
+            "position": { "line": 3, "character": 20 }
+        });
+        [
+            server.request("textDocument/documentSymbol", document.clone()),
+            server.request("textDocument/foldingRange", document),
+            server.request("textDocument/documentHighlight", position),
+        ]
+        .map(|response| response["result"].clone())
+    };
+    let incremental = parsed(&mut server, "file:///edited.ore");
+    assert_eq!(incremental, parsed(&mut server, "file:///full.ore"));
+    // Every renamed occurrence is found as the same parameter.
+    assert_eq!(incremental[2].as_array().map(Vec::len), Some(4));
+    server.shutdown();
+}